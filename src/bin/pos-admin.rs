@@ -0,0 +1,438 @@
+use std::io::{IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use chrono::{NaiveDate, NaiveTime};
+use clap::{Parser, Subcommand, ValueEnum};
+use pos_server_mongodb::config::{self, Config};
+use pos_server_mongodb::models::audit::Actor;
+use pos_server_mongodb::models::categories::{Category, CategoryId, NewCategory};
+use pos_server_mongodb::models::menu::MenuFile;
+use pos_server_mongodb::models::products::{NewProduct, Product, ProductId, Unit};
+use pos_server_mongodb::models::reports::{ReportKind, SalesReport};
+use pos_server_mongodb::models::roles::Role;
+use pos_server_mongodb::models::tables::{NewTable, Table, TableId};
+use pos_server_mongodb::models::validation::{Validate, ValidationErrors};
+use pos_server_mongodb::models::waiters::{default_waiter_roles, NewWaiter, Waiter, WaiterId};
+use pos_server_mongodb::repo::backup::Backup;
+use pos_server_mongodb::repo::migrations::run_migrate_command;
+use pos_server_mongodb::repo::reports::business_day_of;
+use pos_server_mongodb::repo::repository::Repository;
+use pos_server_mongodb::services::reports::parse_day;
+use pos_server_mongodb::services::waiter_session::hash_pin;
+use pos_server_mongodb::telemetry;
+
+/// Administers the POS database directly, without going through the server or Keycloak.
+#[derive(Debug, Parser)]
+#[command(name = "pos-admin")]
+struct AdminCli {
+    #[command(subcommand)]
+    command: AdminCommand,
+    /// TOML config file, `pos.toml` when it exists otherwise. Only `[database]` is used.
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+    #[arg(long, global = true, value_name = "URI")]
+    db_uri: Option<String>,
+    #[arg(long, global = true, value_name = "NAME")]
+    db_name: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    /// Fill an empty database with a demo menu and floor plan.
+    Seed,
+    /// Export or import categories and products.
+    Menu {
+        #[command(subcommand)]
+        command: MenuCommand,
+    },
+    /// Manage waiters.
+    Waiter {
+        #[command(subcommand)]
+        command: WaiterCommand,
+    },
+    /// Apply pending database migrations.
+    Migrate {
+        /// Only list the pending migrations.
+        #[arg(long)]
+        status: bool,
+    },
+    /// Write every collection to a tar archive.
+    Backup {
+        /// `pos-backup-<time>.tar` in the current directory by default.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the database with a backup archive, then migrate it.
+    Restore {
+        file: PathBuf,
+        /// Only check the archive.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Close a business day and print its Z report. Refused while the day has open orders.
+    CloseDay {
+        /// `YYYY-MM-DD`, the current business day by default.
+        #[arg(long)]
+        day: Option<String>,
+    },
+    /// Print an X report, or the Z report of a closed day.
+    Report {
+        #[arg(value_enum)]
+        kind: ReportArg,
+        /// `YYYY-MM-DD`, the current business day by default.
+        #[arg(long)]
+        day: Option<String>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum MenuCommand {
+    /// Write the menu as JSON or CSV.
+    Export {
+        /// Standard output by default.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Taken from the output file extension, JSON otherwise.
+        #[arg(long, value_enum)]
+        format: Option<FileFormat>,
+    },
+    /// Upsert categories by name and products by SKU or name. Nothing is written if any row is
+    /// invalid.
+    Import {
+        file: PathBuf,
+        /// Taken from the file extension when missing.
+        #[arg(long, value_enum)]
+        format: Option<FileFormat>,
+        /// Only report what would change.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum WaiterCommand {
+    /// Create a waiter with a hashed PIN, read from stdin so it stays out of the shell history.
+    Add {
+        #[arg(long)]
+        name: String,
+        /// `admin`, `manager`, `waiter` or `kitchen`; repeat for several. `waiter` by default.
+        #[arg(long = "role", value_parser = parse_role)]
+        roles: Vec<Role>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ReportArg {
+    X,
+    Z,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum FileFormat {
+    Json,
+    Csv,
+}
+
+impl FileFormat {
+    fn of(format: Option<FileFormat>, path: Option<&PathBuf>) -> FileFormat {
+        let extension = path.and_then(|path| path.extension()).and_then(|extension| extension.to_str());
+        match (format, extension) {
+            (Some(format), _) => format,
+            (None, Some(extension)) if extension.eq_ignore_ascii_case("csv") => FileFormat::Csv,
+            (None, _) => FileFormat::Json,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn parse_role(role: &str) -> Result<Role, String> {
+    Role::from_claim(role).ok_or_else(|| format!("Unknown role {}", role))
+}
+
+#[actix_web::main]
+async fn main() -> ExitCode {
+    dotenvy::dotenv().ok();
+
+    let cli = AdminCli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: AdminCli) -> Result<(), String> {
+    let server_cli = config::Cli {
+        config: cli.config.clone(),
+        db_uri: cli.db_uri.clone(),
+        db_name: cli.db_name.clone(),
+        ..config::Cli::default()
+    };
+    let config = Config::layered(&server_cli, |name| std::env::var(name).ok())?;
+    config.validate_database()?;
+    let _telemetry = telemetry::init(&config.logging)?;
+
+    let repo = Repository::connect(&config.database, None)
+        .await
+        .map_err(|err| format!("Connecting to the database failed: {}", err))?
+        .as_actor(Actor {
+            subject: "pos-admin".to_string(),
+            username: std::env::var("USER").ok(),
+            waiter_id: None,
+            device_id: None,
+        });
+
+    match cli.command {
+        AdminCommand::Migrate { status } => run_migrate_command(&repo, status).await.map_err(|err| err.to_string()),
+        // Backups are taken before upgrading, and a restore migrates afterwards.
+        AdminCommand::Backup { output } => backup(&repo, output).await,
+        AdminCommand::Restore { file, dry_run } => restore(&repo, file, dry_run).await,
+        command => {
+            let status = repo.migration_status().await.map_err(|err| err.to_string())?;
+            if !status.pending.is_empty() {
+                return Err(format!(
+                    "Database schema is at version {} but this build needs {}; run pos-admin migrate first",
+                    status.current_version, status.latest_version,
+                ));
+            }
+
+            match command {
+                AdminCommand::Seed => seed(&repo).await,
+                AdminCommand::Menu { command: MenuCommand::Export { output, format } } => export_menu(&repo, output, format).await,
+                AdminCommand::Menu { command: MenuCommand::Import { file, format, dry_run } } => import_menu(&repo, file, format, dry_run).await,
+                AdminCommand::Waiter { command: WaiterCommand::Add { name, roles } } => add_waiter(&repo, name, roles).await,
+                AdminCommand::CloseDay { day } => close_day(&repo, day, config.reports.business_day_cutoff).await,
+                AdminCommand::Report { kind, day, format } => report(&repo, kind, day, format, config.reports.business_day_cutoff).await,
+                AdminCommand::Migrate { .. } | AdminCommand::Backup { .. } | AdminCommand::Restore { .. } => unreachable!("handled above"),
+            }
+        }
+    }
+}
+
+async fn backup(repo: &Repository, output: Option<PathBuf>) -> Result<(), String> {
+    let backup = repo.backup().await.map_err(|err| err.to_string())?;
+    let archive = backup.to_archive().map_err(|err| err.to_string())?;
+    let path = output.unwrap_or_else(|| PathBuf::from(format!("pos-backup-{}.tar", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"))));
+
+    std::fs::write(&path, archive).map_err(|err| format!("Writing {} failed: {}", path.display(), err))?;
+    let documents: usize = backup.manifest.collections.iter().map(|collection| collection.documents).sum();
+    println!(
+        "Backed up {} documents in {} collections at schema version {} to {}",
+        documents, backup.manifest.collections.len(), backup.manifest.schema_version, path.display(),
+    );
+
+    Ok(())
+}
+
+async fn restore(repo: &Repository, file: PathBuf, dry_run: bool) -> Result<(), String> {
+    let archive = std::fs::read(&file).map_err(|err| format!("Reading {} failed: {}", file.display(), err))?;
+    let backup = Backup::from_archive(&archive).map_err(|err| err.to_string())?;
+    println!("Backup from {} by {}, schema version {}", backup.manifest.created_at, backup.manifest.created_by, backup.manifest.schema_version);
+    for collection in &backup.manifest.collections {
+        println!("  {}: {} documents", collection.name, collection.documents);
+    }
+
+    let report = repo.restore(&backup, dry_run).await.map_err(|err| err.to_string())?;
+    if report.dry_run {
+        println!("Dry run, nothing was restored");
+    } else {
+        println!("Restored, then applied {} migrations", report.migrated.len());
+    }
+
+    Ok(())
+}
+
+fn invalid(what: &str, errors: ValidationErrors) -> String {
+    let fields: Vec<String> = errors.fields.iter().map(|field| format!("{} {}", field.field, field.message)).collect();
+    format!("Invalid {}:\n  {}", what, fields.join("\n  "))
+}
+
+async fn seed(repo: &Repository) -> Result<(), String> {
+    let has_menu = !repo.query_all::<Category>().await.map_err(|err| err.to_string())?.is_empty();
+    let has_tables = !repo.query_all::<Table>().await.map_err(|err| err.to_string())?.is_empty();
+
+    if has_menu {
+        println!("Categories exist already, not seeding the menu");
+    } else {
+        let categories = [
+            ("Drinks", "local_bar", "#1e88e5", vec![("Espresso", 2.5, 7.0), ("Lemonade", 3.9, 19.0), ("Draft beer", 4.8, 19.0)]),
+            ("Starters", "soup_kitchen", "#43a047", vec![("Tomato soup", 5.5, 7.0), ("Bruschetta", 6.9, 7.0)]),
+            ("Mains", "restaurant", "#e53935", vec![("Margherita", 9.5, 7.0), ("Burger", 13.9, 7.0), ("Caesar salad", 11.5, 7.0)]),
+            ("Desserts", "icecream", "#8e24aa", vec![("Tiramisu", 6.5, 7.0), ("Ice cream", 4.5, 7.0)]),
+        ];
+
+        let mut product_count = 0;
+        for (name, icon, color, products) in categories {
+            let new_category = NewCategory { name: name.to_string(), icon: icon.to_string(), color: color.to_string() };
+            new_category.validate().map_err(|errors| invalid("category", errors))?;
+            let category = Category {
+                _id: CategoryId::new(),
+                name: new_category.name,
+                icon: new_category.icon,
+                color: new_category.color,
+            };
+            repo.insert_one::<Category>(category.clone()).await.map_err(|err| err.to_string())?;
+
+            for (name, price, tax_rate) in products {
+                let new_product = NewProduct { name: name.to_string(), price, category_id: category._id, tax_rate, sku: None, unit: Unit::Piece, open_price: None };
+                new_product.validate().map_err(|errors| invalid("product", errors))?;
+                repo.insert_product(Product {
+                    _id: ProductId::new(),
+                    name: new_product.name,
+                    price: new_product.price,
+                    category_id: new_product.category_id,
+                    tax_rate: new_product.tax_rate,
+                    sku: new_product.sku,
+                    unit: new_product.unit,
+                    open_price: new_product.open_price,
+                }).await.map_err(|err| err.to_string())?;
+                product_count += 1;
+            }
+        }
+        println!("Seeded 4 categories and {} products", product_count);
+    }
+
+    if has_tables {
+        println!("Tables exist already, not seeding the floor plan");
+    } else {
+        let tables = (1..=8).map(|number| NewTable {
+            name: format!("Table {}", number),
+            x: (number - 1) % 4,
+            y: (number - 1) / 4,
+            level: 0,
+        });
+        let mut table_count = 0;
+        for new_table in tables {
+            new_table.validate().map_err(|errors| invalid("table", errors))?;
+            repo.insert_one::<Table>(Table {
+                _id: TableId::new(),
+                name: new_table.name,
+                x: new_table.x,
+                y: new_table.y,
+                level: new_table.level,
+            }).await.map_err(|err| err.to_string())?;
+            table_count += 1;
+        }
+        println!("Seeded {} tables", table_count);
+    }
+
+    Ok(())
+}
+
+async fn export_menu(repo: &Repository, output: Option<PathBuf>, format: Option<FileFormat>) -> Result<(), String> {
+    let menu = repo.export_menu().await.map_err(|err| err.to_string())?;
+    let data = match FileFormat::of(format, output.as_ref()) {
+        FileFormat::Json => menu.to_json()?,
+        FileFormat::Csv => menu.to_csv()?,
+    };
+
+    match output {
+        Some(path) => {
+            std::fs::write(&path, data).map_err(|err| format!("Writing {} failed: {}", path.display(), err))?;
+            eprintln!("Exported {} categories and {} products to {}", menu.categories.len(), menu.products.len(), path.display());
+        }
+        None => std::io::stdout().write_all(&data).map_err(|err| err.to_string())?,
+    }
+
+    Ok(())
+}
+
+async fn import_menu(repo: &Repository, file: PathBuf, format: Option<FileFormat>, dry_run: bool) -> Result<(), String> {
+    let data = std::fs::read(&file).map_err(|err| format!("Reading {} failed: {}", file.display(), err))?;
+    let (menu, parse_errors) = match FileFormat::of(format, Some(&file)) {
+        FileFormat::Json => (MenuFile::from_json(&data)?, Vec::new()),
+        FileFormat::Csv => MenuFile::from_csv(&data),
+    };
+
+    let report = repo.import_menu(&menu, parse_errors, dry_run).await.map_err(|err| err.to_string())?;
+    println!(
+        "Categories: {} new, {} updated. Products: {} new, {} updated. {} unchanged.",
+        report.categories_created, report.categories_updated, report.products_created, report.products_updated, report.unchanged,
+    );
+    for error in &report.errors {
+        println!("  {} row {}: {} {}", error.section, error.row, error.field, error.message);
+    }
+
+    if !report.errors.is_empty() {
+        Err(format!("{} rows are invalid, nothing was imported", report.errors.len()))
+    } else if report.dry_run {
+        println!("Dry run, nothing was imported");
+        Ok(())
+    } else {
+        println!("Imported");
+        Ok(())
+    }
+}
+
+async fn add_waiter(repo: &Repository, name: String, roles: Vec<Role>) -> Result<(), String> {
+    let data = NewWaiter { name, code: read_pin()?, roles };
+    data.validate().map_err(|errors| invalid("waiter", errors))?;
+
+    let waiter = Waiter {
+        _id: WaiterId::new(),
+        name: data.name,
+        pin_hash: hash_pin(data.code).await.map_err(|err| err.to_string())?,
+        failed_attempts: 0,
+        locked_until: None,
+        roles: if data.roles.is_empty() { default_waiter_roles() } else { data.roles },
+        code: None,
+    };
+    repo.insert_one::<Waiter>(waiter.clone()).await.map_err(|err| err.to_string())?;
+    println!("Created waiter {} with id {}", waiter.name, waiter._id);
+
+    Ok(())
+}
+
+/// Asks for the PIN when run interactively, otherwise takes the first line piped in.
+fn read_pin() -> Result<String, String> {
+    let stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        eprint!("PIN: ");
+        std::io::stderr().flush().map_err(|err| err.to_string())?;
+    }
+
+    let mut pin = String::new();
+    stdin.read_line(&mut pin).map_err(|err| format!("Reading the PIN failed: {}", err))?;
+    Ok(pin.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn business_day(day: Option<String>, cutoff: NaiveTime) -> Result<NaiveDate, String> {
+    Ok(match day {
+        Some(day) => parse_day(&day).map_err(|err| err.to_string())?,
+        None => business_day_of(&chrono::Local::now(), cutoff),
+    })
+}
+
+async fn close_day(repo: &Repository, day: Option<String>, cutoff: NaiveTime) -> Result<(), String> {
+    let day = business_day(day, cutoff)?;
+    let z_report = repo.close_business_day(day, cutoff).await.map_err(|err| err.to_string())?;
+    print!("{}", z_report.report);
+
+    Ok(())
+}
+
+async fn report(repo: &Repository, kind: ReportArg, day: Option<String>, format: OutputFormat, cutoff: NaiveTime) -> Result<(), String> {
+    let day = business_day(day, cutoff)?;
+    let report: SalesReport = match kind {
+        ReportArg::X => repo.sales_report(ReportKind::X, day, cutoff).await.map_err(|err| err.to_string())?,
+        ReportArg::Z => repo.query_z_report(day).await.map_err(|err| err.to_string())?
+            .ok_or_else(|| format!("Business day {} is not closed", day))?
+            .report,
+    };
+
+    match format {
+        OutputFormat::Text => print!("{}", report),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).map_err(|err| err.to_string())?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,547 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use chrono::NaiveTime;
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Deserializer};
+use crate::services::auth::parse_algorithm;
+use crate::services::local_auth::{parse_device_keys, AuthMode};
+
+const DEFAULT_CONFIG_FILE: &str = "pos.toml";
+
+/// Command line flags. They override both the config file and the environment.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(about = "POS server")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML config file, `pos.toml` when it exists otherwise.
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    #[arg(long, value_name = "HOST")]
+    pub address: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Allowed CORS origin, repeat for several.
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+    #[arg(long, value_name = "URI")]
+    pub db_uri: Option<String>,
+    #[arg(long, value_name = "NAME")]
+    pub db_name: Option<String>,
+    /// `keycloak` or `local`.
+    #[arg(long, value_name = "MODE")]
+    pub auth_mode: Option<String>,
+    /// `text` or `json`.
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Apply pending database migrations and exit.
+    Migrate {
+        /// Only list the pending migrations.
+        #[arg(long)]
+        status: bool,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub cors_origins: Vec<String>,
+    /// How long a shutdown waits for in-flight requests before cutting them off.
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "localhost".to_string(),
+            port: 8080,
+            cors_origins: vec!["http://localhost:3000".to_string()],
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub uri: String,
+    pub username: String,
+    pub password: String,
+    pub name: String,
+    /// Apply pending migrations when the server starts. Otherwise it refuses to start until
+    /// `migrate` has been run.
+    pub migrate_on_startup: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            uri: String::new(),
+            username: String::new(),
+            password: String::new(),
+            name: String::new(),
+            migrate_on_startup: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    /// Keycloak JWKS endpoint, required in `keycloak` mode.
+    pub certs_url: Option<String>,
+    pub certs_ttl_secs: u64,
+    /// Waiter logins and device pairings allowed per caller within `attempt_window_secs`.
+    pub attempt_limit: usize,
+    pub attempt_window_secs: u64,
+    pub token: TokenConfig,
+    pub local: LocalAuthConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: AuthMode::Keycloak,
+            certs_url: None,
+            certs_ttl_secs: 300,
+            attempt_limit: 10,
+            attempt_window_secs: 60,
+            token: TokenConfig::default(),
+            local: LocalAuthConfig::default(),
+        }
+    }
+}
+
+/// What a Keycloak token must look like in `keycloak` mode. Empty `issuers` or `audiences`
+/// switch the corresponding check off.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenConfig {
+    pub issuers: Vec<String>,
+    pub audiences: Vec<String>,
+    pub algorithms: Vec<String>,
+    pub leeway_secs: u64,
+    /// Client whose roles count next to the realm roles.
+    pub client_id: Option<String>,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            issuers: vec![],
+            audiences: vec!["account".to_string()],
+            algorithms: vec!["RS256".to_string()],
+            leeway_secs: 60,
+            client_id: None,
+        }
+    }
+}
+
+/// How the server signs its own tokens in `local` mode.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalAuthConfig {
+    /// `HS256` or `EdDSA`.
+    pub algorithm: String,
+    /// HS256 secret, at least 32 characters.
+    pub secret: Option<String>,
+    /// Ed25519 PEM files for EdDSA.
+    pub private_key: Option<PathBuf>,
+    pub public_key: Option<PathBuf>,
+    pub token_ttl_secs: u64,
+    /// `terminal[:role+role]=key` entries separated by commas.
+    pub device_keys: String,
+}
+
+impl Default for LocalAuthConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: "HS256".to_string(),
+            secret: None,
+            private_key: None,
+            public_key: None,
+            token_ttl_secs: 30 * 60,
+            device_keys: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReportsConfig {
+    /// Time of day (`HH:MM`) a business day ends, so sales after midnight count for the day before.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub business_day_cutoff: NaiveTime,
+}
+
+impl Default for ReportsConfig {
+    fn default() -> Self {
+        Self {
+            business_day_cutoff: NaiveTime::from_hms_opt(4, 0, 0).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrdersConfig {
+    /// Voids and refunds above this amount need a manager.
+    pub void_approval_threshold: f64,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M").map_err(|err| format!("Invalid time {}, expected HH:MM: {}", value, err))
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_time(&value).map_err(serde::de::Error::custom)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format {}, expected text or json", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `tracing_subscriber` filter directives, like `info,pos_server_mongodb=debug`.
+    pub filter: String,
+    /// OTLP/gRPC collector spans are sent to. Needs the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+            otlp_endpoint: None,
+        }
+    }
+}
+
+/// Settings the server needs before it can start. Defaults are overridden by the TOML file,
+/// then by environment variables, then by command line flags.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub logging: LoggingConfig,
+    pub reports: ReportsConfig,
+    pub orders: OrdersConfig,
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        Self::load_from(cli, |name| std::env::var(name).ok())
+    }
+
+    pub fn load_from(cli: &Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let config = Self::layered(cli, env)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// File, environment and flags merged but not validated, for tools that only need some
+    /// of the sections.
+    pub fn layered(cli: &Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        config.apply_env(env)?;
+        config.apply_cli(cli)?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Reading config file {} failed: {}", path.display(), err))?;
+
+        toml::from_str(&contents).map_err(|err| format!("Invalid config file {}: {}", path.display(), err))
+    }
+
+    fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let var = |name: &str| env(name).filter(|value| !value.is_empty());
+
+        if let Some(address) = var("API_ADDRESS") {
+            self.server.address = address;
+        }
+        if let Some(port) = var("API_PORT") {
+            self.server.port = port.parse().map_err(|err| format!("Invalid API_PORT {}: {}", port, err))?;
+        }
+        if let Some(origins) = var("API_CORS_ORIGINS") {
+            self.server.cors_origins = origins.split(',').map(|origin| origin.trim().to_string()).collect();
+        }
+        if let Some(timeout) = var("SHUTDOWN_TIMEOUT_SECS") {
+            self.server.shutdown_timeout_secs = timeout.parse().map_err(|err| format!("Invalid SHUTDOWN_TIMEOUT_SECS {}: {}", timeout, err))?;
+        }
+        if let Some(uri) = var("DB_URI") {
+            self.database.uri = uri;
+        }
+        if let Some(username) = var("DB_USERNAME") {
+            self.database.username = username;
+        }
+        if let Some(password) = var("DB_PASSWORD") {
+            self.database.password = password;
+        }
+        if let Some(name) = var("DB_NAME") {
+            self.database.name = name;
+        }
+        if let Some(migrate) = var("DB_MIGRATE_ON_STARTUP") {
+            self.database.migrate_on_startup = migrate.parse().map_err(|err| format!("Invalid DB_MIGRATE_ON_STARTUP {}: {}", migrate, err))?;
+        }
+        if let Some(mode) = var("AUTH_MODE") {
+            self.auth.mode = mode.parse()?;
+        }
+        if let Some(certs_url) = var("API_AUTH_CERTS") {
+            self.auth.certs_url = Some(certs_url);
+        }
+        if let Some(ttl) = var("API_AUTH_CERTS_TTL_SECS") {
+            self.auth.certs_ttl_secs = ttl.parse().map_err(|err| format!("Invalid API_AUTH_CERTS_TTL_SECS {}: {}", ttl, err))?;
+        }
+        // An empty list is how the issuer and audience checks are switched off, so these are
+        // taken even when empty.
+        let list = |name: &str| env(name).map(|value| {
+            value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect::<Vec<String>>()
+        });
+        if let Some(limit) = var("API_AUTH_ATTEMPT_LIMIT") {
+            self.auth.attempt_limit = limit.parse().map_err(|err| format!("Invalid API_AUTH_ATTEMPT_LIMIT {}: {}", limit, err))?;
+        }
+        if let Some(window) = var("API_AUTH_ATTEMPT_WINDOW_SECS") {
+            self.auth.attempt_window_secs = window.parse().map_err(|err| format!("Invalid API_AUTH_ATTEMPT_WINDOW_SECS {}: {}", window, err))?;
+        }
+        if let Some(issuers) = list("API_AUTH_ISSUER") {
+            self.auth.token.issuers = issuers;
+        }
+        if let Some(audiences) = list("API_AUTH_AUDIENCE") {
+            self.auth.token.audiences = audiences;
+        }
+        if let Some(algorithms) = list("API_AUTH_ALGORITHMS") {
+            self.auth.token.algorithms = algorithms;
+        }
+        if let Some(leeway) = var("API_AUTH_LEEWAY_SECS") {
+            self.auth.token.leeway_secs = leeway.parse().map_err(|err| format!("Invalid API_AUTH_LEEWAY_SECS {}: {}", leeway, err))?;
+        }
+        if let Some(client_id) = var("API_AUTH_CLIENT_ID") {
+            self.auth.token.client_id = Some(client_id);
+        }
+        if let Some(algorithm) = var("LOCAL_AUTH_ALGORITHM") {
+            self.auth.local.algorithm = algorithm;
+        }
+        if let Some(secret) = var("LOCAL_AUTH_SECRET") {
+            self.auth.local.secret = Some(secret);
+        }
+        if let Some(path) = var("LOCAL_AUTH_PRIVATE_KEY") {
+            self.auth.local.private_key = Some(PathBuf::from(path));
+        }
+        if let Some(path) = var("LOCAL_AUTH_PUBLIC_KEY") {
+            self.auth.local.public_key = Some(PathBuf::from(path));
+        }
+        if let Some(ttl) = var("LOCAL_AUTH_TOKEN_TTL_SECS") {
+            self.auth.local.token_ttl_secs = ttl.parse().map_err(|err| format!("Invalid LOCAL_AUTH_TOKEN_TTL_SECS {}: {}", ttl, err))?;
+        }
+        if let Some(device_keys) = var("LOCAL_AUTH_DEVICE_KEYS") {
+            self.auth.local.device_keys = device_keys;
+        }
+        if let Some(cutoff) = var("BUSINESS_DAY_CUTOFF") {
+            self.reports.business_day_cutoff = parse_time(&cutoff).map_err(|err| format!("Invalid BUSINESS_DAY_CUTOFF: {}", err))?;
+        }
+        if let Some(threshold) = var("VOID_APPROVAL_THRESHOLD") {
+            self.orders.void_approval_threshold = threshold.parse().map_err(|err| format!("Invalid VOID_APPROVAL_THRESHOLD {}: {}", threshold, err))?;
+        }
+        if let Some(format) = var("LOG_FORMAT") {
+            self.logging.format = format.parse()?;
+        }
+        if let Some(filter) = var("RUST_LOG") {
+            self.logging.filter = filter;
+        }
+        if let Some(endpoint) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.logging.otlp_endpoint = Some(endpoint);
+        }
+
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) -> Result<(), String> {
+        if let Some(address) = &cli.address {
+            self.server.address = address.clone();
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+        if !cli.cors_origins.is_empty() {
+            self.server.cors_origins = cli.cors_origins.clone();
+        }
+        if let Some(uri) = &cli.db_uri {
+            self.database.uri = uri.clone();
+        }
+        if let Some(name) = &cli.db_name {
+            self.database.name = name.clone();
+        }
+        if let Some(mode) = &cli.auth_mode {
+            self.auth.mode = mode.parse()?;
+        }
+        if let Some(format) = &cli.log_format {
+            self.logging.format = format.parse()?;
+        }
+
+        Ok(())
+    }
+
+    /// Lists every problem at once rather than failing on the first one.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = self.server_problems();
+        problems.extend(self.database_problems());
+        problems.extend(self.auth_problems());
+        problems.extend(self.orders_problems());
+
+        report_problems(problems)
+    }
+
+    /// Only what connecting to MongoDB needs.
+    pub fn validate_database(&self) -> Result<(), String> {
+        report_problems(self.database_problems())
+    }
+
+    fn server_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.server.address.trim().is_empty() {
+            problems.push("server.address must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        for origin in &self.server.cors_origins {
+            if !(origin.starts_with("http://") || origin.starts_with("https://")) {
+                problems.push(format!("server.cors_origins entry {} must start with http:// or https://", origin));
+            }
+        }
+
+        problems
+    }
+
+    fn database_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !(self.database.uri.starts_with("mongodb://") || self.database.uri.starts_with("mongodb+srv://")) {
+            problems.push("database.uri (DB_URI) must be a mongodb:// or mongodb+srv:// URI".to_string());
+        }
+        if self.database.name.is_empty() {
+            problems.push("database.name (DB_NAME) must be set".to_string());
+        }
+        if self.database.username.is_empty() != self.database.password.is_empty() {
+            problems.push("database.username and database.password must be set together".to_string());
+        }
+
+        problems
+    }
+
+    fn auth_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.auth.mode == AuthMode::Keycloak && self.auth.certs_url.as_deref().unwrap_or("").is_empty() {
+            problems.push("auth.certs_url (API_AUTH_CERTS) must be set in keycloak mode".to_string());
+        }
+        if self.auth.certs_ttl_secs == 0 {
+            problems.push("auth.certs_ttl_secs must be greater than 0".to_string());
+        }
+        if self.auth.attempt_limit == 0 || self.auth.attempt_window_secs == 0 {
+            problems.push("auth.attempt_limit and auth.attempt_window_secs must be greater than 0".to_string());
+        }
+
+        match self.auth.mode {
+            AuthMode::Keycloak => problems.extend(self.token_problems()),
+            AuthMode::Local => problems.extend(self.local_auth_problems()),
+        }
+
+        problems
+    }
+
+    fn token_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.auth.token.algorithms.is_empty() {
+            problems.push("auth.token.algorithms (API_AUTH_ALGORITHMS) must list at least one algorithm".to_string());
+        }
+        for algorithm in &self.auth.token.algorithms {
+            if let Err(err) = parse_algorithm(algorithm) {
+                problems.push(format!("auth.token.algorithms: {}", err));
+            }
+        }
+
+        problems
+    }
+
+    fn local_auth_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let local = &self.auth.local;
+
+        match local.algorithm.as_str() {
+            "HS256" if local.secret.as_ref().map_or(0, String::len) < 32 => {
+                problems.push("auth.local.secret (LOCAL_AUTH_SECRET) must be at least 32 characters".to_string());
+            }
+            "EdDSA" if local.private_key.is_none() || local.public_key.is_none() => {
+                problems.push("auth.local.private_key and auth.local.public_key (LOCAL_AUTH_PRIVATE_KEY, LOCAL_AUTH_PUBLIC_KEY) must be set for EdDSA".to_string());
+            }
+            "HS256" | "EdDSA" => {}
+            other => problems.push(format!("auth.local.algorithm (LOCAL_AUTH_ALGORITHM) {} must be HS256 or EdDSA", other)),
+        }
+        if local.token_ttl_secs == 0 {
+            problems.push("auth.local.token_ttl_secs must be greater than 0".to_string());
+        }
+        if let Err(err) = parse_device_keys(&local.device_keys) {
+            problems.push(format!("auth.local.device_keys: {}", err));
+        }
+
+        problems
+    }
+
+    fn orders_problems(&self) -> Vec<String> {
+        let threshold = self.orders.void_approval_threshold;
+        if !threshold.is_finite() || threshold < 0.0 {
+            return vec!["orders.void_approval_threshold (VOID_APPROVAL_THRESHOLD) must not be negative".to_string()];
+        }
+
+        Vec::new()
+    }
+}
+
+fn report_problems(problems: Vec<String>) -> Result<(), String> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Invalid configuration:\n  {}", problems.join("\n  ")))
+    }
+}
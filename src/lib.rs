@@ -0,0 +1,7 @@
+pub mod config;
+pub mod models;
+pub mod repo;
+pub mod services;
+pub mod telemetry;
+#[cfg(test)]
+mod tests;
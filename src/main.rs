@@ -1,61 +1,156 @@
-mod models;
-#[cfg(test)]
-mod tests;
-mod services;
-mod repo;
-
+use std::time::Duration;
 use actix_cors::Cors;
 use actix_web::{web, App, HttpServer};
-use actix_web::middleware::Logger;
 use actix_web_httpauth::middleware::HttpAuthentication;
-use crate::repo::repository::Repository;
-use crate::services::auth::validator;
-use crate::services::categories::{add_category, get_all_categories, get_category};
-use crate::services::orders::{add_order, add_product_to_order, check_empty_order, get_all_orders, get_order, get_orders_by_table, get_orders_by_waiter, remove_product_from_order};
-use crate::services::products::{add_product, get_all_products, get_product};
-use crate::services::tables::{add_table, get_all_tables, get_table};
-use crate::services::waiters::{add_waiter, delete_waiter, get_all_waiters, get_waiter};
+use clap::Parser;
+use pos_server_mongodb::config::{Cli, Command, Config};
+use pos_server_mongodb::repo::migrations::run_migrate_command;
+use pos_server_mongodb::repo::repository::Repository;
+use pos_server_mongodb::services::auth::{validator, TokenValidationSettings};
+use pos_server_mongodb::services::error::{json_error_bodies, json_payload_error, path_error, query_payload_error};
+use pos_server_mongodb::services::jwks::JwksCache;
+use pos_server_mongodb::services::local_auth::{AuthMode, LocalAuthSettings};
+use pos_server_mongodb::services::metrics::{record_request_latency, Metrics};
+use pos_server_mongodb::services::rate_limit::RateLimiter;
+use pos_server_mongodb::services::routes;
+use pos_server_mongodb::services::shutdown::{shutdown_signal, ShutdownGate};
+use pos_server_mongodb::telemetry::{self, trace_requests};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let repo = Repository::connect().await;
+    // Logged once the subscriber is up, it would go nowhere before that.
+    let dotenv = dotenvy::dotenv();
+
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Err(std::io::Error::other(err));
+        }
+    };
+
+    let metrics = web::Data::new(Metrics::new());
+    let _telemetry = match telemetry::init(&config.logging) {
+        Ok(telemetry) => telemetry,
+        Err(err) => {
+            eprintln!("{}", err);
+            return Err(std::io::Error::other(err));
+        }
+    };
+    if let Err(err) = dotenv {
+        tracing::debug!("Not loading .env: {}", err);
+    }
+
+    let repo = match Repository::connect(&config.database, Some(metrics.clone().into_inner())).await {
+        Ok(repo) => repo,
+        Err(err) => {
+            tracing::error!("Connecting to the database failed: {}", err);
+            return Err(std::io::Error::other(err.to_string()));
+        }
+    };
+
+    if let Some(Command::Migrate { status }) = cli.command {
+        return run_migrate_command(&repo, status).await.map_err(|err| std::io::Error::other(err.to_string()));
+    }
+
+    if config.database.migrate_on_startup {
+        repo.migrate().await.map_err(|err| std::io::Error::other(format!("Migrating the database failed: {}", err)))?;
+    } else {
+        let status = repo.migration_status().await.map_err(|err| std::io::Error::other(err.to_string()))?;
+        if !status.pending.is_empty() {
+            let message = format!(
+                "Database schema is at version {} but this build needs {}; run the migrate command first",
+                status.current_version, status.latest_version,
+            );
+            tracing::error!("{}", message);
+            return Err(std::io::Error::other(message));
+        }
+    }
 
-    HttpServer::new(move || {
+    let login_limiter = web::Data::new(RateLimiter::new(config.auth.attempt_limit, Duration::from_secs(config.auth.attempt_window_secs)));
+    let shutdown_gate = web::Data::new(ShutdownGate::default());
+    let drain_gate = shutdown_gate.clone();
+
+    let (jwks, token_settings, local_auth) = match config.auth.mode {
+        AuthMode::Keycloak => {
+            let jwks_url = config.auth.certs_url.clone().unwrap_or_default();
+            let jwks_ttl = config.auth.certs_ttl_secs;
+            let jwks = web::Data::new(JwksCache::new(jwks_url, Duration::from_secs(jwks_ttl), Duration::from_secs(jwks_ttl * 4)));
+            JwksCache::spawn_refresh(jwks.clone().into_inner());
+            let token_settings = TokenValidationSettings::from_config(&config.auth.token).map_err(std::io::Error::other)?;
+            (Some(jwks), Some(web::Data::new(token_settings)), None)
+        }
+        AuthMode::Local => {
+            tracing::info!("Running with local authentication, Keycloak is not used");
+            let local_auth = LocalAuthSettings::from_config(&config.auth.local).map_err(std::io::Error::other)?;
+            (None, None, Some(web::Data::new(local_auth)))
+        }
+    };
+
+    let server_config = config.server.clone();
+    let reports_config = web::Data::new(config.reports.clone());
+    let orders_config = web::Data::new(config.orders.clone());
+
+    let server = HttpServer::new(move || {
         let auth = HttpAuthentication::bearer(validator);
-        let cors = Cors::default()
-            .allowed_origin("http://localhost:3000")
+        let cors = server_config.cors_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allow_any_method()
             .allow_any_header()
             .max_age(3600);
 
         App::new()
-            .wrap(Logger::default())
-            .wrap(auth)
+            .wrap_fn(trace_requests)
+            .wrap_fn(json_error_bodies)
+            .wrap_fn(record_request_latency)
             .wrap(cors)
+            .app_data(web::JsonConfig::default().error_handler(json_payload_error))
+            .app_data(web::PathConfig::default().error_handler(path_error))
+            .app_data(web::QueryConfig::default().error_handler(query_payload_error))
             .app_data(web::Data::new(repo.clone()))
-            .service(add_waiter)
-            .service(get_waiter)
-            .service(get_all_waiters)
-            .service(delete_waiter)
-            .service(add_order)
-            .service(get_order)
-            .service(get_all_orders)
-            .service(get_orders_by_waiter)
-            .service(get_orders_by_table)
-            .service(add_product_to_order)
-            .service(remove_product_from_order)
-            .service(check_empty_order)
-            .service(add_product)
-            .service(get_product)
-            .service(get_all_products)
-            .service(get_all_tables)
-            .service(get_table)
-            .service(add_table)
-            .service(get_all_categories)
-            .service(add_category)
-            .service(get_category)
+            .app_data(login_limiter.clone())
+            .app_data(metrics.clone())
+            .app_data(shutdown_gate.clone())
+            .app_data(reports_config.clone())
+            .app_data(orders_config.clone())
+            .configure(|cfg| {
+                if let Some(jwks) = &jwks {
+                    cfg.app_data(jwks.clone());
+                }
+                if let Some(token_settings) = &token_settings {
+                    cfg.app_data(token_settings.clone());
+                }
+                if let Some(local_auth) = &local_auth {
+                    cfg.app_data(local_auth.clone());
+                }
+            })
+            .configure(routes::public)
+            .service(
+                web::scope("")
+                    .wrap(auth)
+                    .configure(routes::protected)
+            )
     })
-        .bind(("localhost", 8080))?
-        .run()
-        .await
+        .disable_signals()
+        .bind((config.server.address.as_str(), config.server.port))?
+        .run();
+
+    let handle = server.handle();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("Shutting down, waiting for {} in-flight mutations", drain_gate.in_flight());
+        if !drain_gate.drain(shutdown_timeout).await {
+            tracing::warn!("{} mutations were still running after {:?}", drain_gate.in_flight(), shutdown_timeout);
+        }
+        // Mutations are done or given up on, so there is nothing left worth a second wait.
+        handle.stop(false).await;
+    });
+
+    server.await?;
+    tracing::info!("Server stopped");
+
+    Ok(())
 }
@@ -0,0 +1,84 @@
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use crate::models::categories::CategoryId;
+use crate::models::products::ProductId;
+use crate::models::waiters::WaiterId;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AnalyticsQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub waiter_id: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProductSales {
+    #[serde(rename = "_id")]
+    pub product_id: ProductId,
+    pub name: String,
+    pub category_id: CategoryId,
+    pub quantity: f64,
+    pub revenue: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProductMix {
+    pub top_by_quantity: Vec<ProductSales>,
+    pub bottom_by_quantity: Vec<ProductSales>,
+    pub top_by_revenue: Vec<ProductSales>,
+    pub bottom_by_revenue: Vec<ProductSales>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CategorySales {
+    #[serde(rename = "_id")]
+    pub category_id: CategoryId,
+    pub name: String,
+    pub quantity: f64,
+    pub revenue: f64,
+    #[serde(default)]
+    pub share: f64,
+}
+
+/// One cell of the sales heatmap. `day_of_week` follows ISO 8601, Monday is 1 and Sunday is 7.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct HourlySales {
+    pub day_of_week: i32,
+    pub hour: i32,
+    pub orders: i64,
+    pub revenue: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TicketStats {
+    pub orders: i64,
+    pub covers: i64,
+    pub revenue: f64,
+    pub average_ticket: f64,
+    pub average_per_cover: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WaiterPerformance {
+    #[serde(rename = "_id")]
+    pub waiter_id: WaiterId,
+    pub name: String,
+    pub orders: i64,
+    pub covers: i64,
+    pub revenue: f64,
+    pub average_ticket: f64,
+    pub payments_total: f64,
+    pub cash_total: f64,
+    pub tips: f64,
+    pub voids: i64,
+    /// Average time from opening to closing an order; `None` when no order has been closed yet.
+    pub average_turn_minutes: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnalyticsFilter {
+    pub from: DateTime,
+    pub to: DateTime,
+    pub waiter_id: Option<Uuid>,
+}
@@ -0,0 +1,67 @@
+use mongodb::bson::{Bson, DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use crate::models::CollectionName;
+use crate::models::devices::DeviceId;
+use crate::models::waiters::WaiterId;
+
+const AUDIT_LOG_COLL_NAME: &str = "audit_log";
+
+pub type AuditEntryId = Uuid;
+
+/// Who made a change. Changes made by the server itself have the subject `system`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Actor {
+    pub subject: String,
+    pub username: Option<String>,
+    pub waiter_id: Option<WaiterId>,
+    pub device_id: Option<DeviceId>,
+}
+
+impl Actor {
+    pub fn system() -> Self {
+        Actor {
+            subject: "system".to_string(),
+            username: None,
+            waiter_id: None,
+            device_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Bson>,
+    pub after: Option<Bson>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub _id: AuditEntryId,
+    pub at: DateTime,
+    pub actor: Actor,
+    pub action: String,
+    pub entity: String,
+    pub entity_id: Option<Uuid>,
+    pub changes: Vec<FieldChange>,
+}
+
+impl CollectionName for AuditEntry {
+    fn collection_name() -> &'static str {
+        AUDIT_LOG_COLL_NAME
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AuditQuery {
+    pub entity: Option<String>,
+    pub entity_id: Option<String>,
+    pub action: Option<String>,
+    pub subject: Option<String>,
+    pub waiter_id: Option<String>,
+    pub device_id: Option<String>,
+    /// Business days, both inclusive.
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<i64>,
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Layout of the archive itself. Bumped only when the archive layout changes, not the schema.
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BackupCollection {
+    pub name: String,
+    pub documents: usize,
+}
+
+/// `manifest.json` at the start of every backup archive.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct BackupManifest {
+    pub format_version: u32,
+    /// Migration the database was at when the backup was taken.
+    pub schema_version: u32,
+    pub created_at: String,
+    /// Version of the server or `pos-admin` that took the backup.
+    pub created_by: String,
+    pub collections: Vec<BackupCollection>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RestoreQuery {
+    /// Only check the archive.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub restored: bool,
+    pub manifest: BackupManifest,
+    /// Migrations applied afterwards to bring an older backup up to this build.
+    pub migrated: Vec<u32>,
+}
@@ -0,0 +1,102 @@
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use crate::models::CollectionName;
+
+const DEVICES_COLL_NAME: &str = "devices";
+
+pub type DeviceId = Uuid;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewDevice {
+    pub name: String,
+    #[serde(default)]
+    pub default_printer: Option<String>,
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DeviceSettings {
+    pub default_printer: Option<String>,
+    pub level: Option<i32>,
+}
+
+/// A registered terminal. Pairing codes and credentials are stored as SHA-256 hashes.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Device {
+    pub _id: DeviceId,
+    pub name: String,
+    pub default_printer: Option<String>,
+    pub level: Option<i32>,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub pairing_code_hash: Option<String>,
+    #[serde(default)]
+    pub pairing_expires_at: Option<DateTime>,
+    #[serde(default)]
+    pub credential_hash: Option<String>,
+    #[serde(default)]
+    pub paired_at: Option<DateTime>,
+    #[serde(default)]
+    pub revoked_at: Option<DateTime>,
+}
+
+impl CollectionName for Device {
+    fn collection_name() -> &'static str {
+        DEVICES_COLL_NAME
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DeviceAPI {
+    pub _id: DeviceId,
+    pub name: String,
+    pub default_printer: Option<String>,
+    pub level: Option<i32>,
+    pub created_at: DateTime,
+    #[serde(default)]
+    pub paired_at: Option<DateTime>,
+    #[serde(default)]
+    pub revoked_at: Option<DateTime>,
+}
+
+impl CollectionName for DeviceAPI {
+    fn collection_name() -> &'static str {
+        DEVICES_COLL_NAME
+    }
+}
+
+impl From<Device> for DeviceAPI {
+    fn from(device: Device) -> Self {
+        DeviceAPI {
+            _id: device._id,
+            name: device.name,
+            default_printer: device.default_printer,
+            level: device.level,
+            created_at: device.created_at,
+            paired_at: device.paired_at,
+            revoked_at: device.revoked_at,
+        }
+    }
+}
+
+/// One-time code shown in the back office and typed in on the device being paired.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DevicePairingCode {
+    pub device: DeviceAPI,
+    pub pairing_code: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DevicePairing {
+    pub device_id: DeviceId,
+    pub pairing_code: String,
+}
+
+/// Returned once on pairing. The device sends `credential` in the `X-Device-Key` header.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DeviceCredential {
+    pub device: DeviceAPI,
+    pub credential: String,
+}
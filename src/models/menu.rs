@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use crate::models::products::{PriceRange, Unit};
+
+/// Category as it appears in a menu file, referred to by name rather than id.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CategoryRow {
+    /// Position in the file, 1-based; the line number for CSV.
+    #[serde(skip)]
+    pub row: usize,
+    pub name: String,
+    #[serde(default)]
+    pub icon: String,
+    pub color: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ProductRow {
+    #[serde(skip)]
+    pub row: usize,
+    /// Name of a category in the file or in the database.
+    pub category: String,
+    pub name: String,
+    #[serde(default)]
+    pub sku: Option<String>,
+    pub price: f64,
+    #[serde(default)]
+    pub tax_rate: f64,
+    #[serde(default)]
+    pub unit: Unit,
+    #[serde(default)]
+    pub open_price: Option<PriceRange>,
+}
+
+/// Categories and products to import or as exported. Existing entries are matched by name,
+/// products by SKU first.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MenuFile {
+    #[serde(default)]
+    pub categories: Vec<CategoryRow>,
+    #[serde(default)]
+    pub products: Vec<ProductRow>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MenuFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MenuExportQuery {
+    #[serde(default)]
+    pub format: MenuFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MenuImportQuery {
+    /// Taken from `Content-Type` when missing.
+    pub format: Option<MenuFormat>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Problem with one row of a menu file.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RowError {
+    /// `categories` or `products`.
+    pub section: String,
+    pub row: usize,
+    pub field: String,
+    pub message: String,
+}
+
+/// What an import did, or would do on a dry run. Nothing is written unless `errors` is empty.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct MenuImportReport {
+    pub dry_run: bool,
+    pub committed: bool,
+    pub categories_created: usize,
+    pub categories_updated: usize,
+    pub products_created: usize,
+    pub products_updated: usize,
+    pub unchanged: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// One line of a menu CSV. A line without a product name only defines a category; icon and
+/// color are needed the first time a new category is mentioned.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+struct CsvRow {
+    category: String,
+    category_icon: Option<String>,
+    category_color: Option<String>,
+    name: Option<String>,
+    sku: Option<String>,
+    price: Option<f64>,
+    tax_rate: Option<f64>,
+    unit: Option<Unit>,
+    open_price_min: Option<f64>,
+    open_price_max: Option<f64>,
+}
+
+impl MenuFile {
+    pub fn from_json(data: &[u8]) -> Result<Self, String> {
+        let mut menu: MenuFile = serde_json::from_slice(data).map_err(|err| format!("Invalid menu JSON: {}", err))?;
+        for (index, category) in menu.categories.iter_mut().enumerate() {
+            category.row = index + 1;
+        }
+        for (index, product) in menu.products.iter_mut().enumerate() {
+            product.row = index + 1;
+        }
+
+        Ok(menu)
+    }
+
+    /// Lines that cannot be read are reported as errors rather than failing the whole file,
+    /// so a dry run lists all of them.
+    pub fn from_csv(data: &[u8]) -> (Self, Vec<RowError>) {
+        let mut menu = MenuFile::default();
+        let mut errors = Vec::new();
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+        let headers = reader.headers().cloned().unwrap_or_default();
+
+        for (index, record) in reader.deserialize::<CsvRow>().enumerate() {
+            // The header is line 1.
+            let row = index + 2;
+            let record = match record {
+                Ok(record) => record,
+                Err(err) => {
+                    errors.push(RowError {
+                        section: "products".to_string(),
+                        row: err.position().map(|position| position.line() as usize).unwrap_or(row),
+                        field: csv_error_field(&err, &headers),
+                        message: err.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            if record.category_icon.is_some() || record.category_color.is_some() {
+                let category = CategoryRow {
+                    row,
+                    name: record.category.clone(),
+                    icon: record.category_icon.unwrap_or_default(),
+                    color: record.category_color.unwrap_or_default(),
+                };
+                // Categories repeat on every product line, they only have to agree.
+                match menu.categories.iter().find(|earlier| earlier.name.eq_ignore_ascii_case(&category.name)) {
+                    Some(earlier) if earlier.icon != category.icon || earlier.color != category.color => errors.push(RowError {
+                        section: "categories".to_string(),
+                        row,
+                        field: "category".to_string(),
+                        message: format!("icon or color differ from line {}", earlier.row),
+                    }),
+                    Some(_) => {}
+                    None => menu.categories.push(category),
+                }
+            }
+
+            let open_price = match (record.open_price_min, record.open_price_max) {
+                (Some(min), Some(max)) => Some(PriceRange { min, max }),
+                (None, None) => None,
+                (min, _) => {
+                    errors.push(RowError {
+                        section: "products".to_string(),
+                        row,
+                        field: if min.is_some() { "open_price_max" } else { "open_price_min" }.to_string(),
+                        message: "must be set together with the other bound".to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            match (record.name, record.price) {
+                (Some(name), Some(price)) => menu.products.push(ProductRow {
+                    row,
+                    category: record.category,
+                    name,
+                    sku: record.sku,
+                    price,
+                    tax_rate: record.tax_rate.unwrap_or_default(),
+                    unit: record.unit.unwrap_or_default(),
+                    open_price,
+                }),
+                (Some(_), None) => errors.push(RowError {
+                    section: "products".to_string(),
+                    row,
+                    field: "price".to_string(),
+                    message: "must be set".to_string(),
+                }),
+                (None, _) if record.sku.is_some() || record.price.is_some() => errors.push(RowError {
+                    section: "products".to_string(),
+                    row,
+                    field: "name".to_string(),
+                    message: "must be set".to_string(),
+                }),
+                (None, _) => {}
+            }
+        }
+
+        (menu, errors)
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>, String> {
+        serde_json::to_vec_pretty(self).map_err(|err| err.to_string())
+    }
+
+    /// Every product on its own line with its category's icon and color; categories without
+    /// products get a line of their own.
+    pub fn to_csv(&self) -> Result<Vec<u8>, String> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let category = |name: &str| self.categories.iter().find(|category| category.name == name);
+
+        for row in &self.categories {
+            if !self.products.iter().any(|product| product.category == row.name) {
+                writer.serialize(CsvRow {
+                    category: row.name.clone(),
+                    category_icon: Some(row.icon.clone()),
+                    category_color: Some(row.color.clone()),
+                    ..CsvRow::default()
+                }).map_err(|err| err.to_string())?;
+            }
+        }
+        for product in &self.products {
+            writer.serialize(CsvRow {
+                category: product.category.clone(),
+                category_icon: category(&product.category).map(|category| category.icon.clone()),
+                category_color: category(&product.category).map(|category| category.color.clone()),
+                name: Some(product.name.clone()),
+                sku: product.sku.clone(),
+                price: Some(product.price),
+                tax_rate: Some(product.tax_rate),
+                unit: Some(product.unit),
+                open_price_min: product.open_price.map(|range| range.min),
+                open_price_max: product.open_price.map(|range| range.max),
+            }).map_err(|err| err.to_string())?;
+        }
+
+        writer.into_inner().map_err(|err| err.to_string())
+    }
+}
+
+fn csv_error_field(err: &csv::Error, headers: &csv::StringRecord) -> String {
+    match err.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.field()
+            .and_then(|index| headers.get(index as usize))
+            .unwrap_or_default()
+            .to_string(),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,27 @@
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use crate::models::CollectionName;
+
+const MIGRATIONS_COLL_NAME: &str = "schema_migrations";
+
+/// A migration that has been applied to the database, keyed by its version.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct AppliedMigration {
+    pub _id: u32,
+    pub name: String,
+    pub applied_at: DateTime,
+}
+
+impl CollectionName for AppliedMigration {
+    fn collection_name() -> &'static str {
+        MIGRATIONS_COLL_NAME
+    }
+}
+
+/// Where the database stands compared to the migrations this build knows about.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MigrationStatus {
+    pub current_version: u32,
+    pub latest_version: u32,
+    pub pending: Vec<String>,
+}
@@ -3,7 +3,17 @@ pub mod orders;
 pub mod products;
 pub mod tables;
 pub mod categories;
+pub mod reports;
+pub mod analytics;
+pub mod shifts;
+pub mod roles;
+pub mod devices;
+pub mod audit;
+pub mod validation;
+pub mod migrations;
+pub mod menu;
+pub mod backup;
 
 pub trait CollectionName {
     fn collection_name() -> &'static str;
-}
\ No newline at end of file
+}
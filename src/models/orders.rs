@@ -1,7 +1,8 @@
 use mongodb::bson::{DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 use crate::models::CollectionName;
-use crate::models::products::{ProductInOrder, ProductId, ProductIdWithQuantity};
+use crate::models::devices::DeviceId;
+use crate::models::products::{ProductInOrder, ProductId, ProductIdWithQuantity, ScaleReading};
 use crate::models::tables::{TableInOrder, TableId};
 use crate::models::waiters::{WaiterInOrder, WaiterId};
 
@@ -9,10 +10,14 @@ const ORDERS_COLL_NAME: &str = "orders";
 
 pub type OrderId = Uuid;
 
+pub type CustomLineId = Uuid;
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct NewOrder {
     pub waiter_id: WaiterId,
     pub table_id: TableId,
+    #[serde(default)]
+    pub covers: u32,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -22,6 +27,25 @@ pub struct Order {
     pub table_id: TableId,
     pub products: Vec<ProductIdWithQuantity>,
     pub created_at: DateTime,
+    #[serde(default)]
+    pub covers: u32,
+    #[serde(default)]
+    pub payments: Vec<Payment>,
+    #[serde(default)]
+    pub discounts: Vec<Discount>,
+    #[serde(default)]
+    pub voids: Vec<VoidedProduct>,
+    #[serde(default)]
+    pub refunds: Vec<Refund>,
+    #[serde(default)]
+    pub closed_at: Option<DateTime>,
+    /// Device the order was opened on.
+    #[serde(default)]
+    pub device_id: Option<DeviceId>,
+    #[serde(default)]
+    pub mutations: Vec<OrderMutation>,
+    #[serde(default)]
+    pub custom_lines: Vec<CustomLine>,
 }
 
 impl CollectionName for Order {
@@ -36,7 +60,230 @@ pub struct OrderAPI {
     pub waiter: WaiterInOrder,
     pub table: TableInOrder,
     pub products: Vec<ProductInOrder>,
+    pub custom_lines: Vec<CustomLine>,
     pub sum: f64,
     pub created_at: DateTime,
+    pub covers: u32,
+    pub payments: Vec<Payment>,
+    pub discounts: Vec<Discount>,
+    pub voids: Vec<VoidedProduct>,
+    pub refunds: Vec<Refund>,
+    pub closed_at: Option<DateTime>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewCustomLine {
+    pub name: String,
+    pub price: f64,
+    #[serde(default)]
+    pub tax_rate: f64,
+    #[serde(default = "one")]
+    pub quantity: f64,
+}
+
+/// One-off item sold without a product behind it, e.g. "misc food".
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CustomLine {
+    pub _id: CustomLineId,
+    pub name: String,
+    pub price: f64,
+    pub tax_rate: f64,
+    pub quantity: f64,
+    #[serde(default)]
+    pub sent_quantity: f64,
+    pub added_at: DateTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CustomLineQuery {
+    pub line_id: CustomLineId,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tender {
+    Cash,
+    Card,
+    Voucher,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewPayment {
+    pub tender: Tender,
+    pub amount: f64,
+    #[serde(default)]
+    pub tip: f64,
 }
 
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Payment {
+    pub tender: Tender,
+    pub amount: f64,
+    #[serde(default)]
+    pub tip: f64,
+    pub paid_at: DateTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Discount {
+    pub reason: String,
+    pub amount: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasonCode {
+    WrongItem,
+    CustomerRequest,
+    QualityIssue,
+    KitchenError,
+    Comp,
+    #[default]
+    Other,
+}
+
+/// A manager vouching for a void or refund on a waiter's terminal.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ManagerApproval {
+    pub waiter_id: WaiterId,
+    pub pin: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewVoid {
+    /// A product on the order or the id of a custom line.
+    pub product_id: ProductId,
+    #[serde(default = "one")]
+    pub quantity: f64,
+    pub reason: ReasonCode,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub approval: Option<ManagerApproval>,
+}
+
+/// Change to one product line of an order, as part of an [`OrderBatch`].
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum LineOperation {
+    /// A scale reading, when given, replaces `quantity`. `price` is required for open-price
+    /// products and refused for the rest.
+    Add {
+        product_id: ProductId,
+        #[serde(default = "one")]
+        quantity: f64,
+        #[serde(default)]
+        scale: Option<ScaleReading>,
+        #[serde(default)]
+        price: Option<f64>,
+    },
+    /// Only what was not sent to the kitchen yet can be removed.
+    Remove {
+        product_id: ProductId,
+        #[serde(default = "one")]
+        quantity: f64,
+    },
+    /// Needs either `quantity` or `scale`.
+    SetQuantity {
+        product_id: ProductId,
+        #[serde(default)]
+        quantity: Option<f64>,
+        #[serde(default)]
+        scale: Option<ScaleReading>,
+    },
+}
+
+impl LineOperation {
+    pub fn product_id(&self) -> &ProductId {
+        match self {
+            LineOperation::Add { product_id, .. }
+            | LineOperation::Remove { product_id, .. }
+            | LineOperation::SetQuantity { product_id, .. } => product_id,
+        }
+    }
+
+    pub fn action(&self) -> OrderAction {
+        match self {
+            LineOperation::Add { .. } => OrderAction::AddProduct,
+            LineOperation::Remove { .. } => OrderAction::RemoveProduct,
+            LineOperation::SetQuantity { .. } => OrderAction::SetQuantity,
+        }
+    }
+}
+
+/// Line operations applied in order, all or none of them.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OrderBatch {
+    pub operations: Vec<LineOperation>,
+}
+
+fn one() -> f64 {
+    1.0
+}
+
+/// Snapshot of a product line taken off an order after it was sent to the kitchen, priced at the
+/// moment it was voided.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct VoidedProduct {
+    pub _id: ProductId,
+    pub name: String,
+    pub price: f64,
+    pub quantity: f64,
+    pub voided_at: DateTime,
+    #[serde(default)]
+    pub reason: ReasonCode,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub approved_by: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewRefund {
+    pub tender: Tender,
+    pub amount: f64,
+    pub reason: ReasonCode,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub approval: Option<ManagerApproval>,
+}
+
+/// Money paid back on a closed order. Reports count it against its tender on the day it happened.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Refund {
+    pub tender: Tender,
+    pub amount: f64,
+    pub reason: ReasonCode,
+    #[serde(default)]
+    pub note: Option<String>,
+    pub approved_by: Option<String>,
+    pub refunded_at: DateTime,
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderAction {
+    Open,
+    AddProduct,
+    RemoveProduct,
+    SetQuantity,
+    AddCustomLine,
+    RemoveCustomLine,
+    AddPayment,
+    AddDiscount,
+    Send,
+    Void,
+    Refund,
+    Close,
+}
+
+/// Which device changed an order, and how.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OrderMutation {
+    pub action: OrderAction,
+    pub device_id: Option<DeviceId>,
+    pub at: DateTime,
+}
@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter};
 use mongodb::bson::{Uuid};
 use serde::{Deserialize, Serialize};
 use crate::models::categories::{Category, CategoryId};
@@ -7,11 +8,119 @@ const PRODUCTS_COLL_NAME: &str = "products";
 
 pub type ProductId = Uuid;
 
+/// Unit a product is priced and ordered in. Pieces are counted, the rest measured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    #[default]
+    Piece,
+    Kilogram,
+    Gram,
+    Litre,
+    Millilitre,
+}
+
+impl Unit {
+    /// Decimal places quantities in this unit are rounded to: whole pieces, grams and
+    /// millilitres.
+    pub fn precision(&self) -> i32 {
+        match self {
+            Unit::Piece | Unit::Gram | Unit::Millilitre => 0,
+            Unit::Kilogram | Unit::Litre => 3,
+        }
+    }
+
+    pub fn round(&self, quantity: f64) -> f64 {
+        let factor = 10f64.powi(self.precision());
+        (quantity * factor).round() / factor
+    }
+
+    /// `quantity` of this unit expressed in `to`, if both measure the same thing.
+    pub fn convert(&self, quantity: f64, to: Unit) -> Option<f64> {
+        let base = |unit: Unit| match unit {
+            Unit::Piece => None,
+            Unit::Kilogram => Some(("mass", 1000.0)),
+            Unit::Gram => Some(("mass", 1.0)),
+            Unit::Litre => Some(("volume", 1000.0)),
+            Unit::Millilitre => Some(("volume", 1.0)),
+        };
+
+        match (base(*self), base(to)) {
+            (Some((from_dimension, from_factor)), Some((to_dimension, to_factor))) if from_dimension == to_dimension => {
+                Some(quantity * from_factor / to_factor)
+            }
+            _ if *self == to => Some(quantity),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Unit::Piece => "pcs",
+            Unit::Kilogram => "kg",
+            Unit::Gram => "g",
+            Unit::Litre => "l",
+            Unit::Millilitre => "ml",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Reading taken off a scale for a product sold by weight or volume.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ScaleReading {
+    pub gross: f64,
+    /// Weight of the container, taken off `gross`.
+    #[serde(default)]
+    pub tare: f64,
+    pub unit: Unit,
+    /// Scales flag readings taken while the load still moves; those are refused.
+    #[serde(default = "stable_by_default")]
+    pub stable: bool,
+}
+
+fn stable_by_default() -> bool {
+    true
+}
+
+impl ScaleReading {
+    pub fn net(&self) -> f64 {
+        self.gross - self.tare
+    }
+}
+
+/// Bounds for the price of an open-price product, which is entered when it is ordered.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PriceRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl PriceRange {
+    pub fn contains(&self, price: f64) -> bool {
+        price.is_finite() && (self.min..=self.max).contains(&price)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct NewProduct {
     pub name: String,
     pub price: f64,
     pub category_id: CategoryId,
+    #[serde(default)]
+    pub tax_rate: f64,
+    /// Stock keeping unit, unique when set.
+    #[serde(default)]
+    pub sku: Option<String>,
+    /// What `price` is per.
+    #[serde(default)]
+    pub unit: Unit,
+    /// Set for products priced when ordered, e.g. a daily special; `price` is then only a
+    /// suggestion.
+    #[serde(default)]
+    pub open_price: Option<PriceRange>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -20,6 +129,18 @@ pub struct Product {
     pub name: String,
     pub price: f64,
     pub category_id: CategoryId,
+    #[serde(default)]
+    pub tax_rate: f64,
+    /// Stock keeping unit, unique when set.
+    #[serde(default)]
+    pub sku: Option<String>,
+    /// What `price` is per.
+    #[serde(default)]
+    pub unit: Unit,
+    /// Set for products priced when ordered, e.g. a daily special; `price` is then only a
+    /// suggestion.
+    #[serde(default)]
+    pub open_price: Option<PriceRange>,
 }
 
 impl CollectionName for Product {
@@ -31,6 +152,19 @@ impl CollectionName for Product {
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct AddProductQuery {
     pub product_id: ProductId,
+    /// Required for open-price products, refused for the rest.
+    #[serde(default)]
+    pub price: Option<f64>,
+}
+
+/// Sets a line to an exact quantity, given directly or as a scale reading.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SetQuantityQuery {
+    pub product_id: ProductId,
+    #[serde(default)]
+    pub quantity: Option<f64>,
+    #[serde(default)]
+    pub scale: Option<ScaleReading>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -39,6 +173,10 @@ pub struct ProductAPI {
     pub name: String,
     pub price: f64,
     pub category: Category,
+    pub tax_rate: f64,
+    pub sku: Option<String>,
+    pub unit: Unit,
+    pub open_price: Option<PriceRange>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -47,11 +185,20 @@ pub struct ProductInOrder {
     pub name: String,
     pub price: f64,
     pub category: Category,
+    pub tax_rate: f64,
+    pub unit: Unit,
     pub quantity: f64,
+    pub sent_quantity: f64,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct ProductIdWithQuantity {
     pub _id: ProductId,
     pub quantity: f64,
+    /// How much of `quantity` the kitchen already has. Only the rest can simply be removed.
+    #[serde(default)]
+    pub sent_quantity: f64,
+    /// Price entered when an open-price product was ordered.
+    #[serde(default)]
+    pub price: Option<f64>,
 }
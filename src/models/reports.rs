@@ -0,0 +1,159 @@
+use std::fmt::{Display, Formatter};
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use crate::models::CollectionName;
+use crate::models::orders::Tender;
+
+const Z_REPORTS_COLL_NAME: &str = "z_reports";
+const COUNTERS_COLL_NAME: &str = "counters";
+const CLOSED_DAYS_COLL_NAME: &str = "closed_days";
+
+/// Counter the Z-report numbers are taken from.
+pub const Z_REPORT_COUNTER: &str = "z_reports";
+
+pub type ZReportId = Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportKind {
+    X,
+    Z,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Text,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ReportQuery {
+    pub day: Option<String>,
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TaxLine {
+    pub rate: f64,
+    pub net: f64,
+    pub tax: f64,
+    pub gross: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TenderLine {
+    pub tender: Tender,
+    pub count: u32,
+    pub amount: f64,
+    pub tips: f64,
+}
+
+/// Totals for one business day. Prices are tax inclusive, so `gross_sales` is what the menu
+/// says, `discounts` are subtracted from it and `net_sales` is the remainder without tax.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SalesReport {
+    pub kind: ReportKind,
+    pub business_day: String,
+    pub period_start: DateTime,
+    pub period_end: DateTime,
+    pub gross_sales: f64,
+    pub discounts: f64,
+    pub net_sales: f64,
+    pub tax: Vec<TaxLine>,
+    pub tax_total: f64,
+    pub payments: Vec<TenderLine>,
+    pub payments_total: f64,
+    pub tips_total: f64,
+    pub voids_count: u32,
+    pub voids_amount: f64,
+    /// Already subtracted from the tender lines and `payments_total`.
+    #[serde(default)]
+    pub refunds_count: u32,
+    #[serde(default)]
+    pub refunds_amount: f64,
+    pub covers: u32,
+    pub order_count: u32,
+    pub generated_at: DateTime,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ZReport {
+    pub _id: ZReportId,
+    pub number: i64,
+    pub business_day: String,
+    pub report: SalesReport,
+}
+
+impl CollectionName for ZReport {
+    fn collection_name() -> &'static str {
+        Z_REPORTS_COLL_NAME
+    }
+}
+
+/// Business day reserved by the Z report taken for it, keyed by the day. Orders and refunds
+/// can no longer be dated between `period_start` and `period_end` once it exists.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClosedDay {
+    pub _id: String,
+    pub period_start: DateTime,
+    pub period_end: DateTime,
+    pub closed_at: DateTime,
+}
+
+impl CollectionName for ClosedDay {
+    fn collection_name() -> &'static str {
+        CLOSED_DAYS_COLL_NAME
+    }
+}
+
+/// Last number handed out from a sequence.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Counter {
+    pub _id: String,
+    pub value: i64,
+}
+
+impl CollectionName for Counter {
+    fn collection_name() -> &'static str {
+        COUNTERS_COLL_NAME
+    }
+}
+
+impl Display for SalesReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let title = match self.kind {
+            ReportKind::X => "X-REPORT",
+            ReportKind::Z => "Z-REPORT",
+        };
+
+        writeln!(f, "{:^40}", title)?;
+        writeln!(f, "{:^40}", format!("Business day {}", self.business_day))?;
+        writeln!(f, "From  {}", self.period_start.try_to_rfc3339_string().unwrap_or_default())?;
+        writeln!(f, "To    {}", self.period_end.try_to_rfc3339_string().unwrap_or_default())?;
+        writeln!(f, "{}", "-".repeat(40))?;
+        writeln!(f, "{:<28}{:>12.2}", "Gross sales", self.gross_sales)?;
+        writeln!(f, "{:<28}{:>12.2}", "Discounts", -self.discounts)?;
+        writeln!(f, "{:<28}{:>12.2}", "Net sales", self.net_sales)?;
+        writeln!(f, "{}", "-".repeat(40))?;
+        for line in &self.tax {
+            writeln!(f, "{:<28}{:>12.2}", format!("Tax {:.2}% on {:.2}", line.rate, line.net), line.tax)?;
+        }
+        writeln!(f, "{:<28}{:>12.2}", "Tax total", self.tax_total)?;
+        writeln!(f, "{}", "-".repeat(40))?;
+        for line in &self.payments {
+            writeln!(f, "{:<28}{:>12.2}", format!("{:?} ({})", line.tender, line.count), line.amount)?;
+        }
+        writeln!(f, "{:<28}{:>12.2}", "Payments total", self.payments_total)?;
+        writeln!(f, "{:<28}{:>12.2}", "Tips", self.tips_total)?;
+        writeln!(f, "{}", "-".repeat(40))?;
+        writeln!(f, "{:<28}{:>12.2}", format!("Voids ({})", self.voids_count), self.voids_amount)?;
+        writeln!(f, "{:<28}{:>12.2}", format!("Refunds ({})", self.refunds_count), -self.refunds_amount)?;
+        writeln!(f, "{:<28}{:>12}", "Covers", self.covers)?;
+        writeln!(f, "{:<28}{:>12}", "Orders", self.order_count)?;
+        writeln!(f, "{}", "-".repeat(40))?;
+        write!(f, "Printed {}", self.generated_at.try_to_rfc3339_string().unwrap_or_default())
+    }
+}
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Manager,
+    Waiter,
+    Kitchen,
+}
+
+impl Role {
+    /// Maps a Keycloak role name onto a POS role. Realm roles in the shipped realm export are
+    /// prefixed with `pos-`, and the generic `pos-user` role is what floor staff get.
+    pub fn from_claim(name: &str) -> Option<Role> {
+        match name.strip_prefix("pos-").unwrap_or(name) {
+            "admin" => Some(Role::Admin),
+            "manager" => Some(Role::Manager),
+            "waiter" | "user" => Some(Role::Waiter),
+            "kitchen" => Some(Role::Kitchen),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use mongodb::bson::{DateTime, Uuid};
+use serde::{Deserialize, Serialize};
+use crate::models::CollectionName;
+use crate::models::waiters::WaiterId;
+
+const SHIFTS_COLL_NAME: &str = "shifts";
+
+pub type ShiftId = Uuid;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClockIn {
+    pub waiter_id: WaiterId,
+    pub opening_float: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClockOut {
+    pub counted_cash: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct NewCashDrop {
+    pub amount: f64,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Shift {
+    pub _id: ShiftId,
+    pub waiter_id: WaiterId,
+    pub clock_in: DateTime,
+    pub clock_out: Option<DateTime>,
+    pub breaks: Vec<Break>,
+    pub drawer: CashDrawer,
+}
+
+impl CollectionName for Shift {
+    fn collection_name() -> &'static str {
+        SHIFTS_COLL_NAME
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Break {
+    pub start: DateTime,
+    pub end: Option<DateTime>,
+}
+
+/// Cash drawer session of a shift. The expected amount is only known once the shift is
+/// closed: the opening float plus cash taken on the waiter's orders minus the drops.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CashDrawer {
+    pub opening_float: f64,
+    pub drops: Vec<CashDrop>,
+    pub cash_taken: Option<f64>,
+    pub expected_cash: Option<f64>,
+    pub counted_cash: Option<f64>,
+    pub variance: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CashDrop {
+    pub amount: f64,
+    pub reason: String,
+    pub dropped_at: DateTime,
+}
@@ -0,0 +1,247 @@
+use std::fmt::Display;
+use serde::Serialize;
+use crate::models::categories::NewCategory;
+use crate::models::orders::{Discount, LineOperation, NewCustomLine, NewOrder, NewPayment, OrderBatch};
+use crate::models::products::{NewProduct, ProductId, ScaleReading};
+use crate::models::shifts::{ClockIn, ClockOut, NewCashDrop};
+use crate::models::tables::NewTable;
+use crate::models::waiters::NewWaiter;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Every problem found with a request body, so clients can fix them all at once.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ValidationErrors {
+    pub fields: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.fields.push(FieldError { field, message: message.into() });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.is_empty() { Ok(()) } else { Err(self) }
+    }
+}
+
+impl Display for ValidationErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = self.fields
+            .iter()
+            .map(|error| format!("{} {}", error.field, error.message))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        write!(f, "{}", message)
+    }
+}
+
+/// Checks on a request body that need nothing but the body itself.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+fn check_name(errors: &mut ValidationErrors, name: &str) {
+    if name.trim().is_empty() {
+        errors.add("name", "must not be empty");
+    }
+}
+
+pub fn is_hex_color(color: &str) -> bool {
+    match color.strip_prefix('#') {
+        Some(hex) => matches!(hex.len(), 3 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+pub fn is_valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.chars().all(|c| c.is_ascii_digit())
+}
+
+impl Validate for NewCategory {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, &self.name);
+        if !is_hex_color(&self.color) {
+            errors.add("color", "must be a hex color like #a1b2c3");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for NewProduct {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, &self.name);
+        if !self.price.is_finite() || self.price <= 0.0 {
+            errors.add("price", "must be greater than zero");
+        }
+        if !(0.0..=100.0).contains(&self.tax_rate) {
+            errors.add("tax_rate", "must be between 0 and 100");
+        }
+        if self.sku.as_ref().is_some_and(|sku| sku.trim().is_empty()) {
+            errors.add("sku", "must not be empty when set");
+        }
+        if let Some(range) = &self.open_price {
+            if !range.min.is_finite() || !range.max.is_finite() || range.min < 0.0 || range.min > range.max {
+                errors.add("open_price", "must have 0 <= min <= max");
+            } else if !range.contains(self.price) {
+                errors.add("price", "must lie within open_price");
+            }
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for NewTable {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, &self.name);
+        errors.into_result()
+    }
+}
+
+impl Validate for NewWaiter {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, &self.name);
+        if !is_valid_pin(&self.code) {
+            errors.add("code", "must be 4 to 8 digits");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for NewOrder {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        // Only references to check, and those need the database.
+        Ok(())
+    }
+}
+
+impl Validate for NewCustomLine {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        check_name(&mut errors, &self.name);
+        if !self.price.is_finite() || self.price <= 0.0 {
+            errors.add("price", "must be greater than zero");
+        }
+        if !(0.0..=100.0).contains(&self.tax_rate) {
+            errors.add("tax_rate", "must be between 0 and 100");
+        }
+        if !self.quantity.is_finite() || self.quantity <= 0.0 {
+            errors.add("quantity", "must be greater than zero");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for NewPayment {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            errors.add("amount", "must be greater than zero");
+        }
+        if !self.tip.is_finite() || self.tip < 0.0 {
+            errors.add("tip", "must not be negative");
+        }
+        errors.into_result()
+    }
+}
+
+/// Whether the discount fits the order total is up to the repository.
+impl Validate for Discount {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self.reason.trim().is_empty() {
+            errors.add("reason", "must not be empty");
+        }
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            errors.add("amount", "must be greater than zero");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for ClockIn {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if !self.opening_float.is_finite() || self.opening_float < 0.0 {
+            errors.add("opening_float", "must not be negative");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for ClockOut {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if !self.counted_cash.is_finite() || self.counted_cash < 0.0 {
+            errors.add("counted_cash", "must not be negative");
+        }
+        errors.into_result()
+    }
+}
+
+impl Validate for NewCashDrop {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if !self.amount.is_finite() || self.amount <= 0.0 {
+            errors.add("amount", "must be greater than zero");
+        }
+        errors.into_result()
+    }
+}
+
+/// Keeps a single request from holding the order for long.
+pub const MAX_BATCH_OPERATIONS: usize = 100;
+
+impl Validate for OrderBatch {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self.operations.is_empty() {
+            errors.add("operations", "must not be empty");
+        }
+        if self.operations.len() > MAX_BATCH_OPERATIONS {
+            errors.add("operations", format!("must not have more than {} entries", MAX_BATCH_OPERATIONS));
+        }
+        for operation in &self.operations {
+            let product_id = operation.product_id();
+            match operation {
+                LineOperation::Add { scale: Some(scale), .. } => check_scale(&mut errors, product_id, scale),
+                LineOperation::Add { quantity, .. } | LineOperation::Remove { quantity, .. } if !quantity.is_finite() || *quantity <= 0.0 => {
+                    errors.add("quantity", format!("must be greater than zero for product {}", product_id));
+                }
+                LineOperation::SetQuantity { quantity: Some(_), scale: Some(_), .. } | LineOperation::SetQuantity { quantity: None, scale: None, .. } => {
+                    errors.add("quantity", format!("needs either a quantity or a scale reading for product {}", product_id));
+                }
+                LineOperation::SetQuantity { scale: Some(scale), .. } => check_scale(&mut errors, product_id, scale),
+                LineOperation::SetQuantity { quantity: Some(quantity), .. } if !quantity.is_finite() || *quantity < 0.0 => {
+                    errors.add("quantity", format!("must not be negative for product {}", product_id));
+                }
+                _ => {}
+            }
+        }
+        errors.into_result()
+    }
+}
+
+fn check_scale(errors: &mut ValidationErrors, product_id: &ProductId, scale: &ScaleReading) {
+    if !scale.gross.is_finite() || !scale.tare.is_finite() || scale.tare < 0.0 {
+        errors.add("scale", format!("has an invalid reading for product {}", product_id));
+    } else if scale.net() <= 0.0 {
+        errors.add("scale", format!("must read more than the tare for product {}", product_id));
+    }
+    if !scale.stable {
+        errors.add("scale", format!("reading for product {} is not stable yet", product_id));
+    }
+}
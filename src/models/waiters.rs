@@ -1,8 +1,10 @@
-use mongodb::bson::Uuid;
+use mongodb::bson::{DateTime, Uuid};
 use serde::{Deserialize, Serialize};
 use crate::models::CollectionName;
+use crate::models::roles::Role;
 
 const WAITERS_COLL_NAME: &str = "waiters";
+const WAITER_SESSIONS_COLL_NAME: &str = "waiter_sessions";
 
 pub type WaiterId = Uuid;
 
@@ -10,13 +12,30 @@ pub type WaiterId = Uuid;
 pub struct NewWaiter {
     pub name: String,
     pub code: String,
+    /// Roles carried by locally issued tokens. Defaults to `waiter`.
+    #[serde(default)]
+    pub roles: Vec<Role>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Waiter {
     pub _id: WaiterId,
     pub name: String,
-    pub code: String,
+    #[serde(default)]
+    pub pin_hash: String,
+    #[serde(default)]
+    pub failed_attempts: u32,
+    #[serde(default)]
+    pub locked_until: Option<DateTime>,
+    #[serde(default = "default_waiter_roles")]
+    pub roles: Vec<Role>,
+    /// Plaintext PIN of waiters created before PINs were hashed. Never written back.
+    #[serde(default, skip_serializing)]
+    pub code: Option<String>,
+}
+
+pub fn default_waiter_roles() -> Vec<Role> {
+    vec![Role::Waiter]
 }
 
 impl CollectionName for Waiter {
@@ -35,4 +54,37 @@ impl CollectionName for WaiterInOrder {
     fn collection_name() -> &'static str {
         WAITERS_COLL_NAME
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WaiterLogin {
+    pub waiter_id: WaiterId,
+    pub pin: String,
+}
+
+/// Server side half of a waiter session. Only the SHA-256 of the token is stored, as `_id`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WaiterSession {
+    pub _id: String,
+    pub waiter_id: WaiterId,
+    pub terminal_id: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+}
+
+impl CollectionName for WaiterSession {
+    fn collection_name() -> &'static str {
+        WAITER_SESSIONS_COLL_NAME
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct WaiterSessionToken {
+    pub token: String,
+    pub waiter: WaiterInOrder,
+    pub terminal_id: String,
+    pub expires_at: DateTime,
+    /// Bearer token for the waiter, only issued in local auth mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_token: Option<String>,
+}
@@ -0,0 +1,283 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, from_document, Document};
+use serde::de::DeserializeOwned;
+use crate::models::analytics::{AnalyticsFilter, CategorySales, HourlySales, ProductMix, TicketStats, WaiterPerformance};
+use crate::models::categories::Category;
+use crate::models::CollectionName;
+use crate::models::orders::Order;
+use crate::models::products::Product;
+use crate::models::waiters::WaiterInOrder;
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+impl Repository {
+    pub async fn aggregate_orders<T>(&self, pipeline: Vec<Document>) -> Result<Vec<T>, RepoError>
+        where
+            T: DeserializeOwned,
+    {
+        let mut cursor = self.get_collection::<Order>().aggregate(pipeline, None).await?;
+
+        let mut results: Vec<T> = Vec::new();
+        while let Some(result) = cursor.try_next().await? {
+            results.push(from_document(result).map_err(RepoError::BsonDeserializationError)?);
+        }
+
+        Ok(results)
+    }
+
+    pub async fn query_product_mix(&self, filter: &AnalyticsFilter, limit: i64) -> Result<ProductMix, RepoError> {
+        let mut pipeline = product_lines_pipeline(filter);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": "$products._id",
+                "name": { "$first": "$product.name" },
+                "category_id": { "$first": "$product.category_id" },
+                "quantity": { "$sum": "$products.quantity" },
+                "revenue": { "$sum": { "$multiply": ["$products.quantity", line_price()] } },
+            }
+        });
+        pipeline.push(doc! {
+            "$facet": {
+                "top_by_quantity": [{ "$sort": { "quantity": -1, "_id": 1 } }, { "$limit": limit }],
+                "bottom_by_quantity": [{ "$sort": { "quantity": 1, "_id": 1 } }, { "$limit": limit }],
+                "top_by_revenue": [{ "$sort": { "revenue": -1, "_id": 1 } }, { "$limit": limit }],
+                "bottom_by_revenue": [{ "$sort": { "revenue": 1, "_id": 1 } }, { "$limit": limit }],
+            }
+        });
+
+        let result = self.aggregate_orders::<ProductMix>(pipeline).await?.pop();
+
+        Ok(result.unwrap_or(ProductMix {
+            top_by_quantity: vec![],
+            bottom_by_quantity: vec![],
+            top_by_revenue: vec![],
+            bottom_by_revenue: vec![],
+        }))
+    }
+
+    pub async fn query_category_sales(&self, filter: &AnalyticsFilter) -> Result<Vec<CategorySales>, RepoError> {
+        let mut pipeline = product_lines_pipeline(filter);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": "$product.category_id",
+                "quantity": { "$sum": "$products.quantity" },
+                "revenue": { "$sum": { "$multiply": ["$products.quantity", line_price()] } },
+            }
+        });
+        pipeline.push(doc! {
+            "$lookup": {
+                "from": Category::collection_name(),
+                "localField": "_id",
+                "foreignField": "_id",
+                "as": "category",
+            }
+        });
+        pipeline.push(doc! {
+            "$project": {
+                "quantity": 1,
+                "revenue": 1,
+                "name": { "$ifNull": [{ "$first": "$category.name" }, ""] },
+            }
+        });
+        pipeline.push(doc! { "$sort": { "revenue": -1 } });
+
+        let mut results = self.aggregate_orders::<CategorySales>(pipeline).await?;
+
+        let total = results.iter().fold(0.0, |acc, category| acc + category.revenue);
+        for category in results.iter_mut() {
+            category.share = if total > 0.0 { category.revenue / total } else { 0.0 };
+        }
+
+        Ok(results)
+    }
+
+    pub async fn query_hourly_sales(&self, filter: &AnalyticsFilter, timezone: &str) -> Result<Vec<HourlySales>, RepoError> {
+        let mut pipeline = order_totals_pipeline(filter);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": {
+                    "day_of_week": { "$isoDayOfWeek": { "date": "$created_at", "timezone": timezone } },
+                    "hour": { "$hour": { "date": "$created_at", "timezone": timezone } },
+                },
+                "orders": { "$sum": 1 },
+                "revenue": { "$sum": "$revenue" },
+            }
+        });
+        pipeline.push(doc! {
+            "$project": {
+                "_id": 0,
+                "day_of_week": "$_id.day_of_week",
+                "hour": "$_id.hour",
+                "orders": 1,
+                "revenue": 1,
+            }
+        });
+        pipeline.push(doc! { "$sort": { "day_of_week": 1, "hour": 1 } });
+
+        self.aggregate_orders::<HourlySales>(pipeline).await
+    }
+
+    pub async fn query_ticket_stats(&self, filter: &AnalyticsFilter) -> Result<TicketStats, RepoError> {
+        let mut pipeline = order_totals_pipeline(filter);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": null,
+                "orders": { "$sum": 1 },
+                "covers": { "$sum": "$covers" },
+                "revenue": { "$sum": "$revenue" },
+            }
+        });
+        pipeline.push(doc! {
+            "$project": {
+                "_id": 0,
+                "orders": 1,
+                "covers": 1,
+                "revenue": 1,
+                "average_ticket": { "$cond": [{ "$gt": ["$orders", 0] }, { "$divide": ["$revenue", "$orders"] }, 0.0] },
+                "average_per_cover": { "$cond": [{ "$gt": ["$covers", 0] }, { "$divide": ["$revenue", "$covers"] }, 0.0] },
+            }
+        });
+
+        let result = self.aggregate_orders::<TicketStats>(pipeline).await?.pop();
+
+        Ok(result.unwrap_or(TicketStats {
+            orders: 0,
+            covers: 0,
+            revenue: 0.0,
+            average_ticket: 0.0,
+            average_per_cover: 0.0,
+        }))
+    }
+
+    pub async fn query_waiter_performance(&self, filter: &AnalyticsFilter) -> Result<Vec<WaiterPerformance>, RepoError> {
+        let mut pipeline = order_totals_pipeline(filter);
+        pipeline.push(doc! {
+            "$group": {
+                "_id": "$waiter_id",
+                "orders": { "$sum": 1 },
+                "covers": { "$sum": "$covers" },
+                "revenue": { "$sum": "$revenue" },
+                "payments_total": { "$sum": { "$sum": "$payments.amount" } },
+                "cash_total": {
+                    "$sum": {
+                        "$sum": {
+                            "$map": {
+                                "input": { "$filter": { "input": "$payments", "cond": { "$eq": ["$$this.tender", "cash"] } } },
+                                "in": "$$this.amount",
+                            }
+                        }
+                    }
+                },
+                "tips": { "$sum": { "$sum": "$payments.tip" } },
+                "voids": { "$sum": "$voids" },
+                // $avg skips orders that are still open, since closed_at is missing on them.
+                "average_turn_millis": { "$avg": { "$subtract": ["$closed_at", "$created_at"] } },
+            }
+        });
+        pipeline.push(doc! {
+            "$lookup": {
+                "from": WaiterInOrder::collection_name(),
+                "localField": "_id",
+                "foreignField": "_id",
+                "as": "waiter",
+            }
+        });
+        pipeline.push(doc! {
+            "$project": {
+                "name": { "$ifNull": [{ "$first": "$waiter.name" }, ""] },
+                "orders": 1,
+                "covers": 1,
+                "revenue": 1,
+                "average_ticket": { "$cond": [{ "$gt": ["$orders", 0] }, { "$divide": ["$revenue", "$orders"] }, 0.0] },
+                "payments_total": 1,
+                "cash_total": 1,
+                "tips": 1,
+                "voids": 1,
+                "average_turn_minutes": { "$divide": ["$average_turn_millis", 60000] },
+            }
+        });
+        pipeline.push(doc! { "$sort": { "revenue": -1 } });
+
+        self.aggregate_orders::<WaiterPerformance>(pipeline).await
+    }
+}
+
+fn match_stage(filter: &AnalyticsFilter) -> Document {
+    let mut stage = doc! { "created_at": { "$gte": filter.from, "$lt": filter.to } };
+    if let Some(waiter_id) = filter.waiter_id {
+        stage.insert("waiter_id", waiter_id);
+    }
+
+    doc! { "$match": stage }
+}
+
+/// One document per product line with the current product joined in as `product`. Custom lines
+/// have no product behind them and are left out.
+fn product_lines_pipeline(filter: &AnalyticsFilter) -> Vec<Document> {
+    vec![
+        match_stage(filter),
+        doc! { "$unwind": "$products" },
+        doc! {
+            "$lookup": {
+                "from": Product::collection_name(),
+                "localField": "products._id",
+                "foreignField": "_id",
+                "as": "product",
+            }
+        },
+        doc! { "$unwind": "$product" },
+    ]
+}
+
+/// One document per order with the revenue of its product and custom lines summed up, empty
+/// orders included.
+/// Payments and the number of voided lines are carried along for the per-waiter summaries.
+fn order_totals_pipeline(filter: &AnalyticsFilter) -> Vec<Document> {
+    vec![
+        match_stage(filter),
+        doc! { "$unwind": { "path": "$products", "preserveNullAndEmptyArrays": true } },
+        doc! {
+            "$lookup": {
+                "from": Product::collection_name(),
+                "localField": "products._id",
+                "foreignField": "_id",
+                "as": "product",
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": "$_id",
+                "waiter_id": { "$first": "$waiter_id" },
+                "created_at": { "$first": "$created_at" },
+                "closed_at": { "$first": "$closed_at" },
+                "covers": { "$first": { "$ifNull": ["$covers", 0] } },
+                "payments": { "$first": { "$ifNull": ["$payments", []] } },
+                "voids": { "$first": { "$size": { "$ifNull": ["$voids", []] } } },
+                "product_revenue": {
+                    "$sum": {
+                        "$multiply": [
+                            { "$ifNull": ["$products.quantity", 0] },
+                            { "$ifNull": ["$products.price", { "$ifNull": [{ "$first": "$product.price" }, 0] }] },
+                        ]
+                    }
+                },
+                "custom_revenue": {
+                    "$first": {
+                        "$sum": {
+                            "$map": {
+                                "input": { "$ifNull": ["$custom_lines", []] },
+                                "in": { "$multiply": ["$$this.quantity", "$$this.price"] },
+                            }
+                        }
+                    }
+                },
+            }
+        },
+        doc! { "$set": { "revenue": { "$add": ["$product_revenue", "$custom_revenue"] } } },
+    ]
+}
+
+/// Price a line was sold at: the one entered for an open-price product, the menu price otherwise.
+fn line_price() -> Document {
+    doc! { "$ifNull": ["$products.price", "$product.price"] }
+}
@@ -0,0 +1,123 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, from_bson, Bson, DateTime, Document, Uuid};
+use mongodb::options::FindOptions;
+use crate::models::CollectionName;
+use crate::models::audit::{AuditEntry, AuditEntryId, FieldChange};
+use crate::models::waiters::WaiterSession;
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+/// Secrets are never copied into the audit log; only the fact that they changed is.
+const REDACTED_FIELDS: &[&str] = &["pin_hash", "code", "credential_hash", "pairing_code_hash"];
+const REDACTED: &str = "[redacted]";
+/// Left out of diffs: an order's `mutations` only ever grow by the entry the audit log records
+/// anyway.
+const UNDIFFED_FIELDS: &[&str] = &["mutations"];
+
+impl Repository {
+    /// Raw stored document, for diffing before and after a change.
+    pub async fn snapshot<T: CollectionName>(&self, id: &Uuid) -> Result<Option<Document>, RepoError> {
+        let result = self.get_raw_collection(T::collection_name())
+            .find_one(doc! { "_id": id }, None)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Best effort: the entry is written after the change it describes, which is committed by
+    /// then, so a failure here is logged rather than failing the request.
+    pub async fn audit<T: CollectionName>(&self, action: &str, entity_id: Option<Uuid>, before: Option<&Document>, after: Option<&Document>) -> Result<(), RepoError> {
+        if T::collection_name() == WaiterSession::collection_name() {
+            return Ok(());
+        }
+
+        let entry = AuditEntry {
+            _id: AuditEntryId::new(),
+            at: DateTime::now(),
+            actor: self.actor(),
+            action: action.to_string(),
+            entity: T::collection_name().to_string(),
+            entity_id,
+            changes: diff_documents(before, after),
+        };
+
+        if let Err(err) = self.get_collection::<AuditEntry>().insert_one(entry, None).await {
+            tracing::error!("Writing the audit entry for {} {} failed: {}", action, T::collection_name(), err);
+        }
+
+        Ok(())
+    }
+
+    /// Records the difference between `before` and the document as it is stored now.
+    pub async fn audit_update<T: CollectionName>(&self, action: &str, id: &Uuid, before: Option<Document>) -> Result<(), RepoError> {
+        let after = match self.snapshot::<T>(id).await {
+            Ok(after) => after,
+            Err(err) => {
+                tracing::error!("Reading {} {} for the audit log failed: {}", T::collection_name(), id, err);
+                return Ok(());
+            }
+        };
+        if before.is_none() && after.is_none() {
+            return Ok(());
+        }
+
+        self.audit::<T>(action, Some(*id), before.as_ref(), after.as_ref()).await
+    }
+
+    pub async fn query_audit_log(&self, filter: Document, limit: i64) -> Result<Vec<AuditEntry>, RepoError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "at": -1 })
+            .limit(limit)
+            .build();
+
+        let cursor = self.get_collection::<AuditEntry>()
+            .find(filter, options)
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+}
+
+pub fn document_id(document: &Document) -> Option<Uuid> {
+    document.get("_id").cloned().and_then(|id| from_bson::<Uuid>(id).ok())
+}
+
+/// Fields that differ between two versions of a document, in the order they appear. Arrays are
+/// compared element by element, so appending to one records only the new element as
+/// `field.index`.
+pub fn diff_documents(before: Option<&Document>, after: Option<&Document>) -> Vec<FieldChange> {
+    let empty = Document::new();
+    let before = before.unwrap_or(&empty);
+    let after = after.unwrap_or(&empty);
+
+    let redact = |field: &str, value: Option<&Bson>| match value {
+        Some(_) if REDACTED_FIELDS.contains(&field) => Some(Bson::String(REDACTED.to_string())),
+        value => value.cloned(),
+    };
+
+    let mut changes = Vec::new();
+    let keys = before.keys().chain(after.keys().filter(|key| !before.contains_key(key.as_str())));
+    for key in keys.filter(|key| !UNDIFFED_FIELDS.contains(&key.as_str())) {
+        match (before.get(key.as_str()), after.get(key.as_str())) {
+            (old, new) if old == new => {}
+            (Some(Bson::Array(old)), Some(Bson::Array(new))) if !REDACTED_FIELDS.contains(&key.as_str()) => {
+                for index in 0..old.len().max(new.len()) {
+                    if old.get(index) != new.get(index) {
+                        changes.push(FieldChange {
+                            field: format!("{}.{}", key, index),
+                            before: old.get(index).cloned(),
+                            after: new.get(index).cloned(),
+                        });
+                    }
+                }
+            }
+            (old, new) => changes.push(FieldChange {
+                field: key.clone(),
+                before: redact(key, old),
+                after: redact(key, new),
+            }),
+        }
+    }
+
+    changes
+}
@@ -0,0 +1,300 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io::Read;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::{FindOptions, InsertManyOptions};
+use crate::models::audit::{AuditEntry, AuditEntryId};
+use crate::models::backup::{BackupCollection, BackupManifest, RestoreReport, BACKUP_FORMAT_VERSION};
+use crate::models::migrations::AppliedMigration;
+use crate::models::CollectionName;
+use crate::repo::error::RepoError;
+use crate::repo::migrations::latest_version;
+use crate::repo::repository::Repository;
+
+const MANIFEST_PATH: &str = "manifest.json";
+const COLLECTIONS_DIR: &str = "collections";
+
+/// Every collection of the database, read into memory. As an archive it is a tar holding
+/// `manifest.json` and one `collections/<name>.jsonl` of canonical Extended JSON per collection,
+/// so types like UUIDs and dates survive the round trip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backup {
+    pub manifest: BackupManifest,
+    pub collections: Vec<(String, Vec<Document>)>,
+}
+
+impl Backup {
+    pub fn to_archive(&self) -> Result<Vec<u8>, RepoError> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+        let mut append = |path: &str, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data)
+                .map_err(|err| RepoError::InvalidState(format!("Writing {} to the backup failed: {}", path, err)))
+        };
+
+        let manifest = serde_json::to_vec_pretty(&self.manifest)
+            .map_err(|err| RepoError::InvalidState(format!("Serializing the backup manifest failed: {}", err)))?;
+        append(MANIFEST_PATH, &manifest)?;
+
+        for (name, documents) in &self.collections {
+            let mut lines = Vec::new();
+            for document in documents {
+                let json = Bson::Document(document.clone()).into_canonical_extjson();
+                serde_json::to_writer(&mut lines, &json)
+                    .map_err(|err| RepoError::InvalidState(format!("Serializing {} failed: {}", name, err)))?;
+                lines.push(b'\n');
+            }
+            append(&format!("{}/{}.jsonl", COLLECTIONS_DIR, name), &lines)?;
+        }
+
+        builder.into_inner().map_err(|err| RepoError::InvalidState(format!("Finishing the backup failed: {}", err)))
+    }
+
+    /// Reads and checks a whole archive, so nothing is restored from a damaged one.
+    pub fn from_archive(data: &[u8]) -> Result<Backup, RepoError> {
+        let invalid = |message: String| RepoError::InvalidBackup(message);
+        let mut manifest: Option<BackupManifest> = None;
+        let mut files: Vec<(String, String)> = Vec::new();
+
+        let mut archive = tar::Archive::new(data);
+        for entry in archive.entries().map_err(|err| invalid(format!("Not a tar archive: {}", err)))? {
+            let mut entry = entry.map_err(|err| invalid(format!("Damaged archive: {}", err)))?;
+            let path = entry.path().map_err(|err| invalid(err.to_string()))?.to_string_lossy().to_string();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).map_err(|err| invalid(format!("Reading {} failed: {}", path, err)))?;
+
+            if path == MANIFEST_PATH {
+                manifest = Some(serde_json::from_str(&contents).map_err(|err| invalid(format!("Invalid manifest: {}", err)))?);
+            } else {
+                files.push((path, contents));
+            }
+        }
+
+        let manifest = manifest.ok_or_else(|| invalid(format!("{} is missing", MANIFEST_PATH)))?;
+        if manifest.format_version != BACKUP_FORMAT_VERSION {
+            return Err(invalid(format!("Archive format {} is not supported, expected {}", manifest.format_version, BACKUP_FORMAT_VERSION)));
+        }
+
+        let mut collections = Vec::new();
+        for collection in &manifest.collections {
+            check_collection_name(&collection.name)?;
+            let path = format!("{}/{}.jsonl", COLLECTIONS_DIR, collection.name);
+            let contents = files.iter()
+                .find(|(file, _)| *file == path)
+                .map(|(_, contents)| contents)
+                .ok_or_else(|| invalid(format!("{} is missing", path)))?;
+
+            let documents = contents.lines()
+                .filter(|line| !line.trim().is_empty())
+                .enumerate()
+                .map(|(index, line)| {
+                    let json: serde_json::Value = serde_json::from_str(line)
+                        .map_err(|err| invalid(format!("{} line {}: {}", path, index + 1, err)))?;
+                    match Bson::try_from(json) {
+                        Ok(Bson::Document(document)) => Ok(document),
+                        Ok(_) => Err(invalid(format!("{} line {}: not a document", path, index + 1))),
+                        Err(err) => Err(invalid(format!("{} line {}: {}", path, index + 1, err))),
+                    }
+                })
+                .collect::<Result<Vec<Document>, RepoError>>()?;
+
+            if documents.len() != collection.documents {
+                return Err(invalid(format!("{} has {} documents, the manifest says {}", path, documents.len(), collection.documents)));
+            }
+            collections.push((collection.name.clone(), documents));
+        }
+
+        if let Some((path, _)) = files.iter().find(|(path, _)| !collections.iter().any(|(name, _)| *path == format!("{}/{}.jsonl", COLLECTIONS_DIR, name))) {
+            return Err(invalid(format!("{} is not listed in the manifest", path)));
+        }
+
+        let backup = Backup { manifest, collections };
+        backup.check_schema_version()?;
+
+        Ok(backup)
+    }
+
+    /// A backup from a newer build may rely on documents this one cannot read; older ones are
+    /// migrated after restoring.
+    pub fn check_schema_version(&self) -> Result<(), RepoError> {
+        if self.manifest.schema_version > latest_version() {
+            return Err(RepoError::InvalidBackup(format!(
+                "Backup is at schema version {} but this build only knows up to {}",
+                self.manifest.schema_version,
+                latest_version(),
+            )));
+        }
+
+        let recorded = self.collections.iter()
+            .find(|(name, _)| name == AppliedMigration::collection_name())
+            .map(|(_, documents)| documents.iter().filter_map(|document| document.get("_id").and_then(bson_u32)).max().unwrap_or(0))
+            .unwrap_or(0);
+        if recorded != self.manifest.schema_version {
+            return Err(RepoError::InvalidBackup(format!(
+                "Manifest says schema version {} but the backed up {} say {}",
+                self.manifest.schema_version,
+                AppliedMigration::collection_name(),
+                recorded,
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn bson_u32(value: &Bson) -> Option<u32> {
+    match value {
+        Bson::Int32(value) => u32::try_from(*value).ok(),
+        Bson::Int64(value) => u32::try_from(*value).ok(),
+        _ => None,
+    }
+}
+
+fn check_collection_name(name: &str) -> Result<(), RepoError> {
+    if name.is_empty() || name.starts_with("system.") || name.contains(['/', '\\', '$', '\0']) {
+        return Err(RepoError::InvalidBackup(format!("Invalid collection name {:?}", name)));
+    }
+
+    Ok(())
+}
+
+impl Repository {
+    /// Reads every collection, including ones added after this was written.
+    pub async fn backup(&self) -> Result<Backup, RepoError> {
+        let schema_version = self.migration_status().await?.current_version;
+        let mut names: Vec<String> = self.database().list_collection_names(None).await?
+            .into_iter()
+            .filter(|name| !name.starts_with("system."))
+            .collect();
+        names.sort();
+
+        let mut collections = Vec::new();
+        for name in names {
+            let options = FindOptions::builder().sort(doc! { "_id": 1 }).build();
+            let documents: Vec<Document> = self.get_raw_collection(&name)
+                .find(None, options)
+                .await?
+                .try_collect()
+                .await?;
+            collections.push((name, documents));
+        }
+
+        Ok(Backup {
+            manifest: BackupManifest {
+                format_version: BACKUP_FORMAT_VERSION,
+                schema_version,
+                created_at: chrono::Utc::now().to_rfc3339(),
+                created_by: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+                collections: collections.iter().map(|(name, documents)| BackupCollection {
+                    name: name.clone(),
+                    documents: documents.len(),
+                }).collect(),
+            },
+            collections,
+        })
+    }
+
+    /// Replaces the whole database with the backup, then migrates it to this build. Collections
+    /// missing from the backup end up empty. MongoDB only has transactions on replica sets, so
+    /// if writing fails half way the state from before is written back. The audit log is never
+    /// replaced: entries from the backup that it lacks are appended, and the restore itself is
+    /// logged before and after.
+    pub async fn restore(&self, backup: &Backup, dry_run: bool) -> Result<RestoreReport, RepoError> {
+        backup.check_schema_version()?;
+        let mut report = RestoreReport {
+            dry_run,
+            restored: false,
+            manifest: backup.manifest.clone(),
+            migrated: Vec::new(),
+        };
+        if dry_run {
+            return Ok(report);
+        }
+
+        let previous = self.backup().await?;
+        let audit_log = AuditEntry::collection_name();
+        let names: BTreeSet<&str> = previous.collections.iter()
+            .chain(backup.collections.iter())
+            .map(|(name, _)| name.as_str())
+            .filter(|name| *name != audit_log)
+            .collect();
+
+        self.audit_restore("restore_started").await?;
+        if let Err(err) = self.replace_collections(&names, &backup.collections).await {
+            tracing::error!("Restoring the backup failed, putting the previous state back: {}", err);
+            if let Err(rollback) = self.replace_collections(&names, &previous.collections).await {
+                tracing::error!("Putting the previous state back failed too: {}", rollback);
+            }
+            return Err(err);
+        }
+        report.restored = true;
+
+        let archived = backup.collections.iter().find(|(name, _)| name == audit_log).map(|(_, documents)| documents.as_slice());
+        self.append_audit_entries(archived.unwrap_or_default()).await?;
+        self.audit_restore("restore").await?;
+
+        report.migrated = self.migrate().await?;
+
+        Ok(report)
+    }
+
+    async fn audit_restore(&self, action: &str) -> Result<(), RepoError> {
+        let entry = AuditEntry {
+            _id: AuditEntryId::new(),
+            at: DateTime::now(),
+            actor: self.actor(),
+            action: action.to_string(),
+            entity: "database".to_string(),
+            entity_id: None,
+            changes: Vec::new(),
+        };
+        self.get_collection::<AuditEntry>().insert_one(entry, None).await?;
+
+        Ok(())
+    }
+
+    /// Adds the archived entries the live audit log does not have yet.
+    async fn append_audit_entries(&self, archived: &[Document]) -> Result<(), RepoError> {
+        let collection = self.get_raw_collection(AuditEntry::collection_name());
+        let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        let live: HashSet<String> = collection.find(None, options)
+            .await?
+            .try_collect::<Vec<Document>>()
+            .await?
+            .into_iter()
+            .filter_map(|entry| entry.get("_id").map(Bson::to_string))
+            .collect();
+
+        let missing: Vec<&Document> = archived.iter()
+            .filter(|entry| entry.get("_id").is_none_or(|id| !live.contains(&id.to_string())))
+            .collect();
+        if !missing.is_empty() {
+            let options = InsertManyOptions::builder().bypass_document_validation(true).build();
+            collection.insert_many(missing, options).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Emptied rather than dropped, so indexes and validators stay in place.
+    async fn replace_collections(&self, names: &BTreeSet<&str>, collections: &[(String, Vec<Document>)]) -> Result<(), RepoError> {
+        for name in names {
+            let collection = self.get_raw_collection(name);
+            collection.delete_many(doc! {}, None).await?;
+
+            let documents = collections.iter().find(|(collection, _)| collection == name).map(|(_, documents)| documents);
+            if let Some(documents) = documents.filter(|documents| !documents.is_empty()) {
+                // Documents from an older schema are fixed up by the migrations that follow.
+                let options = InsertManyOptions::builder().bypass_document_validation(true).build();
+                collection.insert_many(documents, options).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use crate::models::devices::{Device, DeviceId, DeviceSettings};
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+impl Repository {
+    pub async fn query_device_by_credential(&self, credential_hash: &str) -> Result<Option<Device>, RepoError> {
+        let result = self.get_collection::<Device>()
+            .find_one(doc! { "credential_hash": credential_hash, "revoked_at": null }, None)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// Starts (re)pairing. The current credential keeps working until the new code is used.
+    pub async fn device_set_pairing_code(&self, id: &DeviceId, code_hash: &str, expires_at: DateTime) -> Result<Device, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let before = self.snapshot::<Device>(id).await?;
+
+        let device = self.get_collection::<Device>().find_one_and_update(
+            doc! { "_id": id },
+            doc! {
+                "$set": { "pairing_code_hash": code_hash, "pairing_expires_at": expires_at, "revoked_at": null },
+            },
+            options,
+        ).await?.ok_or(RepoError::IdNotFound(*id))?;
+
+        self.audit_update::<Device>("start_pairing", id, before).await?;
+
+        Ok(device)
+    }
+
+    /// Swaps an unexpired pairing code for a credential. `None` when the code does not match.
+    pub async fn device_pair(&self, id: &DeviceId, code_hash: &str, credential_hash: &str) -> Result<Option<Device>, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let now = DateTime::now();
+
+        let before = self.snapshot::<Device>(id).await?;
+
+        let device = self.get_collection::<Device>().find_one_and_update(
+            doc! {
+                "_id": id,
+                "pairing_code_hash": code_hash,
+                "pairing_expires_at": { "$gt": now },
+                "revoked_at": null,
+            },
+            doc! {
+                "$set": { "credential_hash": credential_hash, "paired_at": now },
+                "$unset": { "pairing_code_hash": "", "pairing_expires_at": "" },
+            },
+            options,
+        ).await?;
+
+        if device.is_some() {
+            self.audit_update::<Device>("pair", id, before).await?;
+        }
+
+        Ok(device)
+    }
+
+    pub async fn device_update_settings(&self, id: &DeviceId, settings: DeviceSettings) -> Result<Device, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let before = self.snapshot::<Device>(id).await?;
+
+        let device = self.get_collection::<Device>().find_one_and_update(
+            doc! { "_id": id },
+            doc! { "$set": { "default_printer": settings.default_printer, "level": settings.level } },
+            options,
+        ).await?.ok_or(RepoError::IdNotFound(*id))?;
+
+        self.audit_update::<Device>("update_settings", id, before).await?;
+
+        Ok(device)
+    }
+
+    pub async fn device_revoke(&self, id: &DeviceId) -> Result<Device, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let before = self.snapshot::<Device>(id).await?;
+
+        let device = self.get_collection::<Device>().find_one_and_update(
+            doc! { "_id": id },
+            doc! {
+                "$set": { "revoked_at": DateTime::now() },
+                "$unset": { "credential_hash": "", "pairing_code_hash": "", "pairing_expires_at": "" },
+            },
+            options,
+        ).await?.ok_or(RepoError::IdNotFound(*id))?;
+
+        self.audit_update::<Device>("revoke", id, before).await?;
+
+        Ok(device)
+    }
+}
@@ -1,18 +1,69 @@
 use std::fmt::Display;
 use mongodb::bson::Uuid;
+use mongodb::error::{ErrorKind, WriteFailure};
+use serde::Serialize;
+use crate::models::validation::ValidationErrors;
+
+/// Server error code of a write that breaks a unique index.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Foreign key on a written document that points at nothing.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UnknownReference {
+    pub field: &'static str,
+    pub collection: &'static str,
+    pub id: Uuid,
+}
 
 #[derive(Debug)]
 pub enum RepoError {
     MongoDBError(mongodb::error::Error),
 
-    DotenvError(String),
     BsonSerializationError(mongodb::bson::ser::Error),
-    DeserializeError(std::fmt::Error),
-    CollectionNotFound,
+    BsonDeserializationError(mongodb::bson::de::Error),
 
-    IdInvalidUuid,
     IdNotFound(Uuid),
     IdsNotFound(Vec<Uuid>),
+    UnknownReferences(Vec<UnknownReference>),
+
+    AlreadyExists(String),
+    InvalidState(String),
+    /// Request that does not fit the stored data, e.g. a price for a fixed-price product. Answered
+    /// like a body that fails `Validate`.
+    Validation(ValidationErrors),
+    /// Backup archive that cannot be restored.
+    InvalidBackup(String),
+}
+
+impl RepoError {
+    /// Stable, machine readable name of the error, sent to clients as `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RepoError::MongoDBError(_) => "database_error",
+            RepoError::BsonSerializationError(_) | RepoError::BsonDeserializationError(_) => "serialization_error",
+            RepoError::IdNotFound(_) => "id_not_found",
+            RepoError::IdsNotFound(_) => "ids_not_found",
+            RepoError::UnknownReferences(_) => "unknown_references",
+            RepoError::AlreadyExists(_) => "already_exists",
+            RepoError::InvalidState(_) => "invalid_state",
+            RepoError::Validation(_) => "validation_failed",
+            RepoError::InvalidBackup(_) => "invalid_backup",
+        }
+    }
+
+    /// Turns a unique index violation into `AlreadyExists`, leaving every other error alone.
+    pub fn on_duplicate_key(self, message: impl FnOnce() -> String) -> Self {
+        let duplicate = match &self {
+            RepoError::MongoDBError(err) => match err.kind.as_ref() {
+                ErrorKind::Write(WriteFailure::WriteError(error)) => error.code == DUPLICATE_KEY,
+                ErrorKind::Command(error) => error.code == DUPLICATE_KEY,
+                _ => false,
+            },
+            _ => false,
+        };
+
+        if duplicate { RepoError::AlreadyExists(message()) } else { self }
+    }
 }
 
 impl From<mongodb::error::Error> for RepoError {
@@ -21,17 +72,33 @@ impl From<mongodb::error::Error> for RepoError {
     }
 }
 
+impl From<ValidationErrors> for RepoError {
+    fn from(errors: ValidationErrors) -> Self {
+        RepoError::Validation(errors)
+    }
+}
+
 impl Display for RepoError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RepoError::MongoDBError(ref error) => write!(f, "MongoDB Error: {}", error),
-            RepoError::DotenvError(error_msg) => write!(f, "Error loading environment variables: {}", error_msg),
-            RepoError::DeserializeError(error) => write!(f, "JSON deserialization error: {}", error),
-            RepoError::CollectionNotFound => write!(f, "Collection not found"),
-            RepoError::IdInvalidUuid => write!(f, "Invalid UUID id"),
             RepoError::IdNotFound(id) => write!(f, "Id not found: {}", id),
-            RepoError::IdsNotFound(ids) => write!(f, "Ids not found: {:?}", ids),
+            RepoError::IdsNotFound(ids) => write!(
+                f,
+                "Ids not found: {}",
+                ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(", ")
+            ),
+            RepoError::UnknownReferences(references) => write!(
+                f,
+                "Unknown references: {}",
+                references.iter().map(|r| format!("{} {}", r.field, r.id)).collect::<Vec<String>>().join(", ")
+            ),
+            RepoError::AlreadyExists(error_msg) => write!(f, "Already exists: {}", error_msg),
+            RepoError::InvalidState(error_msg) => write!(f, "Invalid state: {}", error_msg),
+            RepoError::Validation(errors) => write!(f, "Invalid request: {}", errors),
+            RepoError::InvalidBackup(error_msg) => write!(f, "Invalid backup: {}", error_msg),
             RepoError::BsonSerializationError(error) => write!(f, "BSON serialization error: {}", error),
+            RepoError::BsonDeserializationError(error) => write!(f, "BSON deserialization error: {}", error),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use mongodb::bson::{doc, to_document, Document, Uuid};
+use mongodb::options::ReplaceOptions;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::models::categories::{Category, CategoryId, NewCategory};
+use crate::models::menu::{CategoryRow, MenuFile, MenuImportReport, ProductRow, RowError};
+use crate::models::products::{NewProduct, Product, ProductId};
+use crate::models::validation::Validate;
+use crate::models::CollectionName;
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+/// Documents an import would write, already checked against each other and the database.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MenuImportPlan {
+    pub report: MenuImportReport,
+    pub categories: Vec<Category>,
+    pub products: Vec<Product>,
+}
+
+impl Repository {
+    pub async fn export_menu(&self) -> Result<MenuFile, RepoError> {
+        let categories = self.query_all::<Category>().await?;
+        let products = self.query_all::<Product>().await?;
+
+        let products = products.into_iter().map(|product| {
+            let category = categories
+                .iter()
+                .find(|category| category._id == product.category_id)
+                .ok_or(RepoError::IdNotFound(product.category_id))?;
+            Ok(ProductRow {
+                row: 0,
+                category: category.name.clone(),
+                name: product.name,
+                sku: product.sku,
+                price: product.price,
+                tax_rate: product.tax_rate,
+                unit: product.unit,
+                open_price: product.open_price,
+            })
+        }).collect::<Result<Vec<ProductRow>, RepoError>>()?;
+
+        Ok(MenuFile {
+            categories: categories.into_iter().map(|category| CategoryRow {
+                row: 0,
+                name: category.name,
+                icon: category.icon,
+                color: category.color,
+            }).collect(),
+            products,
+        })
+    }
+
+    /// Upserts categories by name and products by SKU, or by name for products without one.
+    /// Nothing is written on a dry run or when any row, including the `parse_errors` of the
+    /// file itself, is invalid.
+    pub async fn import_menu(&self, menu: &MenuFile, parse_errors: Vec<RowError>, dry_run: bool) -> Result<MenuImportReport, RepoError> {
+        let categories = self.query_all::<Category>().await?;
+        let products = self.query_all::<Product>().await?;
+
+        let mut plan = plan_menu_import(menu, &categories, &products);
+        plan.report.dry_run = dry_run;
+        plan.report.errors.splice(0..0, parse_errors);
+        if dry_run || !plan.report.errors.is_empty() {
+            return Ok(plan.report);
+        }
+
+        self.write_menu_import(&plan).await?;
+        plan.report.committed = true;
+
+        Ok(plan.report)
+    }
+
+    /// MongoDB only has transactions on replica sets, so a failed write is undone by putting
+    /// back what the earlier writes replaced.
+    async fn write_menu_import(&self, plan: &MenuImportPlan) -> Result<(), RepoError> {
+        let mut written_categories = Vec::new();
+        let mut written_products = Vec::new();
+
+        let mut result = Ok(());
+        for category in &plan.categories {
+            match self.replace_for_import::<Category>(&category._id, category).await {
+                Ok(before) => written_categories.push((category._id, before)),
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        if result.is_ok() {
+            for product in &plan.products {
+                match self.replace_for_import::<Product>(&product._id, product).await {
+                    Ok(before) => written_products.push((product._id, before)),
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = result {
+            tracing::warn!("Menu import failed, rolling back: {}", err);
+            for (id, before) in written_products.into_iter().rev() {
+                self.restore_after_import::<Product>(&id, before).await;
+            }
+            for (id, before) in written_categories.into_iter().rev() {
+                self.restore_after_import::<Category>(&id, before).await;
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the document that was replaced, if any.
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
+    async fn replace_for_import<T>(&self, id: &Uuid, document: &T) -> Result<Option<Document>, RepoError>
+        where
+            T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
+    {
+        let replacement = to_document(document).map_err(RepoError::BsonSerializationError)?;
+        let before = self.snapshot::<T>(id).await?;
+
+        let options = ReplaceOptions::builder().upsert(true).build();
+        self.get_raw_collection(T::collection_name())
+            .replace_one(doc! { "_id": id }, replacement, options)
+            .await?;
+        self.audit_update::<T>("import", id, before.clone()).await?;
+
+        Ok(before)
+    }
+
+    async fn restore_after_import<T>(&self, id: &Uuid, before: Option<Document>)
+        where
+            T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
+    {
+        let collection = self.get_raw_collection(T::collection_name());
+        let current = self.snapshot::<T>(id).await.ok().flatten();
+        let restored = match &before {
+            Some(document) => collection.replace_one(doc! { "_id": id }, document.clone(), None).await.map(|_| ()),
+            None => collection.delete_one(doc! { "_id": id }, None).await.map(|_| ()),
+        };
+
+        match restored {
+            Ok(()) => {
+                if let Err(err) = self.audit::<T>("import_rollback", Some(*id), current.as_ref(), before.as_ref()).await {
+                    tracing::error!("Auditing the rollback of {} {} failed: {}", T::collection_name(), id, err);
+                }
+            }
+            Err(err) => tracing::error!("Rolling back {} {} failed: {}", T::collection_name(), id, err),
+        }
+    }
+}
+
+fn key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+fn row_error(section: &str, row: usize, field: &str, message: impl Into<String>) -> RowError {
+    RowError {
+        section: section.to_string(),
+        row,
+        field: field.to_string(),
+        message: message.into(),
+    }
+}
+
+/// Matches every row against the stored menu and reports what would change, without touching
+/// the database.
+pub fn plan_menu_import(menu: &MenuFile, categories: &[Category], products: &[Product]) -> MenuImportPlan {
+    let mut plan = MenuImportPlan::default();
+
+    let mut category_ids: HashMap<String, CategoryId> = categories.iter().map(|category| (key(&category.name), category._id)).collect();
+    let mut category_rows: HashMap<String, usize> = HashMap::new();
+    for row in &menu.categories {
+        let data = NewCategory {
+            name: row.name.trim().to_string(),
+            icon: row.icon.clone(),
+            color: row.color.clone(),
+        };
+        let mut valid = true;
+        if let Err(errors) = data.validate() {
+            valid = false;
+            plan.report.errors.extend(errors.fields.into_iter().map(|error| row_error("categories", row.row, error.field, error.message)));
+        }
+        if let Some(earlier) = category_rows.insert(key(&row.name), row.row) {
+            plan.report.errors.push(row_error("categories", row.row, "name", format!("duplicate of row {}", earlier)));
+            continue;
+        }
+
+        let existing = categories.iter().find(|category| key(&category.name) == key(&row.name));
+        let category = Category {
+            _id: existing.map(|category| category._id).unwrap_or_else(CategoryId::new),
+            name: data.name,
+            icon: data.icon,
+            color: data.color,
+        };
+        category_ids.insert(key(&row.name), category._id);
+
+        match existing {
+            Some(existing) if *existing == category => plan.report.unchanged += 1,
+            Some(_) => plan.report.categories_updated += 1,
+            None => plan.report.categories_created += 1,
+        }
+        if valid && existing != Some(&category) {
+            plan.categories.push(category);
+        }
+    }
+
+    let mut product_rows: HashMap<String, usize> = HashMap::new();
+    let mut matched: HashMap<ProductId, usize> = HashMap::new();
+    for row in &menu.products {
+        let sku = row.sku.as_ref().map(|sku| sku.trim().to_string());
+        let category_id = category_ids.get(&key(&row.category)).copied();
+        let data = NewProduct {
+            name: row.name.trim().to_string(),
+            price: row.price,
+            category_id: category_id.unwrap_or_else(CategoryId::new),
+            tax_rate: row.tax_rate,
+            sku: sku.clone(),
+            unit: row.unit,
+            open_price: row.open_price,
+        };
+
+        let mut valid = true;
+        if let Err(errors) = data.validate() {
+            valid = false;
+            plan.report.errors.extend(errors.fields.into_iter().map(|error| row_error("products", row.row, error.field, error.message)));
+        }
+        if category_id.is_none() {
+            valid = false;
+            plan.report.errors.push(row_error("products", row.row, "category", format!("unknown category {}", row.category)));
+        }
+
+        // Rows are told apart by what they are matched on.
+        let row_key = match &sku {
+            Some(sku) => format!("sku:{}", sku),
+            None => format!("name:{}", key(&row.name)),
+        };
+        if let Some(earlier) = product_rows.insert(row_key, row.row) {
+            plan.report.errors.push(row_error("products", row.row, if sku.is_some() { "sku" } else { "name" }, format!("duplicate of row {}", earlier)));
+            continue;
+        }
+
+        // A product with a different SKU is a different product, even when the name matches.
+        let existing = sku.as_ref()
+            .and_then(|sku| products.iter().find(|product| product.sku.as_ref() == Some(sku)))
+            .or_else(|| products.iter().find(|product| key(&product.name) == key(&row.name) && product.sku.is_none()));
+        if let Some(existing) = existing {
+            if let Some(earlier) = matched.insert(existing._id, row.row) {
+                plan.report.errors.push(row_error("products", row.row, "name", format!("matches the same product as row {}", earlier)));
+                continue;
+            }
+        }
+
+        let product = Product {
+            _id: existing.map(|product| product._id).unwrap_or_else(ProductId::new),
+            name: data.name,
+            price: data.price,
+            category_id: data.category_id,
+            tax_rate: data.tax_rate,
+            sku: data.sku,
+            unit: data.unit,
+            open_price: data.open_price,
+        };
+
+        match existing {
+            Some(existing) if *existing == product => plan.report.unchanged += 1,
+            Some(_) => plan.report.products_updated += 1,
+            None => plan.report.products_created += 1,
+        }
+        if valid && existing != Some(&product) {
+            plan.products.push(product);
+        }
+    }
+
+    plan
+}
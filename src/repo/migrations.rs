@@ -0,0 +1,396 @@
+use std::time::Duration;
+use futures::future::BoxFuture;
+use futures::TryStreamExt;
+use mongodb::bson::{doc, DateTime, Document};
+use mongodb::options::{FindOneOptions, FindOptions, IndexOptions, UpdateOptions};
+use mongodb::{Database, IndexModel};
+use crate::models::audit::AuditEntry;
+use crate::models::devices::Device;
+use crate::models::migrations::{AppliedMigration, MigrationStatus};
+use crate::models::orders::Order;
+use crate::models::products::Product;
+use crate::models::reports::{ClosedDay, Counter, ZReport, Z_REPORT_COUNTER};
+use crate::models::shifts::Shift;
+use crate::models::waiters::{Waiter, WaiterSession};
+use crate::models::CollectionName;
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+type MigrationFn = for<'a> fn(&'a Database) -> BoxFuture<'a, Result<(), RepoError>>;
+
+/// Schema changes in the order they are applied. Versions are never reused or reordered, and
+/// every step must be safe to run again in case it failed half way.
+const MIGRATIONS: &[(u32, &str, MigrationFn)] = &[
+    (1, "drop plaintext waiter code index", |db| Box::pin(drop_waiter_code_index(db))),
+    (2, "expire waiter sessions", |db| Box::pin(create_waiter_session_indexes(db))),
+    (3, "one z report per business day", |db| Box::pin(create_z_report_day_index(db))),
+    (4, "look devices up by credential", |db| Box::pin(create_device_credential_index(db))),
+    (5, "audit log indexes", |db| Box::pin(create_audit_log_indexes(db))),
+    (6, "order, shift and product indexes", |db| Box::pin(create_lookup_indexes(db))),
+    (7, "backfill order and waiter fields", |db| Box::pin(backfill_defaults(db))),
+    (8, "schema validators", |db| Box::pin(apply_schema_validators(db))),
+    (9, "unique product sku", |db| Box::pin(create_product_sku_index(db))),
+    (10, "backfill product units", |db| Box::pin(backfill_product_units(db))),
+    (11, "backfill order custom lines", |db| Box::pin(backfill_custom_lines(db))),
+    (12, "unique z report numbers", |db| Box::pin(create_z_report_number_index(db))),
+    (13, "one open shift per waiter", |db| Box::pin(create_open_shift_index(db))),
+    (14, "closed business days", |db| Box::pin(backfill_closed_days(db))),
+];
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|(version, _, _)| *version).unwrap_or(0)
+}
+
+/// The `migrate` command of the server and pos-admin: prints where the schema stands and, unless
+/// `status_only`, applies what is pending.
+pub async fn run_migrate_command(repo: &Repository, status_only: bool) -> Result<(), RepoError> {
+    let status = repo.migration_status().await?;
+    println!("Database schema version {}, latest is {}", status.current_version, status.latest_version);
+    for migration in &status.pending {
+        println!("  pending: {}", migration);
+    }
+
+    if !status_only {
+        let applied = repo.migrate().await?;
+        println!("Applied {} migrations", applied.len());
+    }
+
+    Ok(())
+}
+
+impl Repository {
+    pub async fn migration_status(&self) -> Result<MigrationStatus, RepoError> {
+        let current_version = self.schema_version().await?;
+
+        Ok(MigrationStatus {
+            current_version,
+            latest_version: latest_version(),
+            pending: MIGRATIONS
+                .iter()
+                .filter(|(version, _, _)| *version > current_version)
+                .map(|(version, name, _)| format!("{:03} {}", version, name))
+                .collect(),
+        })
+    }
+
+    /// Applies every migration newer than the database, oldest first, and returns the versions
+    /// that ran.
+    pub async fn migrate(&self) -> Result<Vec<u32>, RepoError> {
+        let current_version = self.schema_version().await?;
+        let collection = self.get_collection::<AppliedMigration>();
+        let mut applied = Vec::new();
+
+        for (version, name, migration) in MIGRATIONS.iter().filter(|(version, _, _)| *version > current_version) {
+            tracing::info!(version, name, "Applying migration");
+            migration(self.database()).await?;
+
+            collection.update_one(
+                doc! { "_id": version },
+                doc! { "$set": { "name": name, "applied_at": DateTime::now() } },
+                UpdateOptions::builder().upsert(true).build(),
+            ).await?;
+            applied.push(*version);
+        }
+
+        Ok(applied)
+    }
+
+    async fn schema_version(&self) -> Result<u32, RepoError> {
+        let options = FindOptions::builder().sort(doc! { "_id": -1 }).limit(1).build();
+        let latest = self.get_collection::<AppliedMigration>()
+            .find(None, options)
+            .await?
+            .try_next()
+            .await?;
+
+        Ok(latest.map(|migration| migration._id).unwrap_or(0))
+    }
+}
+
+/// PINs used to be stored in plaintext under a unique index, which would now reject every
+/// second waiter since new documents have no `code` at all.
+async fn drop_waiter_code_index(database: &Database) -> Result<(), RepoError> {
+    let result = database
+        .collection::<Document>(Waiter::collection_name())
+        .drop_index("code_1", None)
+        .await;
+
+    if let Err(err) = result {
+        tracing::debug!("Not dropping waiter code index: {}", err);
+    }
+
+    Ok(())
+}
+
+async fn create_waiter_session_indexes(database: &Database) -> Result<(), RepoError> {
+    let options = IndexOptions::builder().expire_after(Duration::from_secs(0)).build();
+    let model = IndexModel::builder()
+        .keys(doc! { "expires_at": 1 })
+        .options(options)
+        .build();
+    database
+        .collection::<Document>(WaiterSession::collection_name())
+        .create_index(model, None)
+        .await?;
+
+    Ok(())
+}
+
+async fn create_z_report_day_index(database: &Database) -> Result<(), RepoError> {
+    let options = IndexOptions::builder().unique(true).build();
+    let model = IndexModel::builder()
+        .keys(doc! { "business_day": 1 })
+        .options(options)
+        .build();
+    database
+        .collection::<Document>(ZReport::collection_name())
+        .create_index(model, None)
+        .await?;
+
+    Ok(())
+}
+
+async fn create_device_credential_index(database: &Database) -> Result<(), RepoError> {
+    let model = IndexModel::builder()
+        .keys(doc! { "credential_hash": 1 })
+        .build();
+    database
+        .collection::<Document>(Device::collection_name())
+        .create_index(model, None)
+        .await?;
+
+    Ok(())
+}
+
+async fn create_audit_log_indexes(database: &Database) -> Result<(), RepoError> {
+    let models = vec![
+        IndexModel::builder().keys(doc! { "at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "entity": 1, "entity_id": 1, "at": -1 }).build(),
+        IndexModel::builder().keys(doc! { "actor.subject": 1, "at": -1 }).build(),
+    ];
+    database
+        .collection::<Document>(AuditEntry::collection_name())
+        .create_indexes(models, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Indexes behind the per-waiter and per-table order lists, report ranges and open shifts.
+async fn create_lookup_indexes(database: &Database) -> Result<(), RepoError> {
+    database
+        .collection::<Document>(Order::collection_name())
+        .create_indexes(vec![
+            IndexModel::builder().keys(doc! { "waiter_id": 1, "created_at": -1 }).build(),
+            IndexModel::builder().keys(doc! { "table_id": 1, "created_at": -1 }).build(),
+            IndexModel::builder().keys(doc! { "closed_at": 1 }).build(),
+        ], None)
+        .await?;
+    database
+        .collection::<Document>(Shift::collection_name())
+        .create_indexes(vec![
+            IndexModel::builder().keys(doc! { "waiter_id": 1, "clock_out": 1 }).build(),
+        ], None)
+        .await?;
+    database
+        .collection::<Document>(Product::collection_name())
+        .create_indexes(vec![
+            IndexModel::builder().keys(doc! { "category_id": 1 }).build(),
+        ], None)
+        .await?;
+
+    Ok(())
+}
+
+/// Documents written before these fields existed only read thanks to serde defaults; write the
+/// defaults out so queries on the fields see them too.
+async fn backfill_defaults(database: &Database) -> Result<(), RepoError> {
+    let orders = database.collection::<Document>(Order::collection_name());
+    for field in ["payments", "discounts", "voids", "refunds", "mutations"] {
+        orders.update_many(
+            doc! { field: { "$exists": false } },
+            doc! { "$set": { field: [] } },
+            None,
+        ).await?;
+    }
+    orders.update_many(doc! { "covers": { "$exists": false } }, doc! { "$set": { "covers": 0 } }, None).await?;
+    orders.update_many(doc! { "closed_at": { "$exists": false } }, doc! { "$set": { "closed_at": null } }, None).await?;
+    orders.update_many(
+        doc! { "products.sent_quantity": { "$exists": false }, "products.0": { "$exists": true } },
+        vec![doc! { "$set": { "products": { "$map": {
+            "input": "$products",
+            "as": "line",
+            "in": { "$mergeObjects": [{ "sent_quantity": 0.0 }, "$$line"] },
+        } } } }],
+        None,
+    ).await?;
+
+    let waiters = database.collection::<Document>(Waiter::collection_name());
+    waiters.update_many(doc! { "roles": { "$exists": false } }, doc! { "$set": { "roles": ["waiter"] } }, None).await?;
+    waiters.update_many(doc! { "failed_attempts": { "$exists": false } }, doc! { "$set": { "failed_attempts": 0 } }, None).await?;
+
+    Ok(())
+}
+
+/// Products without a SKU store `null`, which the partial filter leaves out of the index.
+async fn create_product_sku_index(database: &Database) -> Result<(), RepoError> {
+    let options = IndexOptions::builder()
+        .unique(true)
+        .partial_filter_expression(doc! { "sku": { "$type": "string" } })
+        .build();
+    let model = IndexModel::builder()
+        .keys(doc! { "sku": 1 })
+        .options(options)
+        .build();
+    database
+        .collection::<Document>(Product::collection_name())
+        .create_index(model, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Everything sold before units existed was counted in pieces.
+async fn backfill_product_units(database: &Database) -> Result<(), RepoError> {
+    database
+        .collection::<Document>(Product::collection_name())
+        .update_many(doc! { "unit": { "$exists": false } }, doc! { "$set": { "unit": "piece" } }, None)
+        .await?;
+
+    Ok(())
+}
+
+async fn backfill_custom_lines(database: &Database) -> Result<(), RepoError> {
+    database
+        .collection::<Document>(Order::collection_name())
+        .update_many(doc! { "custom_lines": { "$exists": false } }, doc! { "$set": { "custom_lines": [] } }, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Rejects documents missing the fields every reader relies on. `moderate` leaves existing
+/// invalid documents alone until they are next updated.
+async fn apply_schema_validators(database: &Database) -> Result<(), RepoError> {
+    let existing = database.list_collection_names(None).await?;
+
+    for (collection, schema) in schema_validators() {
+        let command = if existing.iter().any(|name| name == collection) {
+            doc! { "collMod": collection, "validator": { "$jsonSchema": schema }, "validationLevel": "moderate" }
+        } else {
+            doc! { "create": collection, "validator": { "$jsonSchema": schema }, "validationLevel": "moderate" }
+        };
+        database.run_command(command, None).await?;
+    }
+
+    Ok(())
+}
+
+pub fn schema_validators() -> Vec<(&'static str, Document)> {
+    vec![
+        (Order::collection_name(), doc! {
+            "bsonType": "object",
+            "required": ["waiter_id", "table_id", "products", "created_at"],
+            "properties": {
+                "waiter_id": { "bsonType": "binData" },
+                "table_id": { "bsonType": "binData" },
+                "products": { "bsonType": "array" },
+                "created_at": { "bsonType": "date" },
+            },
+        }),
+        (Product::collection_name(), doc! {
+            "bsonType": "object",
+            "required": ["name", "price", "category_id"],
+            "properties": {
+                "name": { "bsonType": "string", "minLength": 1 },
+                "price": { "bsonType": "number", "minimum": 0 },
+                "category_id": { "bsonType": "binData" },
+            },
+        }),
+        (Waiter::collection_name(), doc! {
+            "bsonType": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "bsonType": "string", "minLength": 1 },
+                "roles": { "bsonType": "array" },
+            },
+        }),
+        (Shift::collection_name(), doc! {
+            "bsonType": "object",
+            "required": ["waiter_id", "clock_in"],
+            "properties": {
+                "waiter_id": { "bsonType": "binData" },
+                "clock_in": { "bsonType": "date" },
+            },
+        }),
+    ]
+}
+
+/// Numbers used to be the count of reports plus one. The counter that replaces that starts
+/// from the highest number handed out so far.
+async fn create_z_report_number_index(database: &Database) -> Result<(), RepoError> {
+    let reports = database.collection::<Document>(ZReport::collection_name());
+    let options = FindOneOptions::builder().sort(doc! { "number": -1 }).build();
+    let highest = reports
+        .find_one(None, options)
+        .await?
+        .and_then(|report| report.get_i64("number").ok())
+        .unwrap_or(0);
+    database
+        .collection::<Document>(Counter::collection_name())
+        .update_one(
+            doc! { "_id": Z_REPORT_COUNTER },
+            doc! { "$max": { "value": highest } },
+            UpdateOptions::builder().upsert(true).build(),
+        )
+        .await?;
+
+    let options = IndexOptions::builder().unique(true).build();
+    let model = IndexModel::builder()
+        .keys(doc! { "number": 1 })
+        .options(options)
+        .build();
+    reports.create_index(model, None).await?;
+
+    Ok(())
+}
+
+/// Open shifts store `clock_out: null`, which is what the partial filter matches.
+async fn create_open_shift_index(database: &Database) -> Result<(), RepoError> {
+    let options = IndexOptions::builder()
+        .unique(true)
+        .partial_filter_expression(doc! { "clock_out": { "$type": "null" } })
+        .build();
+    let model = IndexModel::builder()
+        .keys(doc! { "waiter_id": 1 })
+        .options(options)
+        .build();
+    database
+        .collection::<Document>(Shift::collection_name())
+        .create_index(model, None)
+        .await?;
+
+    Ok(())
+}
+
+/// Days closed before `closed_days` existed are recorded from their Z reports.
+async fn backfill_closed_days(database: &Database) -> Result<(), RepoError> {
+    let closed_days = database.collection::<Document>(ClosedDay::collection_name());
+    let mut reports = database.collection::<ZReport>(ZReport::collection_name()).find(None, None).await?;
+
+    while let Some(z_report) = reports.try_next().await? {
+        closed_days.update_one(
+            doc! { "_id": &z_report.business_day },
+            doc! {
+                "$setOnInsert": {
+                    "period_start": z_report.report.period_start,
+                    "period_end": z_report.report.period_end,
+                    "closed_at": z_report.report.generated_at,
+                }
+            },
+            UpdateOptions::builder().upsert(true).build(),
+        ).await?;
+    }
+
+    Ok(())
+}
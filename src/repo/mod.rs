@@ -1,3 +1,13 @@
 pub mod repository;
 pub mod orders;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod reports;
+pub mod analytics;
+pub mod shifts;
+pub mod waiters;
+pub mod devices;
+pub mod audit;
+pub mod references;
+pub mod migrations;
+pub mod menu;
+pub mod backup;
@@ -1,13 +1,14 @@
-use std::collections::HashMap;
-use std::hash::Hash;
+use std::collections::HashSet;
 use futures::TryStreamExt;
-use mongodb::bson::{to_bson, doc, Uuid};
-use crate::models::categories::{Category, CategoryId};
-use crate::models::orders::{Order, OrderAPI, OrderId};
-use crate::models::products::{Product, ProductInOrder, ProductId, ProductIdWithQuantity};
+use mongodb::bson::{to_bson, doc, Bson, DateTime, Document, Uuid};
+use crate::models::categories::Category;
+use crate::models::orders::{CustomLine, CustomLineId, Discount, LineOperation, NewCustomLine, NewPayment, NewRefund, NewVoid, Order, OrderAction, OrderAPI, OrderId, OrderMutation, Payment, Refund, VoidedProduct};
+use crate::models::products::{Product, ProductInOrder, ProductId, ProductIdWithQuantity, ScaleReading, Unit};
 use crate::models::tables::{TableId, TableInOrder};
+use crate::models::validation::ValidationErrors;
 use crate::models::waiters::{WaiterInOrder, WaiterId};
 use crate::repo::error::RepoError;
+use crate::repo::references::ReferenceCheck;
 use crate::repo::repository::Repository;
 
 impl Repository {
@@ -25,8 +26,9 @@ impl Repository {
         Ok(results)
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(order_id = %id))]
     pub async fn query_order_api(&self, id: &OrderId) -> Result<OrderAPI, RepoError> {
-        let order = self.query_one::<Order>(&id).await?;
+        let order = self.query_one::<Order>(id).await?;
 
         let waiter = self.query_one::<WaiterInOrder>(&order.waiter_id).await?;
         let table = self.query_one::<TableInOrder>(&order.table_id).await?;
@@ -34,35 +36,21 @@ impl Repository {
 
         let products_ids = order.products.iter().map(|product| product._id as Uuid).collect::<Vec<Uuid>>();
 
-        let products = self
-            .query_many::<Product>(&products_ids)
-            .await?
-            .into_iter()
-            .map(|product| {
-                let quantity = order.products.iter().find(|p| p._id == product._id).unwrap().quantity;
-                let category = categories.iter().find(|c| c._id == product.category_id).unwrap();
-                ProductInOrder {
-                    _id: product._id,
-                    name: product.name.clone(),
-                    price: product.price,
-                    category: category.clone(),
-                    quantity,
-                }
-            })
-            .collect::<Vec<ProductInOrder>>();
-
-        let sum = products.iter().fold(0.0, |acc, product| acc + product.price * product.quantity);
-
-        Ok(
-            OrderAPI {
-                _id: order._id,
-                waiter,
-                table,
-                products,
-                sum,
-                created_at: order.created_at,
-            }
-        )
+        let mut products: Vec<ProductInOrder> = Vec::new();
+        for product in self.query_many::<Product>(&products_ids).await? {
+            let line = order.products
+                .iter()
+                .find(|p| p._id == product._id)
+                .ok_or(RepoError::IdNotFound(product._id))?;
+            let category = categories
+                .iter()
+                .find(|c| c._id == product.category_id)
+                .ok_or(RepoError::IdNotFound(product.category_id))?;
+
+            products.push(product_in_order(line, product, category.clone()));
+        }
+
+        Ok(order_api(order, waiter, table, products))
     }
 
     pub async fn query_orders_by_waiter(&self, id: &WaiterId) -> Result<Vec<OrderAPI>, RepoError> {
@@ -94,64 +82,107 @@ impl Repository {
         Ok(results)
     }
 
-    pub async fn order_add_product(&self, id: &OrderId, product_id: &ProductId) -> Result<OrderAPI, RepoError> {
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_add_product(&self, id: &OrderId, product_id: &ProductId, price: Option<f64>) -> Result<OrderAPI, RepoError> {
+        let order = self.query_open_order(id).await?;
+        let mut references = ReferenceCheck::default();
+        references.check::<Product>(self, "product_id", product_id).await?;
+        references.finish()?;
+        let product = self.query_one::<Product>(product_id).await?;
+        check_counted(&product)?;
+        check_line_price(&product, price)?;
+
+        let mutation = self.order_mutation(OrderAction::AddProduct)?;
+        let before = self.snapshot::<Order>(id).await?;
         let collection = self.get_collection::<Order>();
 
-        let filter = doc! { "_id": id, "products._id": product_id };
-
-        let result = collection.find_one(
-            filter.clone(),
-            None,
-        ).await?;
-
-        if result.is_some() {
-            log::info!("Product already exists in order, incrementing quantity");
+        let result = if let Some(line) = order.products.iter().find(|line| line._id == *product_id) {
+            if line.price != price {
+                return Err(RepoError::InvalidState(format!(
+                    "Product {} is already on the order at {:.2}, add it as a custom line to charge another price",
+                    product_id, line.price.unwrap_or(product.price)
+                )));
+            }
+            tracing::info!("Product already exists in order, incrementing quantity");
             collection.update_one(
-                filter,
+                doc! { "_id": id, "closed_at": null, "products._id": product_id },
                 doc! {
                         "$inc": {
                             "products.$.quantity": 1
-                        }
+                        },
+                        "$push": { "mutations": mutation }
                     },
                 None,
-            ).await?;
+            ).await?
         } else {
-            log::info!("Product does not exist in order, adding it");
+            tracing::info!("Product does not exist in order, adding it");
 
             let product = ProductIdWithQuantity {
                 _id: *product_id,
                 quantity: 1.0,
+                sent_quantity: 0.0,
+                price,
             };
 
-            let product_bson = to_bson(&product).map_err(|err| RepoError::BsonSerializationError(err))?;
+            let product_bson = to_bson(&product).map_err(RepoError::BsonSerializationError)?;
 
-            collection.find_one_and_update(
-                doc! { "_id": id },
-                doc! { "$push": { "products": product_bson } },
+            collection.update_one(
+                doc! { "_id": id, "closed_at": null, "products._id": { "$ne": product_id } },
+                doc! { "$push": { "products": product_bson, "mutations": mutation } },
                 None,
-            ).await?;
+            ).await?
+        };
+        if result.matched_count == 0 {
+            return Err(RepoError::InvalidState(format!("Order {} was closed or changed while adding the product, try again", id)));
         }
 
-        self.query_order_api(&id).await
+        self.audit_update::<Order>("add_product", id, before).await?;
+
+        self.query_order_api(id).await
     }
 
+    /// Takes back one unit the kitchen has not seen yet. Anything already sent has to be voided.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
     pub async fn order_remove_product(&self, id: &OrderId, product_id: &ProductId) -> Result<OrderAPI, RepoError> {
-        let collection = self.get_collection::<Order>();
+        let order = self.query_open_order(id).await?;
+        self.require_counted(product_id).await?;
+        let line = order.products.iter().find(|line| line._id == *product_id).ok_or(RepoError::IdNotFound(*product_id))?;
+        if line.quantity - 1.0 < line.sent_quantity {
+            return Err(RepoError::InvalidState(format!("Product {} was already sent to the kitchen, void it instead", product_id)));
+        }
 
-        let filter = doc! { "_id": id, "products._id": product_id };
+        let mutation = self.order_mutation(OrderAction::RemoveProduct)?;
+        let before = self.snapshot::<Order>(id).await?;
+        let collection = self.get_collection::<Order>();
 
-        collection.find_one_and_update(
-            filter.clone(),
+        // Sent units cannot be taken back, so the line must still be as far from the kitchen as
+        // it was when checked above.
+        let result = collection.update_one(
+            doc! {
+                "_id": id,
+                "closed_at": null,
+                "products": {
+                    "$elemMatch": {
+                        "_id": product_id,
+                        "sent_quantity": line.sent_quantity,
+                        "quantity": { "$gte": line.sent_quantity + 1.0 },
+                    }
+                },
+            },
             doc! {
                         "$inc": {
                             "products.$.quantity": -1
-                        }
+                        },
+                        "$push": { "mutations": mutation }
                     },
             None,
         ).await?;
+        if result.matched_count == 0 {
+            return Err(RepoError::InvalidState(format!("Order {} was closed or changed while removing the product, try again", id)));
+        }
 
         collection.find_one_and_update(
-            filter,
+            doc! { "_id": id },
             doc! {
                         "$pull": {
                             "products": {
@@ -162,19 +193,569 @@ impl Repository {
             None,
         ).await?;
 
-        self.query_order_api(&id).await
+        self.audit_update::<Order>("remove_product", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Products taken off the menu since can still be removed.
+    async fn require_counted(&self, product_id: &ProductId) -> Result<(), RepoError> {
+        let products = self.query_many_by::<Product>(doc! { "_id": product_id }).await?;
+        products.first().map(check_counted).unwrap_or(Ok(()))
+    }
+
+    /// Adds a one-off line that has no product behind it.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_add_custom_line(&self, id: &OrderId, line: NewCustomLine) -> Result<OrderAPI, RepoError> {
+        self.query_open_order(id).await?;
+        let line = CustomLine {
+            _id: CustomLineId::new(),
+            name: line.name.trim().to_string(),
+            price: line.price,
+            tax_rate: line.tax_rate,
+            quantity: line.quantity,
+            sent_quantity: 0.0,
+            added_at: DateTime::now(),
+        };
+        let line_bson = to_bson(&line).map_err(RepoError::BsonSerializationError)?;
+
+        let before = self.snapshot::<Order>(id).await?;
+        self.get_collection::<Order>().update_one(
+            doc! { "_id": id },
+            doc! { "$push": { "custom_lines": line_bson, "mutations": self.order_mutation(OrderAction::AddCustomLine)? } },
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("add_custom_line", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Takes a custom line off before the kitchen sees it. Sent lines have to be voided.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_remove_custom_line(&self, id: &OrderId, line_id: &CustomLineId) -> Result<OrderAPI, RepoError> {
+        let order = self.query_open_order(id).await?;
+        let line = order.custom_lines
+            .iter()
+            .find(|line| line._id == *line_id)
+            .ok_or(RepoError::IdNotFound(*line_id))?;
+        if line.sent_quantity > 0.0 {
+            return Err(RepoError::InvalidState(format!("Custom line {} was already sent to the kitchen, void it instead", line_id)));
+        }
+
+        let before = self.snapshot::<Order>(id).await?;
+        self.get_collection::<Order>().update_one(
+            doc! { "_id": id },
+            doc! {
+                "$pull": { "custom_lines": { "_id": line_id } },
+                "$push": { "mutations": self.order_mutation(OrderAction::RemoveCustomLine)? },
+            },
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("remove_custom_line", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Applies every operation to the order in a single write, or none when one of them fails.
+    /// The write only goes through if nobody else changed the order meanwhile, otherwise the
+    /// batch is worked out again on the fresh order.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_apply_batch(&self, id: &OrderId, operations: &[LineOperation]) -> Result<OrderAPI, RepoError> {
+        let mut references = ReferenceCheck::default();
+        let mut checked: HashSet<ProductId> = HashSet::new();
+        for operation in operations.iter().filter(|operation| !matches!(operation, LineOperation::Remove { .. })) {
+            if checked.insert(*operation.product_id()) {
+                references.check::<Product>(self, "product_id", operation.product_id()).await?;
+            }
+        }
+        references.finish()?;
+
+        let ids: Vec<ProductId> = operations.iter().map(|operation| *operation.product_id()).collect();
+        let products = self.query_many_by::<Product>(doc! { "_id": { "$in": ids } }).await?;
+        let operations = &resolve_line_operations(operations, &products)?;
+
+        let mutations = operations
+            .iter()
+            .map(|operation| self.order_mutation(operation.action()))
+            .collect::<Result<Vec<Bson>, RepoError>>()?;
+
+        for _ in 0..BATCH_ATTEMPTS {
+            let order = self.query_open_order(id).await?;
+            let lines = apply_line_operations(&order.products, operations)?;
+            let unpriced = lines.iter().find(|line| {
+                line.price.is_none() && products.iter().any(|product| product._id == line._id && product.open_price.is_some())
+            });
+            if let Some(line) = unpriced {
+                return Err(RepoError::InvalidState(format!("Product {} is priced when ordered, add it with a price first", line._id)));
+            }
+            let products_bson = to_bson(&lines).map_err(RepoError::BsonSerializationError)?;
+
+            let before = self.snapshot::<Order>(id).await?;
+            let result = self.get_collection::<Order>().update_one(
+                doc! { "_id": id, "closed_at": null, "mutations": { "$size": order.mutations.len() as i64 } },
+                doc! {
+                    "$set": { "products": products_bson },
+                    "$push": { "mutations": { "$each": mutations.clone() } },
+                },
+                None,
+            ).await?;
+
+            if result.matched_count > 0 {
+                self.audit_update::<Order>("batch", id, before).await?;
+                return self.query_order_api(id).await;
+            }
+            tracing::info!("Order changed while applying a batch, retrying");
+        }
+
+        Err(RepoError::InvalidState(format!("Order {} kept changing while applying the batch, try again", id)))
+    }
+
+    /// Marks everything currently on the order as sent to the kitchen.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_send(&self, id: &OrderId) -> Result<OrderAPI, RepoError> {
+        self.query_open_order(id).await?;
+        let before = self.snapshot::<Order>(id).await?;
+        let mutation = self.order_mutation(OrderAction::Send)?;
+
+        self.get_collection::<Order>().update_one(
+            doc! { "_id": id },
+            vec![doc! {
+                "$set": {
+                    "products": {
+                        "$map": {
+                            "input": "$products",
+                            "in": { "$mergeObjects": ["$$this", { "sent_quantity": "$$this.quantity" }] },
+                        }
+                    },
+                    "custom_lines": {
+                        "$map": {
+                            "input": { "$ifNull": ["$custom_lines", []] },
+                            "in": { "$mergeObjects": ["$$this", { "sent_quantity": "$$this.quantity" }] },
+                        }
+                    },
+                    "mutations": { "$concatArrays": [{ "$ifNull": ["$mutations", []] }, [mutation]] },
+                }
+            }],
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("send", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Takes sent units off an open order, keeping a priced snapshot of them in `voids`.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_void(&self, id: &OrderId, void: NewVoid, approved_by: Option<String>) -> Result<OrderAPI, RepoError> {
+        let order = self.query_open_order(id).await?;
+        let product_line = order.products.iter().find(|line| line._id == void.product_id);
+        let custom_line = order.custom_lines.iter().find(|line| line._id == void.product_id);
+        let (field, name, price, sent_quantity) = match (product_line, custom_line) {
+            (Some(line), _) => {
+                let product = self.query_one::<Product>(&void.product_id).await?;
+                ("products", product.name, line.price.unwrap_or(product.price), line.sent_quantity)
+            }
+            (None, Some(line)) => ("custom_lines", line.name.clone(), line.price, line.sent_quantity),
+            (None, None) => return Err(RepoError::IdNotFound(void.product_id)),
+        };
+        if void.quantity <= 0.0 || void.quantity > sent_quantity {
+            return Err(RepoError::InvalidState(format!(
+                "Only {} of product {} can be voided, the rest was not sent to the kitchen",
+                sent_quantity, void.product_id
+            )));
+        }
+
+        let voided = VoidedProduct {
+            _id: void.product_id,
+            name,
+            price,
+            quantity: void.quantity,
+            voided_at: DateTime::now(),
+            reason: void.reason,
+            note: void.note,
+            approved_by,
+        };
+        let voided_bson = to_bson(&voided).map_err(RepoError::BsonSerializationError)?;
+
+        let before = self.snapshot::<Order>(id).await?;
+        let collection = self.get_collection::<Order>();
+        let (quantity, line_sent) = (format!("{}.$.quantity", field), format!("{}.$.sent_quantity", field));
+        let result = collection.update_one(
+            doc! {
+                "_id": id,
+                "closed_at": null,
+                field: { "$elemMatch": { "_id": void.product_id, "sent_quantity": { "$gte": void.quantity } } },
+            },
+            doc! {
+                "$inc": { quantity: -void.quantity, line_sent: -void.quantity },
+                "$push": { "voids": voided_bson, "mutations": self.order_mutation(OrderAction::Void)? },
+            },
+            None,
+        ).await?;
+        if result.matched_count == 0 {
+            return Err(RepoError::InvalidState(format!("Order {} was closed or changed while voiding, try again", id)));
+        }
+        collection.update_one(
+            doc! { "_id": id },
+            doc! { "$pull": { field: { "quantity": { "$lte": 0 } } } },
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("void", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Pays money back on a closed order, never more than was paid on it.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_refund(&self, id: &OrderId, refund: NewRefund, approved_by: Option<String>) -> Result<OrderAPI, RepoError> {
+        let order = self.query_one::<Order>(id).await?;
+        if order.closed_at.is_none() {
+            return Err(RepoError::InvalidState(format!("Order {} is still open, void or correct it instead", id)));
+        }
+
+        let paid = order.payments.iter().fold(0.0, |acc, payment| acc + payment.amount);
+        let refunded = order.refunds.iter().fold(0.0, |acc, refund| acc + refund.amount);
+        if refund.amount <= 0.0 || refund.amount > paid - refunded + f64::EPSILON {
+            return Err(RepoError::InvalidState(format!("Refund of {:.2} exceeds the {:.2} left to refund on order {}", refund.amount, paid - refunded, id)));
+        }
+
+        let refund = Refund {
+            tender: refund.tender,
+            amount: refund.amount,
+            reason: refund.reason,
+            note: refund.note,
+            approved_by,
+            refunded_at: DateTime::now(),
+        };
+        self.require_open_day(refund.refunded_at).await?;
+        let refund_bson = to_bson(&refund).map_err(RepoError::BsonSerializationError)?;
+
+        let before = self.snapshot::<Order>(id).await?;
+        self.get_collection::<Order>().update_one(
+            doc! { "_id": id },
+            doc! { "$push": { "refunds": refund_bson, "mutations": self.order_mutation(OrderAction::Refund)? } },
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("refund", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Whether the order is still open and nothing was ever rung up, paid, discounted or taken
+    /// back on it.
+    pub async fn order_is_empty(&self, id: &OrderId) -> Result<bool, RepoError> {
+        self.query_one::<Order>(id).await?;
+        let count = self.get_collection::<Order>().count_documents(empty_order_filter(id), None).await?;
+
+        Ok(count > 0)
+    }
+
+    /// Deletes an order opened by mistake. Anything that left a trace has to be voided or closed.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn delete_empty_order(&self, id: &OrderId) -> Result<(), RepoError> {
+        let before = self.snapshot::<Order>(id).await?;
+        let result = self.get_collection::<Order>().delete_one(empty_order_filter(id), None).await?;
+        if result.deleted_count == 0 {
+            self.query_one::<Order>(id).await?;
+            return Err(RepoError::InvalidState(format!("Order {} is not empty, void or close it instead", id)));
+        }
+
+        self.audit::<Order>("delete", Some(*id), before.as_ref(), None).await
+    }
+
+    /// Orders not closed yet, exported as a gauge.
+    pub async fn count_open_orders(&self) -> Result<u64, RepoError> {
+        Ok(self.get_collection::<Order>().count_documents(doc! { "closed_at": null }, None).await?)
+    }
+
+    /// Refunds paid out between `start` and `end`, whenever their order was placed.
+    pub async fn query_refunds_between(&self, start: DateTime, end: DateTime) -> Result<Vec<Refund>, RepoError> {
+        let orders = self
+            .query_many_by::<Order>(doc! { "refunds.refunded_at": { "$gte": start, "$lt": end } })
+            .await?;
+
+        Ok(orders
+            .into_iter()
+            .flat_map(|order| order.refunds)
+            .filter(|refund| refund.refunded_at >= start && refund.refunded_at < end)
+            .collect())
+    }
+
+    async fn query_open_order(&self, id: &OrderId) -> Result<Order, RepoError> {
+        let order = self.query_one::<Order>(id).await?;
+        if order.closed_at.is_some() {
+            return Err(RepoError::InvalidState(format!("Order {} is already closed", id)));
+        }
+
+        Ok(order)
+    }
+
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_add_payment(&self, id: &OrderId, payment: NewPayment) -> Result<OrderAPI, RepoError> {
+        self.query_open_order(id).await?;
+        let before = self.snapshot::<Order>(id).await?;
+        let payment = Payment {
+            tender: payment.tender,
+            amount: payment.amount,
+            tip: payment.tip,
+            paid_at: DateTime::now(),
+        };
+
+        let payment_bson = to_bson(&payment).map_err(RepoError::BsonSerializationError)?;
+
+        self.get_collection::<Order>().find_one_and_update(
+            doc! { "_id": id, "closed_at": null },
+            doc! { "$push": { "payments": payment_bson, "mutations": self.order_mutation(OrderAction::AddPayment)? } },
+            None,
+        ).await?.ok_or_else(|| RepoError::InvalidState(format!("Order {} is already closed", id)))?;
+
+        self.audit_update::<Order>("add_payment", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    /// Discounts on an order never add up to more than its total.
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_add_discount(&self, id: &OrderId, discount: Discount) -> Result<OrderAPI, RepoError> {
+        let order = self.query_open_order(id).await?;
+        let total = self.query_order_api(id).await?.sum;
+        let discounted = order.discounts.iter().fold(0.0, |acc, discount| acc + discount.amount);
+        if discount.amount > total - discounted + f64::EPSILON {
+            return Err(RepoError::InvalidState(format!("Discount of {:.2} exceeds the {:.2} left to discount on order {}", discount.amount, total - discounted, id)));
+        }
+
+        let before = self.snapshot::<Order>(id).await?;
+        let discount_bson = to_bson(&discount).map_err(RepoError::BsonSerializationError)?;
+
+        // Only goes through if no other discount landed since the check above.
+        self.get_collection::<Order>().find_one_and_update(
+            doc! { "_id": id, "closed_at": null, "discounts": { "$size": order.discounts.len() as i64 } },
+            doc! { "$push": { "discounts": discount_bson, "mutations": self.order_mutation(OrderAction::AddDiscount)? } },
+            None,
+        ).await?.ok_or_else(|| RepoError::InvalidState(format!("Order {} changed while adding the discount, try again", id)))?;
+
+        self.audit_update::<Order>("add_discount", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    #[tracing::instrument(skip_all, fields(order_id = %id))]
+    pub async fn order_close(&self, id: &OrderId) -> Result<OrderAPI, RepoError> {
+        let order = self.query_one::<Order>(id).await?;
+        let before = self.snapshot::<Order>(id).await?;
+        if order.closed_at.is_some() {
+            return Err(RepoError::AlreadyExists(format!("Order {} is already closed", id)));
+        }
+
+        self.get_collection::<Order>().update_one(
+            doc! { "_id": id },
+            doc! {
+                "$set": { "closed_at": DateTime::now() },
+                "$push": { "mutations": self.order_mutation(OrderAction::Close)? },
+            },
+            None,
+        ).await?;
+
+        self.audit_update::<Order>("close", id, before).await?;
+
+        self.query_order_api(id).await
+    }
+
+    fn order_mutation(&self, action: OrderAction) -> Result<Bson, RepoError> {
+        let mutation = OrderMutation {
+            action,
+            device_id: self.actor().device_id,
+            at: DateTime::now(),
+        };
+
+        to_bson(&mutation).map_err(RepoError::BsonSerializationError)
     }
 }
 
+const BATCH_ATTEMPTS: usize = 3;
+
+/// Matches the order only while it is open and every line, payment, discount, void and refund
+/// array is missing or empty.
+fn empty_order_filter(id: &OrderId) -> Document {
+    doc! {
+        "_id": id,
+        "closed_at": null,
+        "products.0": { "$exists": false },
+        "custom_lines.0": { "$exists": false },
+        "payments.0": { "$exists": false },
+        "discounts.0": { "$exists": false },
+        "voids.0": { "$exists": false },
+        "refunds.0": { "$exists": false },
+    }
+}
 
-fn count_frequency<T>(arr: Vec<T>) -> HashMap<T, i32>
-    where T: Eq + Hash
-{
-    let mut frequency: HashMap<T, i32> = HashMap::new();
+/// Adding or removing one at a time only makes sense for products counted in pieces.
+/// Product line as clients see it, priced at the entered price for open-price products.
+pub(crate) fn product_in_order(line: &ProductIdWithQuantity, product: Product, category: Category) -> ProductInOrder {
+    ProductInOrder {
+        _id: product._id,
+        name: product.name,
+        price: line.price.unwrap_or(product.price),
+        category,
+        tax_rate: product.tax_rate,
+        unit: product.unit,
+        quantity: line.quantity,
+        sent_quantity: line.sent_quantity,
+    }
+}
+
+pub(crate) fn order_api(order: Order, waiter: WaiterInOrder, table: TableInOrder, products: Vec<ProductInOrder>) -> OrderAPI {
+    let sum = products.iter().fold(0.0, |acc, product| acc + product.price * product.quantity)
+        + order.custom_lines.iter().fold(0.0, |acc, line| acc + line.price * line.quantity);
+
+    OrderAPI {
+        _id: order._id,
+        waiter,
+        table,
+        products,
+        custom_lines: order.custom_lines,
+        sum,
+        created_at: order.created_at,
+        covers: order.covers,
+        payments: order.payments,
+        discounts: order.discounts,
+        voids: order.voids,
+        refunds: order.refunds,
+        closed_at: order.closed_at,
+    }
+}
+
+fn check_counted(product: &Product) -> Result<(), RepoError> {
+    let mut errors = ValidationErrors::default();
+    if product.unit != Unit::Piece {
+        errors.add("product_id", format!(
+            "product {} is sold per {}, set its quantity or pass a scale reading instead", product._id, product.unit
+        ));
+    }
+
+    Ok(errors.into_result()?)
+}
 
-    for num in arr {
-        *frequency.entry(num).or_insert(0) += 1;
+/// Open-price products need a price within their range, the rest keep the menu price.
+pub fn check_line_price(product: &Product, price: Option<f64>) -> Result<(), RepoError> {
+    let mut errors = ValidationErrors::default();
+    match (&product.open_price, price) {
+        (Some(range), Some(price)) if !range.contains(price) => errors.add("price", format!(
+            "{:.2} for product {} is outside {:.2} to {:.2}", price, product._id, range.min, range.max
+        )),
+        (Some(_), None) => errors.add("price", format!("is required, product {} is priced when ordered", product._id)),
+        (None, Some(_)) => errors.add("price", format!("must not be set, product {} has a fixed price of {:.2}", product._id, product.price)),
+        _ => {}
     }
 
-    frequency
-}
\ No newline at end of file
+    Ok(errors.into_result()?)
+}
+
+/// Turns scale readings into quantities in each product's unit, rounds every quantity to the
+/// unit's precision and checks prices entered for open-price products. Products no longer on
+/// the menu are counted in pieces.
+pub fn resolve_line_operations(operations: &[LineOperation], products: &[Product]) -> Result<Vec<LineOperation>, RepoError> {
+    operations.iter().map(|operation| {
+        let product_id = *operation.product_id();
+        let product = products.iter().find(|product| product._id == product_id);
+        let unit = product.map(|product| product.unit).unwrap_or_default();
+        let measured = |scale: &ScaleReading| {
+            if unit == Unit::Piece {
+                return Err(RepoError::InvalidState(format!("Product {} is counted in pieces, not weighed", product_id)));
+            }
+            if !scale.stable {
+                return Err(RepoError::InvalidState(format!("Scale reading for product {} is not stable yet", product_id)));
+            }
+            scale.unit.convert(scale.net(), unit).ok_or_else(|| RepoError::InvalidState(format!(
+                "Product {} is sold per {}, a scale reading in {} does not fit", product_id, unit, scale.unit
+            )))
+        };
+        let positive = |quantity: f64| {
+            let quantity = unit.round(quantity);
+            if quantity <= 0.0 {
+                return Err(RepoError::InvalidState(format!("Quantity of product {} rounds to nothing in {}", product_id, unit)));
+            }
+            Ok(quantity)
+        };
+
+        if let (LineOperation::Add { price, .. }, Some(product)) = (operation, product) {
+            check_line_price(product, *price)?;
+        }
+
+        Ok(match operation {
+            LineOperation::Add { scale: Some(scale), price, .. } => LineOperation::Add { product_id, quantity: positive(measured(scale)?)?, scale: None, price: *price },
+            LineOperation::Add { quantity, price, .. } => LineOperation::Add { product_id, quantity: positive(*quantity)?, scale: None, price: *price },
+            LineOperation::Remove { quantity, .. } => LineOperation::Remove { product_id, quantity: positive(*quantity)? },
+            LineOperation::SetQuantity { scale: Some(scale), .. } => LineOperation::SetQuantity { product_id, quantity: Some(unit.round(measured(scale)?)), scale: None },
+            LineOperation::SetQuantity { quantity, .. } => LineOperation::SetQuantity { product_id, quantity: quantity.map(|quantity| unit.round(quantity)), scale: None },
+        })
+    }).collect()
+}
+
+/// Works out the product lines after `operations`, failing on the first one that cannot be
+/// applied. Lines that end up empty are dropped. Scale readings have to be resolved into
+/// quantities first.
+pub fn apply_line_operations(lines: &[ProductIdWithQuantity], operations: &[LineOperation]) -> Result<Vec<ProductIdWithQuantity>, RepoError> {
+    let mut lines = lines.to_vec();
+
+    for operation in operations {
+        let product_id = *operation.product_id();
+        let index = lines.iter().position(|line| line._id == product_id);
+
+        match (operation, index) {
+            (LineOperation::Add { price, .. }, Some(index)) if lines[index].price != *price => {
+                return Err(RepoError::InvalidState(format!(
+                    "Product {} is already on the order at another price, add it as a custom line instead",
+                    product_id
+                )));
+            }
+            (LineOperation::Add { quantity, .. }, Some(index)) => lines[index].quantity += quantity,
+            (LineOperation::Add { quantity, price, .. }, None) => lines.push(ProductIdWithQuantity {
+                _id: product_id,
+                quantity: *quantity,
+                sent_quantity: 0.0,
+                price: *price,
+            }),
+            (LineOperation::Remove { .. }, None) => {
+                return Err(RepoError::InvalidState(format!("Product {} is not on the order", product_id)));
+            }
+            (LineOperation::Remove { quantity, .. }, Some(index)) => {
+                let line = &mut lines[index];
+                if line.quantity - quantity < line.sent_quantity {
+                    return Err(RepoError::InvalidState(format!(
+                        "Only {} of product {} can be removed, the rest was sent to the kitchen, void it instead",
+                        line.quantity - line.sent_quantity, product_id
+                    )));
+                }
+                line.quantity -= quantity;
+            }
+            (LineOperation::SetQuantity { quantity: None, .. }, _) => {
+                return Err(RepoError::InvalidState(format!("No quantity given for product {}", product_id)));
+            }
+            (LineOperation::SetQuantity { quantity: Some(quantity), .. }, Some(index)) => {
+                let line = &mut lines[index];
+                if *quantity < line.sent_quantity {
+                    return Err(RepoError::InvalidState(format!(
+                        "Product {} cannot go below the {} sent to the kitchen, void it instead",
+                        product_id, line.sent_quantity
+                    )));
+                }
+                line.quantity = *quantity;
+            }
+            (LineOperation::SetQuantity { quantity: Some(quantity), .. }, None) => lines.push(ProductIdWithQuantity {
+                _id: product_id,
+                quantity: *quantity,
+                sent_quantity: 0.0,
+                price: None,
+            }),
+        }
+    }
+
+    lines.retain(|line| line.quantity > 0.0);
+
+    Ok(lines)
+}
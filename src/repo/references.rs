@@ -0,0 +1,75 @@
+use mongodb::bson::{doc, Uuid};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::models::categories::Category;
+use crate::models::orders::Order;
+use crate::models::products::Product;
+use crate::models::tables::Table;
+use crate::models::waiters::Waiter;
+use crate::models::CollectionName;
+use crate::repo::error::{RepoError, UnknownReference};
+use crate::repo::repository::Repository;
+
+/// Collects the foreign keys of a document that do not point at anything.
+#[derive(Default)]
+pub struct ReferenceCheck {
+    unknown: Vec<UnknownReference>,
+}
+
+impl ReferenceCheck {
+    pub async fn check<T>(&mut self, repo: &Repository, field: &'static str, id: &Uuid) -> Result<(), RepoError>
+        where
+            T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
+    {
+        if !repo.exists::<T>(id).await? {
+            self.unknown.push(UnknownReference {
+                field,
+                collection: T::collection_name(),
+                id: *id,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), RepoError> {
+        if self.unknown.is_empty() {
+            Ok(())
+        } else {
+            Err(RepoError::UnknownReferences(self.unknown))
+        }
+    }
+}
+
+impl Repository {
+    /// Inserts an order once its waiter and table exist, the waiter is clocked in and the
+    /// business day is still open.
+    pub async fn insert_order(&self, order: Order) -> Result<(), RepoError> {
+        self.require_open_day(order.created_at).await?;
+
+        let mut references = ReferenceCheck::default();
+        references.check::<Waiter>(self, "waiter_id", &order.waiter_id).await?;
+        references.check::<Table>(self, "table_id", &order.table_id).await?;
+        references.finish()?;
+
+        if self.query_open_shift(&order.waiter_id).await?.is_none() {
+            return Err(RepoError::InvalidState(format!("Waiter {} is not clocked in", order.waiter_id)));
+        }
+
+        self.insert_one::<Order>(order).await
+    }
+
+    pub async fn insert_product(&self, product: Product) -> Result<(), RepoError> {
+        let mut references = ReferenceCheck::default();
+        references.check::<Category>(self, "category_id", &product.category_id).await?;
+        references.finish()?;
+
+        if let Some(sku) = &product.sku {
+            if self.get_collection::<Product>().count_documents(doc! { "sku": sku }, None).await? > 0 {
+                return Err(RepoError::AlreadyExists(format!("Product with SKU {}", sku)));
+            }
+        }
+
+        self.insert_one::<Product>(product).await
+    }
+}
@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, HashSet};
+use chrono::{Days, NaiveDate, NaiveTime, TimeZone};
+use mongodb::bson::{doc, DateTime, Uuid};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use crate::models::categories::{Category, CategoryId};
+use crate::models::orders::{Order, OrderAPI, Refund, Tender};
+use crate::models::products::{Product, ProductInOrder, Unit};
+use crate::models::reports::{ClosedDay, Counter, ReportKind, SalesReport, TaxLine, TenderLine, ZReport, ZReportId, Z_REPORT_COUNTER};
+use crate::models::tables::TableInOrder;
+use crate::models::waiters::WaiterInOrder;
+use crate::repo::error::RepoError;
+use crate::repo::orders::{order_api, product_in_order};
+use crate::repo::repository::Repository;
+
+/// Name shown in reports for a waiter, table, product or category that has been deleted.
+const UNKNOWN_NAME: &str = "Unknown";
+
+impl Repository {
+    /// Loads the orders and everything they point at in one query per collection. Reports have
+    /// to come out even when a waiter, table or product has been deleted since.
+    pub async fn query_orders_between(&self, start: DateTime, end: DateTime) -> Result<Vec<OrderAPI>, RepoError> {
+        let orders = self.query_many_by::<Order>(doc! { "created_at": { "$gte": start, "$lt": end } }).await?;
+
+        let ids = |id: fn(&Order) -> Vec<Uuid>| orders.iter().flat_map(id).collect::<HashSet<Uuid>>().into_iter().collect::<Vec<Uuid>>();
+        let waiters = self.query_many_by::<WaiterInOrder>(doc! { "_id": { "$in": ids(|order| vec![order.waiter_id]) } }).await?;
+        let tables = self.query_many_by::<TableInOrder>(doc! { "_id": { "$in": ids(|order| vec![order.table_id]) } }).await?;
+        let products = self.query_many_by::<Product>(doc! {
+            "_id": { "$in": ids(|order| order.products.iter().map(|line| line._id).collect()) }
+        }).await?;
+        let categories = self.query_all::<Category>().await?;
+
+        Ok(orders_with_references(orders, &waiters, &tables, &products, &categories))
+    }
+
+    pub async fn sales_report(&self, kind: ReportKind, day: NaiveDate, cutoff: NaiveTime) -> Result<SalesReport, RepoError> {
+        let (start, end) = business_day_bounds(&chrono::Local, day, cutoff);
+        let orders = self.query_orders_between(start, end).await?;
+        let refunds = self.query_refunds_between(start, end).await?;
+
+        Ok(build_sales_report(kind, day, start, end, &orders, &refunds))
+    }
+
+    pub async fn query_z_report(&self, day: NaiveDate) -> Result<Option<ZReport>, RepoError> {
+        let result = self.get_collection::<ZReport>()
+            .find_one(doc! { "business_day": day.to_string() }, None)
+            .await?;
+
+        Ok(result)
+    }
+
+    /// The day is reserved before anything else, so of two closes racing only one gets a number,
+    /// and from then on nothing can be dated into it. A day with open orders is not closed.
+    pub async fn close_business_day(&self, day: NaiveDate, cutoff: NaiveTime) -> Result<ZReport, RepoError> {
+        let (start, end) = business_day_bounds(&chrono::Local, day, cutoff);
+        let closed_day = ClosedDay {
+            _id: day.to_string(),
+            period_start: start,
+            period_end: end,
+            closed_at: DateTime::now(),
+        };
+        self.get_collection::<ClosedDay>()
+            .insert_one(closed_day, None)
+            .await
+            .map_err(|err| RepoError::from(err).on_duplicate_key(|| format!("Business day {} is already closed", day)))?;
+
+        let result = self.take_z_report(day, cutoff, start, end).await;
+        if result.is_err() {
+            if let Err(err) = self.get_collection::<ClosedDay>().delete_one(doc! { "_id": day.to_string() }, None).await {
+                tracing::error!("Reopening business day {} after a failed close failed: {}", day, err);
+            }
+        }
+
+        result
+    }
+
+    async fn take_z_report(&self, day: NaiveDate, cutoff: NaiveTime, start: DateTime, end: DateTime) -> Result<ZReport, RepoError> {
+        let open = self.query_many_by::<Order>(doc! { "created_at": { "$gte": start, "$lt": end }, "closed_at": null }).await?;
+        if !open.is_empty() {
+            return Err(RepoError::InvalidState(format!(
+                "Business day {} still has open orders: {}",
+                day,
+                open.iter().map(|order| order._id.to_string()).collect::<Vec<String>>().join(", "),
+            )));
+        }
+
+        let report = self.sales_report(ReportKind::Z, day, cutoff).await?;
+        let number = self.next_counter_value(Z_REPORT_COUNTER).await?;
+
+        let z_report = ZReport {
+            _id: ZReportId::new(),
+            number,
+            business_day: day.to_string(),
+            report,
+        };
+
+        self.insert_one::<ZReport>(z_report.clone())
+            .await
+            .map_err(|err| err.on_duplicate_key(|| format!("Business day {} is already closed", day)))?;
+
+        Ok(z_report)
+    }
+
+    /// Refuses anything dated `at` once the business day it falls into has been closed.
+    pub async fn require_open_day(&self, at: DateTime) -> Result<(), RepoError> {
+        let closed = self.get_collection::<ClosedDay>()
+            .find_one(doc! { "period_start": { "$lte": at }, "period_end": { "$gt": at } }, None)
+            .await?;
+
+        match closed {
+            Some(day) => Err(RepoError::InvalidState(format!("Business day {} is closed already", day._id))),
+            None => Ok(()),
+        }
+    }
+
+    async fn next_counter_value(&self, name: &str) -> Result<i64, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        let counter = self.get_collection::<Counter>()
+            .find_one_and_update(doc! { "_id": name }, doc! { "$inc": { "value": 1_i64 } }, options)
+            .await?
+            .ok_or_else(|| RepoError::InvalidState(format!("Counter {} could not be created", name)))?;
+
+        Ok(counter.value)
+    }
+}
+
+/// Start (inclusive) and end (exclusive) of a business day that opens at `cutoff` local time
+/// and runs until the same time on the following calendar day.
+pub fn business_day_bounds<Tz: TimeZone>(tz: &Tz, day: NaiveDate, cutoff: NaiveTime) -> (DateTime, DateTime) {
+    let next_day = day.checked_add_days(Days::new(1)).unwrap_or(day);
+
+    let to_bson = |date: NaiveDate| {
+        let local = date.and_time(cutoff);
+        let millis = tz
+            .from_local_datetime(&local)
+            .earliest()
+            .map(|dt| dt.timestamp_millis())
+            .unwrap_or_else(|| local.and_utc().timestamp_millis());
+        DateTime::from_millis(millis)
+    };
+
+    (to_bson(day), to_bson(next_day))
+}
+
+/// Business day a moment belongs to: anything before the cutoff still counts towards the previous day.
+pub fn business_day_of<Tz: TimeZone>(at: &chrono::DateTime<Tz>, cutoff: NaiveTime) -> NaiveDate {
+    let local = at.naive_local();
+
+    if local.time() < cutoff {
+        local.date().pred_opt().unwrap_or(local.date())
+    } else {
+        local.date()
+    }
+}
+
+/// Joins orders with their waiters, tables and products. References that no longer resolve show
+/// up as "Unknown"; a deleted product keeps its entered price, or 0 when it had none.
+pub fn orders_with_references(orders: Vec<Order>, waiters: &[WaiterInOrder], tables: &[TableInOrder], products: &[Product], categories: &[Category]) -> Vec<OrderAPI> {
+    let unknown_category = |id: CategoryId| Category {
+        _id: id,
+        name: UNKNOWN_NAME.to_string(),
+        icon: String::new(),
+        color: String::new(),
+    };
+
+    orders.into_iter().map(|order| {
+        let waiter = waiters.iter().find(|waiter| waiter._id == order.waiter_id).cloned()
+            .unwrap_or_else(|| WaiterInOrder { _id: order.waiter_id, name: UNKNOWN_NAME.to_string() });
+        let table = tables.iter().find(|table| table._id == order.table_id).cloned()
+            .unwrap_or_else(|| TableInOrder { _id: order.table_id, name: UNKNOWN_NAME.to_string() });
+
+        let lines = order.products.iter().map(|line| {
+            let product = products.iter().find(|product| product._id == line._id).cloned().unwrap_or_else(|| Product {
+                _id: line._id,
+                name: UNKNOWN_NAME.to_string(),
+                price: 0.0,
+                category_id: CategoryId::from_bytes([0; 16]),
+                tax_rate: 0.0,
+                sku: None,
+                unit: Unit::default(),
+                open_price: None,
+            });
+            let category = categories.iter().find(|category| category._id == product.category_id).cloned()
+                .unwrap_or_else(|| unknown_category(product.category_id));
+
+            product_in_order(line, product, category)
+        }).collect::<Vec<ProductInOrder>>();
+
+        order_api(order, waiter, table, lines)
+    }).collect()
+}
+
+/// `refunds` are those paid out during the day, which may belong to orders from earlier days.
+pub fn build_sales_report(kind: ReportKind, day: NaiveDate, start: DateTime, end: DateTime, orders: &[OrderAPI], refunds: &[Refund]) -> SalesReport {
+    let mut gross_sales = 0.0;
+    let mut discounts = 0.0;
+    let mut covers = 0;
+    let mut voids_count = 0;
+    let mut voids_amount = 0.0;
+    // Keyed by the rate in hundredths of a percent so that rates can be grouped exactly.
+    let mut tax: BTreeMap<i64, f64> = BTreeMap::new();
+    let mut payments: BTreeMap<Tender, TenderLine> = BTreeMap::new();
+
+    for order in orders {
+        let lines = order.products.iter()
+            .map(|product| (product.price * product.quantity, product.tax_rate))
+            .chain(order.custom_lines.iter().map(|line| (line.price * line.quantity, line.tax_rate)))
+            .collect::<Vec<(f64, f64)>>();
+        let order_gross = lines.iter().fold(0.0, |acc, (gross, _)| acc + gross);
+        let order_discount = order.discounts.iter().fold(0.0, |acc, discount| acc + discount.amount).min(order_gross);
+        let discount_ratio = if order_gross > 0.0 { order_discount / order_gross } else { 0.0 };
+
+        for (gross, tax_rate) in lines {
+            *tax.entry((tax_rate * 100.0).round() as i64).or_insert(0.0) += gross * (1.0 - discount_ratio);
+        }
+
+        for payment in &order.payments {
+            let line = payments.entry(payment.tender).or_insert(TenderLine {
+                tender: payment.tender,
+                count: 0,
+                amount: 0.0,
+                tips: 0.0,
+            });
+            line.count += 1;
+            line.amount += payment.amount;
+            line.tips += payment.tip;
+        }
+
+        for void in &order.voids {
+            voids_count += 1;
+            voids_amount += void.price * void.quantity;
+        }
+
+        gross_sales += order_gross;
+        discounts += order_discount;
+        covers += order.covers;
+    }
+
+    for refund in refunds {
+        payments.entry(refund.tender).or_insert(TenderLine {
+            tender: refund.tender,
+            count: 0,
+            amount: 0.0,
+            tips: 0.0,
+        }).amount -= refund.amount;
+    }
+    let refunds_amount = refunds.iter().fold(0.0, |acc, refund| acc + refund.amount);
+
+    let tax = tax
+        .into_iter()
+        .map(|(rate, gross)| {
+            let rate = rate as f64 / 100.0;
+            let net = gross / (1.0 + rate / 100.0);
+            TaxLine {
+                rate,
+                net: round2(net),
+                tax: round2(gross - net),
+                gross: round2(gross),
+            }
+        })
+        .collect::<Vec<TaxLine>>();
+    let tax_total = tax.iter().fold(0.0, |acc, line| acc + line.tax);
+
+    let payments = payments
+        .into_values()
+        .map(|line| TenderLine {
+            amount: round2(line.amount),
+            tips: round2(line.tips),
+            ..line
+        })
+        .collect::<Vec<TenderLine>>();
+    let payments_total = payments.iter().fold(0.0, |acc, line| acc + line.amount);
+    let tips_total = payments.iter().fold(0.0, |acc, line| acc + line.tips);
+
+    SalesReport {
+        kind,
+        business_day: day.to_string(),
+        period_start: start,
+        period_end: end,
+        gross_sales: round2(gross_sales),
+        discounts: round2(discounts),
+        net_sales: round2(gross_sales - discounts - tax_total),
+        tax,
+        tax_total: round2(tax_total),
+        payments,
+        payments_total: round2(payments_total),
+        tips_total: round2(tips_total),
+        voids_count,
+        voids_amount: round2(voids_amount),
+        refunds_count: refunds.len() as u32,
+        refunds_amount: round2(refunds_amount),
+        covers,
+        order_count: orders.len() as u32,
+        generated_at: DateTime::now(),
+    }
+}
+
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
@@ -1,43 +1,67 @@
-use dotenvy::dotenv;
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::config::DatabaseConfig;
 use mongodb::{Client, Database};
-use mongodb::bson::{doc, Uuid};
+use mongodb::bson::{doc, to_document, Document, Uuid};
+use mongodb::event::command::CommandEventHandler;
 use mongodb::options::{ClientOptions, Credential};
 use serde::de::DeserializeOwned;
 use serde::{Serialize};
-use crate::services::waiters::create_waiter_code_index;
 use futures::TryStreamExt;
 use crate::models::CollectionName;
+use crate::models::audit::Actor;
+use crate::repo::audit::document_id;
 use crate::repo::error::RepoError;
 
 #[derive(Clone, Debug)]
 pub struct Repository {
     database: Database,
+    /// Who mutations made through this handle are attributed to; `None` is the system itself.
+    actor: Option<Actor>,
 }
 
 impl Repository {
-    pub async fn connect() -> Self {
-        dotenv().expect(".env file not found");
-
-        let uri = dotenvy::var("DB_URI").expect("DB_URI must be set");
-        let username = dotenvy::var("DB_USERNAME").expect("DB_USERNAME must be set");
-        let password = dotenvy::var("DB_PASSWORD").expect("DB_PASSWORD must be set");
-        let db_name = dotenvy::var("DB_NAME").expect("DB_NAME must be set");
-
-        let mut client_options = ClientOptions::parse_async(uri).await.unwrap();
-        let default_cred = Credential::builder()
-            .username(username)
-            .password(password)
-            .source(db_name.clone())
-            .build();
-        client_options.credential = Some(default_cred);
-        let client = Client::with_options(client_options).unwrap();
-        let db = client.database(&*db_name);
-
-        create_waiter_code_index(&db).await;
-
-        Self {
-            database: db
+    /// Connects without touching the schema; see [`Repository::migrate`]. Command timings are
+    /// reported to `events`.
+    pub async fn connect(config: &DatabaseConfig, events: Option<Arc<dyn CommandEventHandler>>) -> Result<Self, RepoError> {
+        let mut client_options = ClientOptions::parse_async(&config.uri).await?;
+        client_options.command_event_handler = events;
+        if !config.username.is_empty() {
+            let default_cred = Credential::builder()
+                .username(config.username.clone())
+                .password(config.password.clone())
+                .source(config.name.clone())
+                .build();
+            client_options.credential = Some(default_cred);
         }
+        let client = Client::with_options(client_options)?;
+        let db = client.database(&config.name);
+
+        Ok(Self {
+            database: db,
+            actor: None,
+        })
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    pub async fn ping(&self) -> Result<(), RepoError> {
+        self.database.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    /// Handle whose mutations are attributed to `actor` in the audit log.
+    pub fn as_actor(&self, actor: Actor) -> Repository {
+        Repository {
+            database: self.database.clone(),
+            actor: Some(actor),
+        }
+    }
+
+    pub fn actor(&self) -> Actor {
+        self.actor.clone().unwrap_or_else(Actor::system)
     }
 
     pub fn get_collection<T>(&self) -> mongodb::Collection<T>
@@ -47,52 +71,76 @@ impl Repository {
         self.database.collection::<T>(T::collection_name())
     }
 
+    pub fn get_raw_collection(&self, name: &str) -> mongodb::Collection<Document> {
+        self.database.collection::<Document>(name)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
     pub async fn insert_one<T>(&self, document: T) -> Result<(), RepoError>
         where
             T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
     {
+        let after = to_document(&document).map_err(RepoError::BsonSerializationError)?;
+
         match self.get_collection::<T>().insert_one(document, None).await {
-            Ok(_) => Ok(()),
+            Ok(_) => self.audit::<T>("insert", document_id(&after), None, Some(&after)).await,
             Err(err) => Err(RepoError::MongoDBError(err)),
         }
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
     pub async fn query_one<T>(&self, id: &Uuid) -> Result<T, RepoError>
         where
             T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
     {
-        let bson_id = Uuid::parse_str(&id.to_string()).unwrap();
-
         match self.get_collection::<T>()
-            .find_one(Some(doc! {"_id": bson_id}), None)
+            .find_one(Some(doc! {"_id": id}), None)
             .await? {
             Some(result) => Ok(result),
-            None => Err(RepoError::IdNotFound(id.clone())),
+            None => Err(RepoError::IdNotFound(*id)),
         }
     }
 
-    pub async fn query_many<T>(&self, ids: &Vec<Uuid>) -> Result<Vec<T>, RepoError>
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
+    pub async fn exists<T>(&self, id: &Uuid) -> Result<bool, RepoError>
         where
             T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
     {
-        let bson_ids: Vec<Uuid> = ids.iter().map(|id| Uuid::parse_str(&id.to_string()).unwrap()).collect();
+        let count = self.get_collection::<T>()
+            .count_documents(doc! {"_id": id}, None)
+            .await?;
+
+        Ok(count > 0)
+    }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
+    pub async fn query_many<T>(&self, ids: &[Uuid]) -> Result<Vec<T>, RepoError>
+        where
+            T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
+    {
         let mut cursor = self.get_collection::<T>()
-            .find(Some(doc! {"_id": {"$in": bson_ids}}), None)
+            .find(Some(doc! {"_id": {"$in": ids}}), None)
             .await?;
 
         let mut results: Vec<T> = Vec::new();
+        let mut found: HashSet<Uuid> = HashSet::new();
         while let Some(result) = cursor.try_next().await? {
+            if let Some(id) = to_document(&result).ok().as_ref().and_then(document_id) {
+                found.insert(id);
+            }
             results.push(result)
         }
 
-        if results.len() == ids.len() {
+        // Inserting into `found` reports every missing id once, even when it was requested twice.
+        let missing: Vec<Uuid> = ids.iter().filter(|id| found.insert(**id)).copied().collect();
+        if missing.is_empty() {
             Ok(results)
         } else {
-            Err(RepoError::IdsNotFound(ids.clone()))
+            Err(RepoError::IdsNotFound(missing))
         }
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
     pub async fn query_all<T>(&self) -> Result<Vec<T>, RepoError>
         where
             T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
@@ -108,15 +156,33 @@ impl Repository {
         Ok(results)
     }
 
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
+    pub async fn query_many_by<T>(&self, filter: Document) -> Result<Vec<T>, RepoError>
+        where
+            T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
+    {
+        let mut cursor = self.get_collection::<T>()
+            .find(Some(filter), None)
+            .await?;
+
+        let mut results: Vec<T> = Vec::new();
+        while let Some(result) = cursor.try_next().await? {
+            results.push(result)
+        }
+        Ok(results)
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(collection = T::collection_name()))]
     pub async fn delete_one<T>(&self, id: &Uuid) -> Result<(), RepoError>
         where
             T: Serialize + DeserializeOwned + Unpin + Send + Sync + CollectionName,
     {
-        let bson_id = Uuid::parse_str(&id.to_string()).unwrap();
+        let before = self.snapshot::<T>(id).await?;
 
         match self.get_collection::<T>()
-            .delete_one(doc! {"_id": bson_id}, None)
+            .delete_one(doc! {"_id": id}, None)
             .await {
+            Ok(result) if result.deleted_count > 0 => self.audit::<T>("delete", Some(*id), before.as_ref(), None).await,
             Ok(_) => Ok(()),
             Err(err) => Err(RepoError::MongoDBError(err)),
         }
@@ -0,0 +1,192 @@
+use mongodb::bson::{doc, to_bson, DateTime};
+use mongodb::options::UpdateOptions;
+use serde::Deserialize;
+use crate::models::orders::Tender;
+use crate::models::shifts::{Break, CashDrawer, CashDrop, NewCashDrop, Shift, ShiftId};
+use crate::models::waiters::{Waiter, WaiterId};
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+#[derive(Deserialize)]
+struct CashTotal {
+    total: f64,
+}
+
+impl Repository {
+    pub async fn query_open_shift(&self, waiter_id: &WaiterId) -> Result<Option<Shift>, RepoError> {
+        let result = self.get_collection::<Shift>()
+            .find_one(doc! { "waiter_id": waiter_id, "clock_out": null }, None)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn query_shifts_by_waiter(&self, waiter_id: &WaiterId) -> Result<Vec<Shift>, RepoError> {
+        self.query_many_by::<Shift>(doc! { "waiter_id": waiter_id }).await
+    }
+
+    pub async fn query_open_shifts(&self) -> Result<Vec<Shift>, RepoError> {
+        self.query_many_by::<Shift>(doc! { "clock_out": null }).await
+    }
+
+    pub async fn clock_in(&self, waiter_id: &WaiterId, opening_float: f64) -> Result<Shift, RepoError> {
+        self.query_one::<Waiter>(waiter_id).await?;
+
+        if self.query_open_shift(waiter_id).await?.is_some() {
+            return Err(RepoError::AlreadyExists(format!("Waiter {} is already clocked in", waiter_id)));
+        }
+
+        let shift = Shift {
+            _id: ShiftId::new(),
+            waiter_id: *waiter_id,
+            clock_in: DateTime::now(),
+            clock_out: None,
+            breaks: vec![],
+            drawer: CashDrawer {
+                opening_float,
+                drops: vec![],
+                cash_taken: None,
+                expected_cash: None,
+                counted_cash: None,
+                variance: None,
+            },
+        };
+
+        // The unique index on open shifts catches a second clock-in racing this one.
+        self.insert_one::<Shift>(shift.clone())
+            .await
+            .map_err(|err| err.on_duplicate_key(|| format!("Waiter {} is already clocked in", waiter_id)))?;
+
+        Ok(shift)
+    }
+
+    pub async fn shift_start_break(&self, id: &ShiftId) -> Result<Shift, RepoError> {
+        let shift = self.query_open_shift_by_id(id).await?;
+        let before = self.snapshot::<Shift>(id).await?;
+        if shift.breaks.iter().any(|b| b.end.is_none()) {
+            return Err(RepoError::InvalidState(format!("Shift {} is already on a break", id)));
+        }
+
+        let break_bson = to_bson(&Break { start: DateTime::now(), end: None }).map_err(RepoError::BsonSerializationError)?;
+
+        self.get_collection::<Shift>().update_one(
+            doc! { "_id": id },
+            doc! { "$push": { "breaks": break_bson } },
+            None,
+        ).await?;
+
+        self.audit_update::<Shift>("start_break", id, before).await?;
+
+        self.query_one::<Shift>(id).await
+    }
+
+    pub async fn shift_end_break(&self, id: &ShiftId) -> Result<Shift, RepoError> {
+        self.query_open_shift_by_id(id).await?;
+        let before = self.snapshot::<Shift>(id).await?;
+
+        let result = self.get_collection::<Shift>().update_one(
+            doc! { "_id": id, "breaks.end": null },
+            doc! { "$set": { "breaks.$.end": DateTime::now() } },
+            None,
+        ).await?;
+
+        if result.matched_count == 0 {
+            return Err(RepoError::InvalidState(format!("Shift {} is not on a break", id)));
+        }
+
+        self.audit_update::<Shift>("end_break", id, before).await?;
+
+        self.query_one::<Shift>(id).await
+    }
+
+    pub async fn shift_add_cash_drop(&self, id: &ShiftId, cash_drop: NewCashDrop) -> Result<Shift, RepoError> {
+        self.query_open_shift_by_id(id).await?;
+        let before = self.snapshot::<Shift>(id).await?;
+
+        let cash_drop = CashDrop {
+            amount: cash_drop.amount,
+            reason: cash_drop.reason,
+            dropped_at: DateTime::now(),
+        };
+        let drop_bson = to_bson(&cash_drop).map_err(RepoError::BsonSerializationError)?;
+
+        self.get_collection::<Shift>().update_one(
+            doc! { "_id": id },
+            doc! { "$push": { "drawer.drops": drop_bson } },
+            None,
+        ).await?;
+
+        self.audit_update::<Shift>("add_cash_drop", id, before).await?;
+
+        self.query_one::<Shift>(id).await
+    }
+
+    pub async fn clock_out(&self, id: &ShiftId, counted_cash: f64) -> Result<Shift, RepoError> {
+        let shift = self.query_open_shift_by_id(id).await?;
+        let before = self.snapshot::<Shift>(id).await?;
+        let now = DateTime::now();
+
+        let cash_taken = self.query_cash_taken(&shift.waiter_id, shift.clock_in, now).await?;
+        let dropped = shift.drawer.drops.iter().fold(0.0, |acc, cash_drop| acc + cash_drop.amount);
+        let expected_cash = shift.drawer.opening_float + cash_taken - dropped;
+
+        let result = self.get_collection::<Shift>().update_one(
+            doc! { "_id": id, "clock_out": null },
+            doc! {
+                "$set": {
+                    "clock_out": now,
+                    "breaks.$[open].end": now,
+                    "drawer.cash_taken": cash_taken,
+                    "drawer.expected_cash": expected_cash,
+                    "drawer.counted_cash": counted_cash,
+                    "drawer.variance": counted_cash - expected_cash,
+                }
+            },
+            UpdateOptions::builder()
+                .array_filters(vec![doc! { "open.end": null }])
+                .build(),
+        ).await?;
+        if result.matched_count == 0 {
+            return Err(RepoError::InvalidState(format!("Shift {} is already closed", id)));
+        }
+
+        self.audit_update::<Shift>("clock_out", id, before).await?;
+
+        self.query_one::<Shift>(id).await
+    }
+
+    /// Cash (amount and tip) paid on the waiter's orders between `from` and `to`, less cash refunded.
+    pub async fn query_cash_taken(&self, waiter_id: &WaiterId, from: DateTime, to: DateTime) -> Result<f64, RepoError> {
+        let cash = to_bson(&Tender::Cash).map_err(RepoError::BsonSerializationError)?;
+
+        let taken = self.aggregate_orders::<CashTotal>(vec![
+            doc! { "$match": { "waiter_id": waiter_id } },
+            doc! { "$unwind": "$payments" },
+            doc! { "$match": { "payments.tender": cash.clone(), "payments.paid_at": { "$gte": from, "$lt": to } } },
+            doc! {
+                "$group": {
+                    "_id": null,
+                    "total": { "$sum": { "$add": ["$payments.amount", { "$ifNull": ["$payments.tip", 0] }] } },
+                }
+            },
+        ]).await?.pop();
+
+        let refunded = self.aggregate_orders::<CashTotal>(vec![
+            doc! { "$match": { "waiter_id": waiter_id } },
+            doc! { "$unwind": "$refunds" },
+            doc! { "$match": { "refunds.tender": cash, "refunds.refunded_at": { "$gte": from, "$lt": to } } },
+            doc! { "$group": { "_id": null, "total": { "$sum": "$refunds.amount" } } },
+        ]).await?.pop();
+
+        Ok(taken.map(|cash| cash.total).unwrap_or(0.0) - refunded.map(|cash| cash.total).unwrap_or(0.0))
+    }
+
+    async fn query_open_shift_by_id(&self, id: &ShiftId) -> Result<Shift, RepoError> {
+        let shift = self.query_one::<Shift>(id).await?;
+        if shift.clock_out.is_some() {
+            return Err(RepoError::InvalidState(format!("Shift {} is already closed", id)));
+        }
+
+        Ok(shift)
+    }
+}
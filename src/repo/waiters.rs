@@ -0,0 +1,74 @@
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use crate::models::waiters::{Waiter, WaiterId, WaiterSession};
+use crate::repo::error::RepoError;
+use crate::repo::repository::Repository;
+
+impl Repository {
+    /// Counts a failed PIN attempt and locks the waiter out once `max_attempts` is reached.
+    pub async fn waiter_login_failed(&self, id: &WaiterId, max_attempts: u32, lockout_millis: i64) -> Result<Waiter, RepoError> {
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let waiter = self.get_collection::<Waiter>().find_one_and_update(
+            doc! { "_id": id },
+            doc! { "$inc": { "failed_attempts": 1 } },
+            options,
+        ).await?.ok_or(RepoError::IdNotFound(*id))?;
+
+        if waiter.failed_attempts < max_attempts {
+            return Ok(waiter);
+        }
+
+        let locked_until = DateTime::from_millis(DateTime::now().timestamp_millis() + lockout_millis);
+        self.get_collection::<Waiter>().update_one(
+            doc! { "_id": id },
+            doc! { "$set": { "failed_attempts": 0, "locked_until": locked_until } },
+            None,
+        ).await?;
+
+        self.query_one::<Waiter>(id).await
+    }
+
+    /// Clears the lockout counters, replacing a legacy plaintext code with `pin_hash` when given.
+    pub async fn waiter_login_succeeded(&self, id: &WaiterId, pin_hash: Option<String>) -> Result<(), RepoError> {
+        let mut update = doc! {
+            "$set": { "failed_attempts": 0, "locked_until": null },
+        };
+        if let Some(pin_hash) = pin_hash {
+            update = doc! {
+                "$set": { "failed_attempts": 0, "locked_until": null, "pin_hash": pin_hash },
+                "$unset": { "code": "" },
+            };
+        }
+
+        self.get_collection::<Waiter>().update_one(doc! { "_id": id }, update, None).await?;
+
+        Ok(())
+    }
+
+    pub async fn query_waiter_session(&self, token_hash: &str) -> Result<Option<WaiterSession>, RepoError> {
+        let result = self.get_collection::<WaiterSession>()
+            .find_one(doc! { "_id": token_hash, "expires_at": { "$gt": DateTime::now() } }, None)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn delete_waiter_session(&self, token_hash: &str) -> Result<(), RepoError> {
+        self.get_collection::<WaiterSession>()
+            .delete_one(doc! { "_id": token_hash }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_waiter_sessions(&self, waiter_id: &WaiterId) -> Result<(), RepoError> {
+        self.get_collection::<WaiterSession>()
+            .delete_many(doc! { "waiter_id": waiter_id }, None)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::NaiveTime;
+use crate::config::ReportsConfig;
+use crate::models::analytics::{AnalyticsFilter, AnalyticsQuery};
+use crate::models::waiters::WaiterId;
+use crate::repo::reports::{business_day_bounds, business_day_of};
+use crate::repo::repository::Repository;
+use crate::services::error::ServiceError;
+use crate::services::reports::parse_day;
+use crate::services::roles::{Authorized, Managers};
+
+const DEFAULT_ANALYTICS_LIMIT: i64 = 10;
+
+#[get("/reports/analytics/products")]
+pub(crate) async fn get_product_mix(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AnalyticsQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = analytics_filter(&query, reports.business_day_cutoff)?;
+    let limit = query.limit.unwrap_or(DEFAULT_ANALYTICS_LIMIT).max(1);
+
+    let result = repo.query_product_mix(&filter, limit).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/reports/analytics/categories")]
+pub(crate) async fn get_category_sales(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AnalyticsQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = analytics_filter(&query, reports.business_day_cutoff)?;
+
+    let result = repo.query_category_sales(&filter).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/reports/analytics/hourly")]
+pub(crate) async fn get_hourly_sales(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AnalyticsQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = analytics_filter(&query, reports.business_day_cutoff)?;
+    let timezone = local_timezone();
+
+    let result = repo.query_hourly_sales(&filter, &timezone).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/reports/analytics/tickets")]
+pub(crate) async fn get_ticket_stats(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AnalyticsQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = analytics_filter(&query, reports.business_day_cutoff)?;
+
+    let result = repo.query_ticket_stats(&filter).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/reports/waiters")]
+pub(crate) async fn get_waiter_performance(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AnalyticsQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = analytics_filter(&query, reports.business_day_cutoff)?;
+
+    let result = repo.query_waiter_performance(&filter).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Turns `from`/`to` business days (both inclusive, defaulting to the current one) into a time range.
+pub(crate) fn analytics_filter(query: &AnalyticsQuery, cutoff: NaiveTime) -> Result<AnalyticsFilter, ServiceError> {
+    let from = match &query.from {
+        Some(day) => parse_day(day)?,
+        None => business_day_of(&chrono::Local::now(), cutoff),
+    };
+    let to = match &query.to {
+        Some(day) => parse_day(day)?,
+        None => from,
+    };
+
+    if to < from {
+        return Err(ServiceError::BadRequest(format!("Range end {} is before its start {}", to, from)));
+    }
+
+    let waiter_id = match &query.waiter_id {
+        Some(id) => Some(WaiterId::parse_str(id).map_err(|err| ServiceError::BadRequest(format!("Invalid waiter id {}: {}", id, err)))?),
+        None => None,
+    };
+
+    let (start, _) = business_day_bounds(&chrono::Local, from, cutoff);
+    let (_, end) = business_day_bounds(&chrono::Local, to, cutoff);
+
+    Ok(AnalyticsFilter {
+        from: start,
+        to: end,
+        waiter_id,
+    })
+}
+
+/// The IANA name of the zone `chrono::Local` uses, so MongoDB buckets orders from either side of
+/// a DST change by their own offset. Falls back to today's fixed offset if the name is unknown.
+fn local_timezone() -> String {
+    iana_time_zone::get_timezone().unwrap_or_else(|err| {
+        tracing::warn!("Could not determine the local time zone name, using the current offset: {}", err);
+        chrono::Local::now().offset().to_string()
+    })
+}
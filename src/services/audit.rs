@@ -0,0 +1,120 @@
+use std::ops::Deref;
+use actix_web::{get, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::Payload;
+use chrono::NaiveTime;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::{doc, Document, Uuid};
+use crate::config::ReportsConfig;
+use crate::models::audit::{Actor, AuditQuery};
+use crate::repo::reports::business_day_bounds;
+use crate::repo::repository::Repository;
+use crate::services::devices::CurrentDevice;
+use crate::services::error::ServiceError;
+use crate::services::shutdown::InFlight;
+use crate::services::reports::parse_day;
+use crate::services::roles::{Authorized, Managers, Principal};
+use crate::services::waiter_session::{AuthenticatedWaiter, WAITER_TOKEN_HEADER};
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+#[get("/audit")]
+pub(crate) async fn get_audit_log(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<AuditQuery>) -> Result<HttpResponse, ServiceError> {
+    let filter = audit_filter(&query, reports.business_day_cutoff)?;
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, MAX_AUDIT_LIMIT);
+
+    let result = repo.query_audit_log(filter, limit).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+fn audit_filter(query: &AuditQuery, cutoff: NaiveTime) -> Result<Document, ServiceError> {
+    let parse_id = |name: &str, id: &str| Uuid::parse_str(id).map_err(|err| ServiceError::BadRequest(format!("Invalid {} {}: {}", name, id, err)));
+
+    let mut filter = doc! {};
+    if let Some(entity) = &query.entity {
+        filter.insert("entity", entity);
+    }
+    if let Some(id) = &query.entity_id {
+        filter.insert("entity_id", parse_id("entity id", id)?);
+    }
+    if let Some(action) = &query.action {
+        filter.insert("action", action);
+    }
+    if let Some(subject) = &query.subject {
+        filter.insert("actor.subject", subject);
+    }
+    if let Some(id) = &query.waiter_id {
+        filter.insert("actor.waiter_id", parse_id("waiter id", id)?);
+    }
+    if let Some(id) = &query.device_id {
+        filter.insert("actor.device_id", parse_id("device id", id)?);
+    }
+
+    if query.from.is_some() || query.to.is_some() {
+        let mut at = doc! {};
+        if let Some(day) = &query.from {
+            at.insert("$gte", business_day_bounds(&chrono::Local, parse_day(day)?, cutoff).0);
+        }
+        if let Some(day) = &query.to {
+            at.insert("$lt", business_day_bounds(&chrono::Local, parse_day(day)?, cutoff).1);
+        }
+        filter.insert("at", at);
+    }
+
+    Ok(filter)
+}
+
+/// Repository whose mutations are written to the audit log on behalf of the caller: the
+/// authenticated principal, the device the request came from and the waiter logged in on it.
+pub struct AuditedRepository {
+    repo: Repository,
+    /// Lets a shutdown wait for this request to finish.
+    _mutation: InFlight,
+}
+
+impl Deref for AuditedRepository {
+    type Target = Repository;
+
+    fn deref(&self) -> &Self::Target {
+        &self.repo
+    }
+}
+
+impl FromRequest for AuditedRepository {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let repo = req
+                .app_data::<web::Data<Repository>>()
+                .ok_or_else(|| ServiceError::InternalError("Repository is not configured".to_string()))?;
+            let guard = InFlight::enter(&req)?;
+
+            let device = CurrentDevice::extract(&req).await?;
+            let principal = req.extensions().get::<Principal>().cloned();
+
+            // A waiter token only counts from the terminal it was issued to, like everywhere else.
+            let waiter_id = if req.headers().contains_key(WAITER_TOKEN_HEADER) {
+                Some(AuthenticatedWaiter::extract(&req).await?.waiter_id)
+            } else {
+                None
+            };
+
+            let actor = Actor {
+                subject: principal.as_ref().map(|principal| principal.subject.clone()).unwrap_or_else(|| "anonymous".to_string()),
+                username: principal.and_then(|principal| principal.username),
+                waiter_id,
+                device_id: device.id(),
+            };
+
+            Ok(AuditedRepository {
+                repo: repo.as_actor(actor),
+                _mutation: guard,
+            })
+        })
+    }
+}
@@ -1,64 +1,223 @@
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::pin::Pin;
-use jsonwebtoken::{Algorithm, decode, DecodingKey, Validation};
+use std::str::FromStr;
+use jsonwebtoken::{Algorithm, decode, decode_header, DecodingKey, Validation};
 use jsonwebtoken::errors::ErrorKind;
 use actix_web::{dev::ServiceRequest, Error as ActixError};
-use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
+use actix_web_httpauth::extractors::bearer::{self, BearerAuth, Config};
 use actix_web_httpauth::extractors::AuthenticationError;
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use actix_web::{web, HttpMessage};
+use crate::config::TokenConfig;
+use crate::services::jwks::JwksCache;
+use crate::services::local_auth::LocalAuthSettings;
+use crate::services::metrics::Metrics;
+use crate::repo::repository::Repository;
+use crate::services::waiter_session::{session_token_hash, TERMINAL_ID_HEADER};
+use crate::services::roles::{Principal, Role};
 
-#[derive(Debug, Deserialize, Serialize)]
-struct JWKS {
-    keys: Vec<JWK>,
+/// What a bearer token must look like to be accepted. Empty `issuers` or `audiences`
+/// switch the corresponding check off.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TokenValidationSettings {
+    pub issuers: Vec<String>,
+    pub audiences: Vec<String>,
+    pub algorithms: Vec<Algorithm>,
+    pub leeway_secs: u64,
+    pub client_id: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct JWK {
-    kty: String,
-    n: String,
-    e: String,
-    use_: Option<String>,
-    alg: Option<String>,
-    kid: Option<String>,
-    x5c: Option<Vec<String>>,
-}
+impl TokenValidationSettings {
+    pub fn from_config(config: &TokenConfig) -> Result<Self, String> {
+        let algorithms = config.algorithms
+            .iter()
+            .map(|name| parse_algorithm(name))
+            .collect::<Result<Vec<Algorithm>, String>>()?;
+        if algorithms.is_empty() {
+            return Err("auth.token.algorithms must list at least one algorithm".to_string());
+        }
 
-async fn get_public_keys() -> Result<JWKS, Box<dyn Error>> {
-    let jwks_url = std::env::var("API_AUTH_CERTS").map_err(|e| format!("API_AUTH_CERTS must be set: {e:?}")).expect("AUTH_CERTS must be set");
-    let response = reqwest::get(&jwks_url).await?;
-    let jwks: JWKS = response.json().await?;
+        Ok(Self {
+            issuers: config.issuers.clone(),
+            audiences: config.audiences.clone(),
+            algorithms,
+            leeway_secs: config.leeway_secs,
+            client_id: config.client_id.clone().filter(|id| !id.is_empty()),
+        })
+    }
+}
 
-    Ok(jwks)
+/// Only asymmetric algorithms make sense for keys published by an identity provider.
+pub fn parse_algorithm(name: &str) -> Result<Algorithm, String> {
+    match Algorithm::from_str(name) {
+        Ok(algorithm @ (Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512
+        | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA)) => Ok(algorithm),
+        _ => Err(format!("Unsupported token algorithm {}", name)),
+    }
 }
 
+/// `aud`, `iss` and `exp` are checked by `Validation` on the raw token.
 #[derive(Debug, Deserialize)]
-struct Claims {
-    aud: String,
+pub struct Claims {
     sub: String,
-    exp: u64,
+    preferred_username: Option<String>,
+    #[serde(default)]
+    realm_access: Option<RoleClaims>,
+    #[serde(default)]
+    resource_access: HashMap<String, RoleClaims>,
 }
 
-pub async fn validate_token(token: &str) -> Result<bool, Box<dyn Error>> {
-    let public_keys = get_public_keys().await?.keys;
-    for key in public_keys {
-        let decoding_key = DecodingKey::from_rsa_components(&key.n, &key.e)?;
+#[derive(Debug, Default, Deserialize)]
+struct RoleClaims {
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl Claims {
+    /// Realm roles plus the roles granted on `client_id`, if one is given.
+    pub fn principal(&self, client_id: Option<&str>) -> Principal {
+        let client_roles = client_id
+            .and_then(|client_id| self.resource_access.get(client_id))
+            .map(|access| access.roles.iter())
+            .into_iter()
+            .flatten();
+
+        let roles = self.realm_access
+            .iter()
+            .flat_map(|access| access.roles.iter())
+            .chain(client_roles)
+            .filter_map(|name| Role::from_claim(name))
+            .collect();
+
+        Principal {
+            subject: self.sub.clone(),
+            username: self.preferred_username.clone(),
+            roles,
+        }
+    }
+}
 
-        let mut validation = Validation::new(Algorithm::RS256);
-        validation.set_audience(&["account"]);
+/// Why a token was rejected, reported to the client in the `WWW-Authenticate` header.
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    Malformed(String),
+    AlgorithmNotAccepted(Algorithm),
+    UnknownKey,
+    KeysUnavailable(String),
+    BadSignature,
+    Expired,
+    NotYetValid,
+    WrongAudience,
+    WrongIssuer,
+    WrongTerminal,
+    UnknownDevice,
+    SessionEnded,
+    Invalid(String),
+}
+
+impl TokenError {
+    /// Short label for the auth failures metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            TokenError::Malformed(_) => "malformed",
+            TokenError::AlgorithmNotAccepted(_) => "algorithm_not_accepted",
+            TokenError::UnknownKey => "unknown_key",
+            TokenError::KeysUnavailable(_) => "keys_unavailable",
+            TokenError::BadSignature => "bad_signature",
+            TokenError::Expired => "expired",
+            TokenError::NotYetValid => "not_yet_valid",
+            TokenError::WrongAudience => "wrong_audience",
+            TokenError::WrongIssuer => "wrong_issuer",
+            TokenError::WrongTerminal => "wrong_terminal",
+            TokenError::UnknownDevice => "unknown_device",
+            TokenError::SessionEnded => "session_ended",
+            TokenError::Invalid(_) => "invalid",
+        }
+    }
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Malformed(err) => write!(f, "malformed token: {}", err),
+            TokenError::AlgorithmNotAccepted(algorithm) => write!(f, "algorithm {:?} is not accepted", algorithm),
+            TokenError::UnknownKey => write!(f, "unknown signing key"),
+            TokenError::KeysUnavailable(err) => write!(f, "signing keys unavailable: {}", err),
+            TokenError::BadSignature => write!(f, "bad signature"),
+            TokenError::Expired => write!(f, "token expired"),
+            TokenError::NotYetValid => write!(f, "token not yet valid"),
+            TokenError::WrongAudience => write!(f, "wrong audience"),
+            TokenError::WrongIssuer => write!(f, "wrong issuer"),
+            TokenError::WrongTerminal => write!(f, "token was issued to another terminal"),
+            TokenError::UnknownDevice => write!(f, "unknown or revoked device"),
+            TokenError::SessionEnded => write!(f, "waiter session ended"),
+            TokenError::Invalid(err) => write!(f, "invalid token: {}", err),
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for TokenError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        match error.kind() {
+            ErrorKind::InvalidSignature => TokenError::BadSignature,
+            ErrorKind::ExpiredSignature => TokenError::Expired,
+            ErrorKind::ImmatureSignature => TokenError::NotYetValid,
+            ErrorKind::InvalidAudience => TokenError::WrongAudience,
+            ErrorKind::InvalidIssuer => TokenError::WrongIssuer,
+            ErrorKind::InvalidToken | ErrorKind::Base64(_) | ErrorKind::Json(_) | ErrorKind::Utf8(_) => TokenError::Malformed(error.to_string()),
+            _ => TokenError::Invalid(error.to_string()),
+        }
+    }
+}
 
-        match decode::<Claims>(&token, &decoding_key, &validation) {
+pub async fn validate_token(token: &str, jwks: &JwksCache, settings: &TokenValidationSettings) -> Result<Claims, TokenError> {
+    let header = decode_header(token)?;
+    if !settings.algorithms.contains(&header.alg) {
+        return Err(TokenError::AlgorithmNotAccepted(header.alg));
+    }
+
+    let public_keys = jwks
+        .keys_for(header.kid.as_deref())
+        .await
+        .map_err(|err| TokenError::KeysUnavailable(err.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.leeway = settings.leeway_secs;
+    if settings.audiences.is_empty() {
+        validation.validate_aud = false;
+    } else {
+        validation.set_audience(&settings.audiences);
+    }
+    if !settings.issuers.is_empty() {
+        validation.set_issuer(&settings.issuers);
+    }
+
+    let mut result = Err(TokenError::UnknownKey);
+    for key in public_keys {
+        let decoding_key = match DecodingKey::from_jwk(&key) {
+            Ok(decoding_key) => decoding_key,
+            Err(err) => {
+                tracing::warn!("Skipping unusable signing key {:?}: {}", key.common.key_id, err);
+                continue;
+            }
+        };
+
+        match decode::<Claims>(token, &decoding_key, &validation) {
             Ok(res) => {
-                log::info!("Token validated: {:?}", res.claims);
-                return Ok(true);
+                tracing::info!("Token validated for {}", res.claims.sub);
+                return Ok(res.claims);
+            }
+            // Without a kid every key is tried, so a signature mismatch only means "not this key".
+            Err(err) if matches!(err.kind(), ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm) => {
+                result = Err(TokenError::BadSignature);
             }
-            Err(err) => match err.kind() {
-                ErrorKind::InvalidToken => continue,
-                other_kind => return Err(format!("Error validating token: {:?}", other_kind).into()),
-            },
+            Err(err) => return Err(err.into()),
         }
     }
 
-    Ok(false)
+    result
 }
 
 pub async fn validator(req: ServiceRequest, credentials: BearerAuth) -> Result<ServiceRequest, (ActixError, ServiceRequest)> {
@@ -67,16 +226,73 @@ pub async fn validator(req: ServiceRequest, credentials: BearerAuth) -> Result<S
         .map(|data| Pin::new(data).get_ref().clone())
         .unwrap_or_default();
 
-    match validate_token(credentials.token()).await {
-        Ok(res) => {
-            if res {
-                Ok(req)
-            } else {
-                Err((AuthenticationError::from(config).into(), req))
-            }
+    let result = match req.app_data::<web::Data<LocalAuthSettings>>() {
+        Some(local) => validate_local(&req, credentials.token(), local).await,
+        None => match (req.app_data::<web::Data<JwksCache>>(), req.app_data::<web::Data<TokenValidationSettings>>()) {
+            (Some(jwks), Some(settings)) => validate_token(credentials.token(), jwks, settings)
+                .await
+                .map(|claims| claims.principal(settings.client_id.as_deref())),
+            _ => return Err((AuthenticationError::from(config).into(), req)),
+        },
+    };
+
+    match result {
+        Ok(principal) => {
+            tracing::Span::current().record("subject", principal.subject.as_str());
+            req.extensions_mut().insert(principal);
+            Ok(req)
         }
         Err(err) => {
-            Err((AuthenticationError::from(config).into(), req))
+            tracing::info!("Rejecting bearer token: {}", err);
+            if let Some(metrics) = req.app_data::<web::Data<Metrics>>() {
+                metrics.auth_failed(err.reason());
+            }
+            let error = AuthenticationError::from(config)
+                .with_error(bearer::Error::InvalidToken)
+                .with_error_description(err.to_string().replace('"', "'"));
+            Err((error.into(), req))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Device keys are checked first, then credentials of paired devices; anything else must be a
+/// token we issued to a waiter on the terminal sending the request, whose session is still open.
+async fn validate_local(req: &ServiceRequest, token: &str, settings: &LocalAuthSettings) -> Result<Principal, TokenError> {
+    if let Some(device) = settings.device(token) {
+        return Ok(device.principal());
+    }
+
+    if !token.contains('.') {
+        let repo = req.app_data::<web::Data<Repository>>().ok_or(TokenError::UnknownDevice)?;
+        let device = repo
+            .query_device_by_credential(&session_token_hash(token))
+            .await
+            .map_err(|err| TokenError::KeysUnavailable(err.to_string()))?
+            .ok_or(TokenError::UnknownDevice)?;
+
+        let principal = Principal {
+            subject: format!("device:{}", device._id),
+            username: Some(device.name.clone()),
+            roles: HashSet::from([Role::Waiter]),
+        };
+        req.extensions_mut().insert(device);
+        return Ok(principal);
+    }
+
+    let claims = settings.validate_token(token)?;
+    let terminal_id = req.headers().get(TERMINAL_ID_HEADER).and_then(|value| value.to_str().ok());
+    if terminal_id != Some(claims.terminal_id.as_str()) {
+        return Err(TokenError::WrongTerminal);
+    }
+
+    let repo = req.app_data::<web::Data<Repository>>().ok_or(TokenError::SessionEnded)?;
+    let session = repo
+        .query_waiter_session(&claims.sid)
+        .await
+        .map_err(|err| TokenError::KeysUnavailable(err.to_string()))?;
+    if !session.is_some_and(|session| session.waiter_id.to_string() == claims.sub && session.terminal_id == claims.terminal_id) {
+        return Err(TokenError::SessionEnded);
+    }
+
+    Ok(claims.principal())
+}
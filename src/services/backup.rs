@@ -0,0 +1,43 @@
+use actix_web::{get, post, web, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use crate::models::backup::RestoreQuery;
+use crate::repo::backup::Backup;
+use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
+use crate::services::error::ServiceError;
+use crate::services::roles::{Admins, Authorized};
+
+/// Upper bound on an uploaded archive, which is held in memory while it is checked.
+const MAX_BACKUP_BYTES: usize = 512 * 1024 * 1024;
+
+#[get("/admin/backup")]
+pub(crate) async fn get_backup(_auth: Authorized<Admins>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+    let backup = repo.backup().await?;
+    let archive = backup.to_archive()?;
+    let file_name = format!("pos-backup-{}.tar", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-tar")
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name)))
+        .body(archive))
+}
+
+/// Replaces the database with an archive from `/admin/backup`. With `dry_run=true` the archive
+/// is only checked.
+#[post("/admin/restore")]
+pub(crate) async fn restore_backup(_auth: Authorized<Admins>, repo: AuditedRepository, query: web::Query<RestoreQuery>, payload: web::Payload) -> Result<HttpResponse, ServiceError> {
+    let archive = match payload.to_bytes_limited(MAX_BACKUP_BYTES).await {
+        Ok(archive) => archive.map_err(|err| ServiceError::BadRequest(format!("Reading the archive failed: {}", err)))?,
+        Err(_) => return Err(ServiceError::Detailed {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            code: "payload_too_large",
+            message: format!("Backups larger than {} bytes cannot be uploaded, restore them with pos-admin", MAX_BACKUP_BYTES),
+            details: None,
+        }),
+    };
+
+    let backup = Backup::from_archive(&archive)?;
+    let report = repo.restore(&backup, query.dry_run).await?;
+
+    Ok(HttpResponse::Ok().json(report))
+}
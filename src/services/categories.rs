@@ -1,18 +1,22 @@
 use actix_web::{get, HttpResponse, post, web};
-use mongodb::bson::{doc};
 use crate::models::categories::{Category, CategoryId, NewCategory};
+use crate::models::validation::Validate;
 use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
 use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::roles::{Authorized, AllStaff, Managers};
 
 #[get("/categories")]
-pub(crate) async fn get_all_categories(repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn get_all_categories(_auth: Authorized<AllStaff>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
     let result = repo.query_all::<Category>().await?;
     Ok(HttpResponse::Ok().json(result))
 }
 
 #[post("/categories")]
-pub(crate) async fn add_category(repo: web::Data<Repository>, data: web::Json<NewCategory>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn add_category(_auth: Authorized<Managers>, repo: AuditedRepository, data: web::Json<NewCategory>) -> Result<HttpResponse, ServiceError> {
     let data = data.into_inner();
+    data.validate()?;
 
     let new_category = Category {
         _id: CategoryId::new(),
@@ -21,17 +25,16 @@ pub(crate) async fn add_category(repo: web::Data<Repository>, data: web::Json<Ne
         color: data.color,
     };
 
-    repo.insert_one::<Category>(new_category.clone()).await.map_err(|err| ServiceError::InternalError(err.to_string()))?;
+    repo.insert_one::<Category>(new_category.clone()).await?;
 
     Ok(HttpResponse::Ok().json(new_category))
 }
 
 #[get("/categories/{id}")]
-pub(crate) async fn get_category(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = CategoryId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_category(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
 
-    let collection = repo.get_collection::<Category>();
+    let result = repo.query_one::<Category>(&id).await?;
 
-    let result = collection.find_one(doc! { "_id": id }, None).await.map_err(|err| ServiceError::InternalError(err.to_string()))?;
     Ok(HttpResponse::Ok().json(result))
 }
\ No newline at end of file
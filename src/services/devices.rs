@@ -0,0 +1,163 @@
+use actix_web::{get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::dev::Payload;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use futures::future::LocalBoxFuture;
+use mongodb::bson;
+use mongodb::bson::doc;
+use crate::models::audit::Actor;
+use crate::models::devices::{Device, DeviceAPI, DeviceCredential, DeviceId, DevicePairing, DevicePairingCode, DeviceSettings, NewDevice};
+use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
+use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::rate_limit::RateLimiter;
+use crate::services::roles::{Authorized, Managers};
+use crate::services::shutdown::InFlight;
+use crate::services::waiter_session::{new_session_token, session_token_hash};
+
+pub const DEVICE_KEY_HEADER: &str = "X-Device-Key";
+const PAIRING_CODE_MILLIS: i64 = 10 * 60 * 1000;
+
+#[get("/devices")]
+pub(crate) async fn get_all_devices(_auth: Authorized<Managers>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+    let result = repo.query_all::<DeviceAPI>().await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/devices")]
+pub(crate) async fn add_device(_auth: Authorized<Managers>, repo: AuditedRepository, data: web::Json<NewDevice>) -> Result<HttpResponse, ServiceError> {
+    let data = data.into_inner();
+    let device = Device {
+        _id: DeviceId::new(),
+        name: data.name,
+        default_printer: data.default_printer,
+        level: data.level,
+        created_at: bson::DateTime::now(),
+        pairing_code_hash: None,
+        pairing_expires_at: None,
+        credential_hash: None,
+        paired_at: None,
+        revoked_at: None,
+    };
+
+    repo.insert_one::<Device>(device.clone()).await?;
+
+    Ok(HttpResponse::Ok().json(start_pairing(&repo, &device._id).await?))
+}
+
+/// Issues a fresh pairing code, e.g. when a tablet was wiped or replaced.
+#[post("/devices/{id}/pairing")]
+pub(crate) async fn restart_device_pairing(_auth: Authorized<Managers>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    Ok(HttpResponse::Ok().json(start_pairing(&repo, &id).await?))
+}
+
+/// Called by the device itself, before it has any credentials, so it is not behind the bearer check.
+#[post("/devices/pair")]
+pub(crate) async fn pair_device(_mutation: InFlight, repo: web::Data<Repository>, limiter: web::Data<RateLimiter>, data: web::Json<DevicePairing>) -> Result<HttpResponse, ServiceError> {
+    let data = data.into_inner();
+
+    if !limiter.check(&format!("pair:{}", data.device_id)) {
+        return Err(ServiceError::TooManyRequests("Too many pairing attempts for this device".to_string()));
+    }
+
+    let (credential, credential_hash) = new_session_token();
+    let device = repo
+        .as_actor(Actor {
+            subject: format!("device:{}", data.device_id),
+            username: None,
+            waiter_id: None,
+            device_id: Some(data.device_id),
+        })
+        .device_pair(&data.device_id, &session_token_hash(&data.pairing_code), &credential_hash)
+        .await?
+        .ok_or_else(|| ServiceError::Unauthorized("Invalid or expired pairing code".to_string()))?;
+
+    tracing::info!("Device {} ({}) paired", device._id, device.name);
+
+    Ok(HttpResponse::Ok().json(DeviceCredential {
+        device: device.into(),
+        credential,
+    }))
+}
+
+#[get("/devices/me")]
+pub(crate) async fn get_current_device(device: CurrentDevice) -> Result<HttpResponse, ServiceError> {
+    let device = device.0.ok_or_else(|| ServiceError::Unauthorized(format!("Missing {} header", DEVICE_KEY_HEADER)))?;
+
+    Ok(HttpResponse::Ok().json(DeviceAPI::from(device)))
+}
+
+#[post("/devices/{id}/settings")]
+pub(crate) async fn update_device_settings(_auth: Authorized<Managers>, repo: AuditedRepository, id: IdPath, data: web::Json<DeviceSettings>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    let result = repo.device_update_settings(&id, data.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(DeviceAPI::from(result)))
+}
+
+#[post("/devices/{id}/revoke")]
+pub(crate) async fn revoke_device(auth: Authorized<Managers>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    tracing::info!("Revoking device {} on behalf of {}", id, auth.principal.subject);
+
+    let result = repo.device_revoke(&id).await?;
+
+    Ok(HttpResponse::Ok().json(DeviceAPI::from(result)))
+}
+
+async fn start_pairing(repo: &Repository, id: &DeviceId) -> Result<DevicePairingCode, ServiceError> {
+    let pairing_code = format!("{:08}", OsRng.next_u32() % 100_000_000);
+    let expires_at = bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() + PAIRING_CODE_MILLIS);
+
+    let device = repo.device_set_pairing_code(id, &session_token_hash(&pairing_code), expires_at).await?;
+
+    Ok(DevicePairingCode {
+        device: device.into(),
+        pairing_code,
+        expires_at,
+    })
+}
+
+/// Device a request came from: the one authenticated by the bearer validator, or the one whose
+/// credential is in the `X-Device-Key` header. `None` when the request names no device.
+#[derive(Clone, Debug)]
+pub struct CurrentDevice(pub Option<Device>);
+
+impl CurrentDevice {
+    pub fn id(&self) -> Option<DeviceId> {
+        self.0.as_ref().map(|device| device._id)
+    }
+}
+
+impl FromRequest for CurrentDevice {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            if let Some(device) = req.extensions().get::<Device>().cloned() {
+                return Ok(CurrentDevice(Some(device)));
+            }
+
+            let credential = match req.headers().get(DEVICE_KEY_HEADER).and_then(|value| value.to_str().ok()) {
+                Some(credential) => credential,
+                None => return Ok(CurrentDevice(None)),
+            };
+
+            let repo = req
+                .app_data::<web::Data<Repository>>()
+                .ok_or_else(|| ServiceError::InternalError("Repository is not configured".to_string()))?;
+
+            match repo.query_device_by_credential(&session_token_hash(credential)).await? {
+                Some(device) => Ok(CurrentDevice(Some(device))),
+                None => Err(ServiceError::Unauthorized("Unknown or revoked device".to_string())),
+            }
+        })
+    }
+}
@@ -1,16 +1,78 @@
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
     error,
-    http::{header::ContentType, StatusCode},
-    HttpResponse,
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse,
 };
+use serde::Serialize;
+use serde_json::json;
+use crate::models::validation::ValidationErrors;
 use crate::repo::error::RepoError;
+use crate::services::request_id::{RequestId, REQUEST_ID_HEADER};
 
 #[derive(Debug)]
 pub enum ServiceError {
     InternalError(String),
     BadRequest(String),
     NotFound(String),
+    Forbidden(String),
+    Unauthorized(String),
+    TooManyRequests(String),
+    /// Error with its own machine readable code and, optionally, structured details.
+    Detailed {
+        status: StatusCode,
+        code: &'static str,
+        message: String,
+        details: Option<serde_json::Value>,
+    },
+}
+
+/// What every failed request gets back, whatever went wrong.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    pub request_id: Option<String>,
+}
+
+impl ServiceError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServiceError::InternalError(_) => "internal_error",
+            ServiceError::BadRequest(_) => "bad_request",
+            ServiceError::NotFound(_) => "not_found",
+            ServiceError::Forbidden(_) => "forbidden",
+            ServiceError::Unauthorized(_) => "unauthorized",
+            ServiceError::TooManyRequests(_) => "too_many_requests",
+            ServiceError::Detailed { code, .. } => code,
+        }
+    }
+
+    /// Internal details are logged, not sent to the client.
+    pub fn body(&self, request_id: Option<&RequestId>) -> ErrorBody {
+        let (message, details) = match self {
+            ServiceError::InternalError(_) => ("Internal server error".to_string(), None),
+            ServiceError::Detailed { status, .. } if status.is_server_error() => ("Internal server error".to_string(), None),
+            ServiceError::BadRequest(message)
+            | ServiceError::NotFound(message)
+            | ServiceError::Forbidden(message)
+            | ServiceError::Unauthorized(message)
+            | ServiceError::TooManyRequests(message) => (message.clone(), None),
+            ServiceError::Detailed { message, details, .. } => (message.clone(), details.clone()),
+        };
+
+        ErrorBody {
+            code: self.code().to_string(),
+            message,
+            details,
+            request_id: request_id.map(|id| id.to_string()),
+        }
+    }
 }
 
 impl Display for ServiceError {
@@ -19,6 +81,10 @@ impl Display for ServiceError {
             ServiceError::InternalError(err) => write!(f, "Internal Server Error: {err}"),
             ServiceError::BadRequest(err) => write!(f, "Bad Request: {err}"),
             ServiceError::NotFound(err) => write!(f, "Not Found: {err}"),
+            ServiceError::Forbidden(err) => write!(f, "Forbidden: {err}"),
+            ServiceError::Unauthorized(err) => write!(f, "Unauthorized: {err}"),
+            ServiceError::TooManyRequests(err) => write!(f, "Too Many Requests: {err}"),
+            ServiceError::Detailed { code, message, .. } => write!(f, "{code}: {message}"),
         }
     }
 }
@@ -29,27 +95,142 @@ impl error::ResponseError for ServiceError {
             ServiceError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
             ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ServiceError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ServiceError::Detailed { status, .. } => status,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        if self.status_code().is_server_error() {
+            tracing::error!("{}", self);
+        }
+
+        HttpResponse::build(self.status_code()).json(self.body(None))
     }
 }
 
 impl From<RepoError> for ServiceError {
     fn from(error: RepoError) -> Self {
-        match error {
-            RepoError::MongoDBError(err) => ServiceError::InternalError(err.to_string()),
-            RepoError::DotenvError(err) => ServiceError::InternalError(err),
-            RepoError::DeserializeError(err) => ServiceError::InternalError(err.to_string()),
-            RepoError::CollectionNotFound => ServiceError::InternalError("Collection not found".to_string()),
-            RepoError::IdInvalidUuid => ServiceError::InternalError("Invalid UUID id".to_string()),
-            RepoError::IdNotFound(id) => ServiceError::NotFound(format!("Id not found: {}", id)),
-            RepoError::BsonSerializationError(err) => ServiceError::InternalError(err.to_string()),
-            RepoError::IdsNotFound(ids) => ServiceError::NotFound(format!("Ids not found: {:?}", ids)),
+        let (status, details) = match &error {
+            RepoError::Validation(errors) => return errors.clone().into(),
+            RepoError::IdNotFound(id) => (StatusCode::NOT_FOUND, Some(json!({ "id": id.to_string() }))),
+            RepoError::IdsNotFound(ids) => (
+                StatusCode::NOT_FOUND,
+                Some(json!({ "missing_ids": ids.iter().map(|id| id.to_string()).collect::<Vec<String>>() })),
+            ),
+            RepoError::UnknownReferences(references) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Some(json!({ "references": references })),
+            ),
+            RepoError::InvalidBackup(_) => (StatusCode::BAD_REQUEST, None),
+            RepoError::AlreadyExists(_) | RepoError::InvalidState(_) => (StatusCode::CONFLICT, None),
+            RepoError::MongoDBError(_)
+            | RepoError::BsonSerializationError(_)
+            | RepoError::BsonDeserializationError(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+        };
+
+        ServiceError::Detailed {
+            status,
+            code: error.code(),
+            message: error.to_string(),
+            details,
+        }
+    }
+}
+
+impl From<ValidationErrors> for ServiceError {
+    fn from(errors: ValidationErrors) -> Self {
+        ServiceError::Detailed {
+            status: StatusCode::BAD_REQUEST,
+            code: "validation_failed",
+            message: format!("Invalid request: {}", errors),
+            details: Some(json!({ "fields": errors.fields })),
         }
     }
 }
+
+/// Middleware for `App::wrap_fn` giving every error response, including ones actix or the auth
+/// middleware produced, the JSON error body, and tagging all responses with the request id.
+pub fn json_error_bodies<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<BoxBody>, error::Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = error::Error>,
+        B: MessageBody + 'static,
+{
+    let request_id = RequestId::assign(&req);
+    let response = srv.call(req);
+
+    async move { Ok(with_error_body(response.await?, request_id)) }
+}
+
+fn with_error_body<B: MessageBody + 'static>(res: ServiceResponse<B>, request_id: RequestId) -> ServiceResponse<BoxBody> {
+    let mut res = res.map_into_boxed_body();
+
+    let status = res.status();
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+
+    if (status.is_client_error() || status.is_server_error()) && (!is_json || res.response().error().is_some()) {
+        let body = match res.response().error() {
+            Some(err) => match err.as_error::<ServiceError>() {
+                Some(service_error) => service_error.body(Some(&request_id)),
+                None => generic_body(status, Some(err.to_string()), &request_id),
+            },
+            None => generic_body(status, None, &request_id),
+        };
+
+        res = res.map_body(|head, _| {
+            head.headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+            BoxBody::new(serde_json::to_vec(&body).unwrap_or_default())
+        });
+    }
+
+    if let Ok(value) = header::HeaderValue::from_str(&request_id.to_string()) {
+        res.headers_mut().insert(header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    res
+}
+
+fn generic_body(status: StatusCode, message: Option<String>, request_id: &RequestId) -> ErrorBody {
+    let reason = status.canonical_reason().unwrap_or("Error");
+
+    ErrorBody {
+        code: reason.to_lowercase().replace([' ', '-'], "_"),
+        message: message.filter(|message| !message.is_empty() && !status.is_server_error()).unwrap_or_else(|| reason.to_string()),
+        details: None,
+        request_id: Some(request_id.to_string()),
+    }
+}
+
+/// Extractor errors (bad JSON, bad path segments, bad query strings) in the same shape.
+pub fn json_payload_error(err: error::JsonPayloadError, _: &HttpRequest) -> error::Error {
+    ServiceError::Detailed {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_body",
+        message: err.to_string(),
+        details: None,
+    }.into()
+}
+
+pub fn path_error(err: error::PathError, _: &HttpRequest) -> error::Error {
+    ServiceError::Detailed {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_path",
+        message: err.to_string(),
+        details: None,
+    }.into()
+}
+
+pub fn query_payload_error(err: error::QueryPayloadError, _: &HttpRequest) -> error::Error {
+    ServiceError::Detailed {
+        status: StatusCode::BAD_REQUEST,
+        code: "invalid_query",
+        message: err.to_string(),
+        details: None,
+    }.into()
+}
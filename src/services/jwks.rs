@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use futures::lock::Mutex;
+use jsonwebtoken::jwk::{Jwk, JwkSet, PublicKeyUse};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+struct CachedKeys {
+    jwks: Arc<JwkSet>,
+    fetched_at: Instant,
+}
+
+/// Keycloak signing keys, fetched from `API_AUTH_CERTS` and kept for `ttl`.
+///
+/// Expired keys keep being served for up to `max_stale` while a refresh runs in the background,
+/// so requests only wait for Keycloak when there are no usable keys at all or a token is signed
+/// with a `kid` we have not seen. Unknown `kid`s trigger a fetch at most once per
+/// `refetch_cooldown`.
+#[derive(Debug)]
+pub struct JwksCache {
+    ttl: Duration,
+    max_stale: Duration,
+    refetch_cooldown: Duration,
+    fetcher: Fetcher,
+    last_refetch: RwLock<Option<Instant>>,
+    refreshing_in_background: Arc<AtomicBool>,
+}
+
+/// The part of the cache a background refresh needs, shared with the task it runs in.
+#[derive(Clone, Debug)]
+struct Fetcher {
+    url: String,
+    client: reqwest::Client,
+    keys: Arc<RwLock<Option<CachedKeys>>>,
+    refreshing: Arc<Mutex<()>>,
+}
+
+impl JwksCache {
+    pub fn new(url: String, ttl: Duration, max_stale: Duration) -> Self {
+        Self {
+            ttl,
+            max_stale,
+            refetch_cooldown: Duration::from_secs(10).min(ttl),
+            fetcher: Fetcher {
+                url,
+                client: reqwest::Client::builder()
+                    .timeout(FETCH_TIMEOUT)
+                    .build()
+                    .unwrap_or_default(),
+                keys: Arc::new(RwLock::new(None)),
+                refreshing: Arc::new(Mutex::new(())),
+            },
+            last_refetch: RwLock::new(None),
+            refreshing_in_background: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Keys that may have signed a token with the given `kid`; all keys when it has none.
+    pub async fn keys_for(&self, kid: Option<&str>) -> Result<Vec<Jwk>, Box<dyn Error>> {
+        let cached = match self.cached() {
+            Some(cached) => cached,
+            None => return Ok(matching_keys(self.refresh().await?.as_ref(), kid)),
+        };
+
+        let age = cached.fetched_at.elapsed();
+        if age >= self.ttl + self.max_stale {
+            return Ok(matching_keys(self.refresh().await?.as_ref(), kid));
+        }
+        if age >= self.ttl {
+            self.refresh_in_background();
+        }
+
+        let keys = matching_keys(&cached.jwks, kid);
+        if !keys.is_empty() || kid.is_none() || !self.may_refetch() {
+            return Ok(keys);
+        }
+
+        tracing::info!("Unknown key id {:?}, refetching JWKS", kid);
+        *self.last_refetch.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now());
+        match self.refresh().await {
+            Ok(jwks) => Ok(matching_keys(&jwks, kid)),
+            Err(err) => {
+                tracing::warn!("Refetching JWKS for unknown key id failed: {}", err);
+                Ok(keys)
+            }
+        }
+    }
+
+    /// Fetches the key set, unless another caller finished doing so while we waited.
+    pub async fn refresh(&self) -> Result<Arc<JwkSet>, Box<dyn Error>> {
+        self.fetcher.refresh().await
+    }
+
+    /// Starts a refresh without waiting for it, unless one is running. A failed one holds off the
+    /// next for `refetch_cooldown`.
+    fn refresh_in_background(&self) {
+        if self.refreshing_in_background.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let fetcher = self.fetcher.clone();
+        let running = self.refreshing_in_background.clone();
+        let cooldown = self.refetch_cooldown;
+        actix_web::rt::spawn(async move {
+            if let Err(err) = fetcher.refresh().await {
+                tracing::warn!("Refreshing expired JWKS failed, serving the old keys meanwhile: {}", err);
+                actix_web::rt::time::sleep(cooldown).await;
+            }
+            running.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Keeps the keys fresh in the background so requests rarely wait for Keycloak.
+    pub fn spawn_refresh(cache: Arc<JwksCache>) {
+        actix_web::rt::spawn(async move {
+            loop {
+                if let Err(err) = cache.refresh().await {
+                    tracing::warn!("Background JWKS refresh failed: {}", err);
+                }
+                actix_web::rt::time::sleep(cache.ttl / 2).await;
+            }
+        });
+    }
+
+    fn cached(&self) -> Option<CachedKeys> {
+        self.fetcher.cached()
+    }
+
+    fn may_refetch(&self) -> bool {
+        self.last_refetch
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_none_or(|last| last.elapsed() >= self.refetch_cooldown)
+    }
+}
+
+impl Fetcher {
+    async fn refresh(&self) -> Result<Arc<JwkSet>, Box<dyn Error>> {
+        let requested_at = Instant::now();
+        let _guard = self.refreshing.lock().await;
+
+        if let Some(cached) = self.cached().filter(|cached| cached.fetched_at >= requested_at) {
+            return Ok(cached.jwks);
+        }
+
+        let jwks: JwkSet = self.client
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let jwks = Arc::new(jwks);
+
+        *self.keys.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(CachedKeys {
+            jwks: jwks.clone(),
+            fetched_at: Instant::now(),
+        });
+
+        Ok(jwks)
+    }
+
+    fn cached(&self) -> Option<CachedKeys> {
+        self.keys.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+}
+
+/// Signing keys with the given `kid`, or all of them. Keycloak also publishes encryption keys.
+fn matching_keys(jwks: &JwkSet, kid: Option<&str>) -> Vec<Jwk> {
+    jwks.keys
+        .iter()
+        .filter(|key| !matches!(key.common.public_key_use, Some(PublicKeyUse::Encryption)))
+        .filter(|key| kid.is_none() || key.common.key_id.as_deref() == kid)
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,193 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use crate::config::LocalAuthConfig;
+use crate::models::roles::Role;
+use crate::models::waiters::{Waiter, WaiterSession};
+use crate::services::auth::TokenError;
+use crate::services::roles::Principal;
+use crate::services::waiter_session::session_token_hash;
+
+pub const LOCAL_ISSUER: &str = "pos-local";
+
+/// Which identity provider guards the API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    Keycloak,
+    Local,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "keycloak" => Ok(AuthMode::Keycloak),
+            "local" => Ok(AuthMode::Local),
+            other => Err(format!("Unknown auth mode {}, expected keycloak or local", other)),
+        }
+    }
+}
+
+/// Terminal allowed in with a static API key instead of a token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceKey {
+    pub terminal_id: String,
+    pub roles: HashSet<Role>,
+}
+
+/// Signing keys and device keys for running without Keycloak. Waiters get tokens signed by the
+/// server itself on PIN login; terminals authenticate with their device key to log them in.
+#[derive(Clone)]
+pub struct LocalAuthSettings {
+    pub algorithm: Algorithm,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    pub token_ttl: Duration,
+    /// Device keys by the SHA-256 of the key, so the plaintext is not kept around.
+    device_keys: HashMap<String, DeviceKey>,
+}
+
+impl LocalAuthSettings {
+    pub fn hs256(secret: &[u8], token_ttl: Duration) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            token_ttl,
+            device_keys: HashMap::new(),
+        }
+    }
+
+    pub fn eddsa(private_pem: &[u8], public_pem: &[u8], token_ttl: Duration) -> Result<Self, String> {
+        Ok(Self {
+            algorithm: Algorithm::EdDSA,
+            encoding_key: EncodingKey::from_ed_pem(private_pem).map_err(|err| format!("Invalid Ed25519 private key: {}", err))?,
+            decoding_key: DecodingKey::from_ed_pem(public_pem).map_err(|err| format!("Invalid Ed25519 public key: {}", err))?,
+            token_ttl,
+            device_keys: HashMap::new(),
+        })
+    }
+
+    pub fn with_device_key(mut self, key: &str, device: DeviceKey) -> Self {
+        self.device_keys.insert(session_token_hash(key), device);
+        self
+    }
+
+    /// Reads the PEM files for EdDSA. Everything else was checked by `Config::validate`.
+    pub fn from_config(config: &LocalAuthConfig) -> Result<Self, String> {
+        let token_ttl = Duration::from_secs(config.token_ttl_secs);
+
+        let mut settings = match config.algorithm.as_str() {
+            "HS256" => {
+                let secret = config.secret.as_deref().ok_or("auth.local.secret must be set")?;
+                Self::hs256(secret.as_bytes(), token_ttl)
+            }
+            "EdDSA" => {
+                let read = |path: Option<&PathBuf>| {
+                    let path = path.ok_or("auth.local.private_key and auth.local.public_key must be set")?;
+                    std::fs::read(path).map_err(|err| format!("Reading {} failed: {}", path.display(), err))
+                };
+                Self::eddsa(&read(config.private_key.as_ref())?, &read(config.public_key.as_ref())?, token_ttl)?
+            }
+            other => return Err(format!("Unsupported local auth algorithm {}, expected HS256 or EdDSA", other)),
+        };
+
+        for (key, device) in parse_device_keys(&config.device_keys)? {
+            settings = settings.with_device_key(&key, device);
+        }
+
+        Ok(settings)
+    }
+
+    /// The token only works while `session` does, so logging out revokes it.
+    pub fn issue_token(&self, waiter: &Waiter, session: &WaiterSession) -> Result<String, jsonwebtoken::errors::Error> {
+        let issued_at = jsonwebtoken::get_current_timestamp();
+        let claims = LocalClaims {
+            iss: LOCAL_ISSUER.to_string(),
+            sub: waiter._id.to_string(),
+            name: waiter.name.clone(),
+            roles: waiter.roles.clone(),
+            terminal_id: session.terminal_id.clone(),
+            sid: session._id.clone(),
+            iat: issued_at,
+            exp: issued_at + self.token_ttl.as_secs(),
+        };
+
+        encode(&Header::new(self.algorithm), &claims, &self.encoding_key)
+    }
+
+    pub fn validate_token(&self, token: &str) -> Result<LocalClaims, TokenError> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.leeway = 0;
+        validation.validate_aud = false;
+        validation.set_issuer(&[LOCAL_ISSUER]);
+
+        Ok(decode::<LocalClaims>(token, &self.decoding_key, &validation)?.claims)
+    }
+
+    pub fn device(&self, key: &str) -> Option<&DeviceKey> {
+        self.device_keys.get(&session_token_hash(key))
+    }
+}
+
+/// Claims of a token issued by [`LocalAuthSettings::issue_token`], bound to one terminal.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LocalClaims {
+    pub iss: String,
+    pub sub: String,
+    pub name: String,
+    pub roles: Vec<Role>,
+    pub terminal_id: String,
+    /// Id of the waiter session the token was issued with.
+    pub sid: String,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+impl LocalClaims {
+    pub fn principal(&self) -> Principal {
+        Principal {
+            subject: self.sub.clone(),
+            username: Some(self.name.clone()),
+            roles: self.roles.iter().copied().collect(),
+        }
+    }
+}
+
+impl DeviceKey {
+    pub fn principal(&self) -> Principal {
+        Principal {
+            subject: format!("device:{}", self.terminal_id),
+            username: Some(self.terminal_id.clone()),
+            roles: self.roles.clone(),
+        }
+    }
+}
+
+/// Parses `terminal[:role+role]=key` entries separated by commas. Devices without roles are
+/// floor terminals and get `waiter`.
+pub fn parse_device_keys(value: &str) -> Result<Vec<(String, DeviceKey)>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (device, key) = entry
+                .split_once('=')
+                .filter(|(device, key)| !device.is_empty() && !key.is_empty())
+                .ok_or_else(|| format!("Invalid device key entry {}, expected terminal=key", entry))?;
+            let (terminal_id, roles) = device.split_once(':').unwrap_or((device, "waiter"));
+            let roles = roles
+                .split('+')
+                .map(|name| Role::from_claim(name).ok_or_else(|| format!("Unknown role {} for device {}", name, terminal_id)))
+                .collect::<Result<HashSet<Role>, String>>()?;
+
+            Ok((key.to_string(), DeviceKey { terminal_id: terminal_id.to_string(), roles }))
+        })
+        .collect()
+}
@@ -0,0 +1,62 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use actix_web::http::{header, StatusCode};
+use serde_json::json;
+use crate::models::menu::{MenuExportQuery, MenuFile, MenuFormat, MenuImportQuery, RowError};
+use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
+use crate::services::error::ServiceError;
+use crate::services::roles::{Authorized, Managers};
+
+#[get("/menu/export")]
+pub(crate) async fn export_menu(_auth: Authorized<Managers>, repo: web::Data<Repository>, query: web::Query<MenuExportQuery>) -> Result<HttpResponse, ServiceError> {
+    let menu = repo.export_menu().await?;
+
+    let response = match query.format {
+        MenuFormat::Json => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(menu.to_json().map_err(ServiceError::InternalError)?),
+        MenuFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header((header::CONTENT_DISPOSITION, "attachment; filename=\"menu.csv\""))
+            .body(menu.to_csv().map_err(ServiceError::InternalError)?),
+    };
+
+    Ok(response)
+}
+
+/// Takes the file as the raw body. With `dry_run=true` the report lists every row error and
+/// what would change; otherwise any error rejects the whole file with 422.
+#[post("/menu/import")]
+pub(crate) async fn import_menu(_auth: Authorized<Managers>, repo: AuditedRepository, req: HttpRequest, query: web::Query<MenuImportQuery>, body: web::Bytes) -> Result<HttpResponse, ServiceError> {
+    let format = query.format.unwrap_or_else(|| menu_format_of(&req));
+    let (menu, parse_errors) = parse_menu(format, &body)?;
+
+    let report = repo.import_menu(&menu, parse_errors, query.dry_run).await?;
+
+    if !report.dry_run && !report.committed {
+        return Err(ServiceError::Detailed {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            code: "import_failed",
+            message: format!("{} rows are invalid, nothing was imported", report.errors.len()),
+            details: Some(json!(report)),
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+fn menu_format_of(req: &HttpRequest) -> MenuFormat {
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    if content_type.starts_with("text/csv") {
+        MenuFormat::Csv
+    } else {
+        MenuFormat::Json
+    }
+}
+
+fn parse_menu(format: MenuFormat, data: &[u8]) -> Result<(MenuFile, Vec<RowError>), ServiceError> {
+    match format {
+        MenuFormat::Json => Ok((MenuFile::from_json(data).map_err(ServiceError::BadRequest)?, Vec::new())),
+        MenuFormat::Csv => Ok(MenuFile::from_csv(data)),
+    }
+}
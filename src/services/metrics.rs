@@ -0,0 +1,190 @@
+use std::future::Future;
+use std::time::Instant;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    get, web, HttpResponse,
+};
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use serde_json::json;
+use crate::repo::repository::Repository;
+use crate::services::error::ServiceError;
+use crate::services::jwks::JwksCache;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RequestLabels {
+    pub method: String,
+    pub route: String,
+    pub status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MongoLabels {
+    pub command: String,
+    pub outcome: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct AuthFailureLabels {
+    pub reason: &'static str,
+}
+
+type Latency<L> = Family<L, Histogram, fn() -> Histogram>;
+
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.001, 2.0, 14))
+}
+
+/// Prometheus metrics served on `/metrics`.
+pub struct Metrics {
+    registry: Registry,
+    pub requests: Latency<RequestLabels>,
+    pub mongo_commands: Latency<MongoLabels>,
+    pub open_orders: Gauge,
+    pub auth_failures: Family<AuthFailureLabels, Counter>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests: Latency<RequestLabels> = Family::new_with_constructor(latency_histogram);
+        let mongo_commands: Latency<MongoLabels> = Family::new_with_constructor(latency_histogram);
+        let open_orders = Gauge::default();
+        let auth_failures = Family::<AuthFailureLabels, Counter>::default();
+
+        let mut registry = Registry::with_prefix("pos");
+        registry.register("http_request_duration_seconds", "Time spent handling requests, by route", requests.clone());
+        registry.register("mongodb_command_duration_seconds", "Time spent on MongoDB commands", mongo_commands.clone());
+        registry.register("open_orders", "Orders that are not closed yet", open_orders.clone());
+        registry.register("auth_failures", "Rejected bearer tokens, by reason", auth_failures.clone());
+
+        Self {
+            registry,
+            requests,
+            mongo_commands,
+            open_orders,
+            auth_failures,
+        }
+    }
+
+    pub fn auth_failed(&self, reason: &'static str) {
+        self.auth_failures.get_or_create(&AuthFailureLabels { reason }).inc();
+    }
+
+    pub fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut body = String::new();
+        encode(&mut body, &self.registry)?;
+        Ok(body)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandEventHandler for Metrics {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        self.mongo_commands
+            .get_or_create(&MongoLabels { command: event.command_name, outcome: "success" })
+            .observe(event.duration.as_secs_f64());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        self.mongo_commands
+            .get_or_create(&MongoLabels { command: event.command_name, outcome: "failure" })
+            .observe(event.duration.as_secs_f64());
+    }
+}
+
+/// Middleware for `App::wrap_fn` timing every request. Routes are labelled by their pattern,
+/// so `/orders/{id}` is one series rather than one per order.
+pub fn record_request_latency<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+{
+    let metrics = req.app_data::<web::Data<Metrics>>().cloned();
+    let method = req.method().to_string();
+    let started = Instant::now();
+    let response = srv.call(req);
+
+    async move {
+        let response = response.await?;
+
+        if let Some(metrics) = metrics {
+            let labels = RequestLabels {
+                method,
+                route: response.request().match_pattern().unwrap_or_else(|| "unmatched".to_string()),
+                status: response.status().as_u16(),
+            };
+            metrics.requests.get_or_create(&labels).observe(started.elapsed().as_secs_f64());
+        }
+
+        Ok(response)
+    }
+}
+
+#[get("/healthz")]
+pub(crate) async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Ready once MongoDB answers a ping and, with Keycloak, signing keys can be had.
+#[get("/readyz")]
+pub(crate) async fn readyz(repo: web::Data<Repository>, jwks: Option<web::Data<JwksCache>>) -> HttpResponse {
+    let mongodb = match repo.ping().await {
+        Ok(()) => "ok".to_string(),
+        Err(err) => {
+            tracing::warn!("Readiness check: MongoDB ping failed: {}", err);
+            "unavailable".to_string()
+        }
+    };
+
+    let jwks = match jwks {
+        None => "not used".to_string(),
+        Some(jwks) => match jwks.keys_for(None).await {
+            Ok(keys) if !keys.is_empty() => "ok".to_string(),
+            Ok(_) => "no keys".to_string(),
+            Err(err) => {
+                tracing::warn!("Readiness check: JWKS unavailable: {}", err);
+                "unavailable".to_string()
+            }
+        },
+    };
+
+    let ready = mongodb == "ok" && (jwks == "ok" || jwks == "not used");
+    let body = json!({
+        "status": if ready { "ready" } else { "not ready" },
+        "checks": { "mongodb": mongodb, "jwks": jwks },
+    });
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[get("/metrics")]
+pub(crate) async fn get_metrics(repo: web::Data<Repository>, metrics: web::Data<Metrics>) -> Result<HttpResponse, ServiceError> {
+    match repo.count_open_orders().await {
+        Ok(count) => {
+            metrics.open_orders.set(count as i64);
+        }
+        Err(err) => tracing::warn!("Counting open orders for metrics failed: {}", err),
+    }
+
+    let body = metrics.encode().map_err(|err| ServiceError::InternalError(err.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/openmetrics-text; version=1.0.0; charset=utf-8")
+        .body(body))
+}
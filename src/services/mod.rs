@@ -5,3 +5,20 @@ pub mod error;
 pub mod tables;
 pub mod auth;
 pub mod categories;
+pub mod reports;
+pub mod analytics;
+pub mod shifts;
+pub mod rate_limit;
+pub mod waiter_session;
+pub mod roles;
+pub mod jwks;
+pub mod local_auth;
+pub mod devices;
+pub mod audit;
+pub mod request_id;
+pub mod path;
+pub mod shutdown;
+pub mod metrics;
+pub mod menu;
+pub mod backup;
+pub mod routes;
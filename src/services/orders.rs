@@ -1,38 +1,63 @@
 use actix_web::{delete, get, HttpResponse, post, web};
 use mongodb::{bson};
-use mongodb::bson::{doc, Uuid};
-use crate::models::orders::{NewOrder, Order, OrderId};
-use crate::models::products::{AddProductQuery};
-use crate::models::tables::TableId;
-use crate::models::waiters::WaiterId;
+use crate::config::OrdersConfig;
+use crate::models::orders::{CustomLineQuery, Discount, ManagerApproval, NewCustomLine, NewOrder, NewPayment, NewRefund, NewVoid, LineOperation, Order, OrderAction, OrderBatch, OrderId, OrderMutation};
+use crate::models::products::{AddProductQuery, SetQuantityQuery};
+use crate::models::validation::Validate;
+use crate::models::waiters::Waiter;
+use crate::repo::error::RepoError;
 use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
 use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::telemetry::record_order_id;
+use crate::services::roles::{Authorized, AllStaff, FloorStaff, Managers, Principal, Role};
+use crate::services::waiter_session::verify_pin;
+use crate::services::waiters::{MAX_FAILED_PIN_ATTEMPTS, PIN_LOCKOUT_MILLIS};
 
 #[get("/orders")]
-pub(crate) async fn get_all_orders(repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn get_all_orders(_auth: Authorized<AllStaff>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
     let result = repo.query_all_orders_api().await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 #[post("/orders")]
-pub(crate) async fn add_order(repo: web::Data<Repository>, data: web::Json<NewOrder>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn add_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, data: web::Json<NewOrder>) -> Result<HttpResponse, ServiceError> {
+    data.validate()?;
+
+    let device_id = repo.actor().device_id;
     let new_order = Order {
         _id: OrderId::new(),
         waiter_id: data.waiter_id,
         table_id: data.table_id,
         products: vec![],
         created_at: bson::DateTime::now(),
+        covers: data.covers,
+        payments: vec![],
+        discounts: vec![],
+        voids: vec![],
+        refunds: vec![],
+        closed_at: None,
+        device_id,
+        mutations: vec![OrderMutation {
+            action: OrderAction::Open,
+            device_id,
+            at: bson::DateTime::now(),
+        }],
+        custom_lines: vec![],
     };
 
-    repo.insert_one::<Order>(new_order.clone()).await?;
+    record_order_id(&new_order._id);
+    repo.insert_order(new_order.clone()).await?;
 
     Ok(HttpResponse::Ok().json(new_order))
 }
 
 #[get("/orders/{id}")]
-pub(crate) async fn get_order(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = OrderId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_order(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
 
     let result = repo.query_order_api(&id).await?;
 
@@ -40,18 +65,20 @@ pub(crate) async fn get_order(repo: web::Data<Repository>, id: web::Path<String>
 }
 
 #[post("/orders/{id}/add-product")]
-pub(crate) async fn add_product_to_order(repo: web::Data<Repository>, id: web::Path<String>, data: web::Json<AddProductQuery>) -> Result<HttpResponse, ServiceError> {
-    let id = OrderId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn add_product_to_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<AddProductQuery>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
     let add_product_query = data.into_inner();
 
-    let result = repo.order_add_product(&id, &add_product_query.product_id).await?;
+    let result = repo.order_add_product(&id, &add_product_query.product_id, add_product_query.price).await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 #[post("/orders/{id}/remove-product")]
-pub(crate) async fn remove_product_from_order(repo: web::Data<Repository>, id: web::Path<String>, data: web::Json<AddProductQuery>) -> Result<HttpResponse, ServiceError> {
-    let id = OrderId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn remove_product_from_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<AddProductQuery>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
     let add_product_query = data.into_inner();
 
     let result = repo.order_remove_product(&id, &add_product_query.product_id).await?;
@@ -59,9 +86,135 @@ pub(crate) async fn remove_product_from_order(repo: web::Data<Repository>, id: w
     Ok(HttpResponse::Ok().json(result))
 }
 
+/// A one-off item with its own name, price and tax rate, e.g. "misc food".
+#[post("/orders/{id}/add-custom-line")]
+pub(crate) async fn add_custom_line_to_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<NewCustomLine>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    data.validate()?;
+
+    let result = repo.order_add_custom_line(&id, data.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/remove-custom-line")]
+pub(crate) async fn remove_custom_line_from_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<CustomLineQuery>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+
+    let result = repo.order_remove_custom_line(&id, &data.line_id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Sets one line to an exact quantity, e.g. a weighed product straight off the scale.
+#[post("/orders/{id}/set-quantity")]
+pub(crate) async fn set_product_quantity(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<SetQuantityQuery>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    let query = data.into_inner();
+    let batch = OrderBatch {
+        operations: vec![LineOperation::SetQuantity {
+            product_id: query.product_id,
+            quantity: query.quantity,
+            scale: query.scale,
+        }],
+    };
+    batch.validate()?;
+
+    let result = repo.order_apply_batch(&id, &batch.operations).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Several add, remove and set-quantity operations in one request, applied all or none.
+#[post("/orders/{id}/batch")]
+pub(crate) async fn apply_order_batch(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<OrderBatch>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    data.validate()?;
+
+    let result = repo.order_apply_batch(&id, &data.operations).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/send")]
+pub(crate) async fn send_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+
+    let result = repo.order_send(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/voids")]
+pub(crate) async fn void_product(auth: Authorized<FloorStaff>, repo: AuditedRepository, orders: web::Data<OrdersConfig>, id: IdPath, data: web::Json<NewVoid>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    let mut void = data.into_inner();
+
+    let order = repo.query_order_api(&id).await?;
+    let price = order.products.iter().find(|line| line._id == void.product_id).map(|line| line.price)
+        .or_else(|| order.custom_lines.iter().find(|line| line._id == void.product_id).map(|line| line.price))
+        .ok_or(RepoError::IdNotFound(void.product_id))?;
+    let approved_by = manager_approval(&repo, &auth.principal, void.approval.take(), price * void.quantity, orders.void_approval_threshold).await?;
+
+    let result = repo.order_void(&id, void, approved_by).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/refunds")]
+pub(crate) async fn refund_order(auth: Authorized<FloorStaff>, repo: AuditedRepository, orders: web::Data<OrdersConfig>, id: IdPath, data: web::Json<NewRefund>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    let mut refund = data.into_inner();
+
+    let approved_by = manager_approval(&repo, &auth.principal, refund.approval.take(), refund.amount, orders.void_approval_threshold).await?;
+
+    let result = repo.order_refund(&id, refund, approved_by).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/payments")]
+pub(crate) async fn add_payment_to_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<NewPayment>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    data.validate()?;
+
+    let result = repo.order_add_payment(&id, data.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/discounts")]
+pub(crate) async fn add_discount_to_order(_auth: Authorized<Managers>, repo: AuditedRepository, id: IdPath, data: web::Json<Discount>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+    data.validate()?;
+
+    let result = repo.order_add_discount(&id, data.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/orders/{id}/close")]
+pub(crate) async fn close_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
+
+    let result = repo.order_close(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
 #[get("/orders/waiter/{id}")]
-pub(crate) async fn get_orders_by_waiter(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = WaiterId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_orders_by_waiter(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
 
     let result = repo.query_orders_by_waiter(&id).await?;
 
@@ -69,25 +222,69 @@ pub(crate) async fn get_orders_by_waiter(repo: web::Data<Repository>, id: web::P
 }
 
 #[get("/orders/table/{id}")]
-pub(crate) async fn get_orders_by_table(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = TableId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_orders_by_table(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
 
     let result = repo.query_orders_by_table(&id).await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
+/// Whether the order could be deleted: still open with nothing on, paid or taken back.
 #[get("/orders/{id}/check-empty")]
-pub(crate) async fn check_empty_order(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = TableId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn check_empty_order(_auth: Authorized<FloorStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
 
-    let result = repo.query_order_api(&id).await?;
+    let result = repo.order_is_empty(&id).await?;
 
-    if result.products.len() > 0 {
-        return Ok(HttpResponse::Ok().json(false));
-    }
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Removes an order opened by mistake, 409 unless it is empty.
+#[delete("/orders/{id}")]
+pub(crate) async fn delete_order(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    record_order_id(&id);
 
-    repo.delete_one::<Order>(&id).await?;
+    repo.delete_empty_order(&id).await?;
 
     Ok(HttpResponse::Ok().json(true))
-}
\ No newline at end of file
+}
+
+/// Roles that may approve voids and refunds above the threshold, whether calling or by PIN.
+const APPROVER_ROLES: &[Role] = &[Role::Manager, Role::Admin];
+
+/// Who approved a void or refund of `amount`: nobody below the threshold, the caller if they are
+/// a manager or admin, otherwise the manager whose PIN came with the request.
+async fn manager_approval(repo: &Repository, principal: &Principal, approval: Option<ManagerApproval>, amount: f64, threshold: f64) -> Result<Option<String>, ServiceError> {
+    if amount <= threshold {
+        return Ok(None);
+    }
+    if principal.has_any_role(APPROVER_ROLES) {
+        return Ok(Some(principal.subject.clone()));
+    }
+
+    let approval = approval.ok_or_else(|| ServiceError::Forbidden(format!("{:.2} is above the approval limit, a manager has to approve", amount)))?;
+    let manager = match repo.query_one::<Waiter>(&approval.waiter_id).await {
+        Ok(manager) => manager,
+        Err(RepoError::IdNotFound(_)) => return Err(ServiceError::Forbidden("Invalid manager or PIN".to_string())),
+        Err(err) => return Err(err.into()),
+    };
+
+    if manager.locked_until.is_some_and(|until| until > bson::DateTime::now()) {
+        return Err(ServiceError::TooManyRequests("Manager is locked out after too many wrong PINs".to_string()));
+    }
+    // The PIN is checked first and a wrong role answers like a wrong PIN, so approvals cannot be
+    // used to find out who the managers are.
+    if !verify_pin(approval.pin, manager.pin_hash.clone()).await? {
+        repo.waiter_login_failed(&manager._id, MAX_FAILED_PIN_ATTEMPTS, PIN_LOCKOUT_MILLIS).await?;
+        return Err(ServiceError::Forbidden("Invalid manager or PIN".to_string()));
+    }
+    repo.waiter_login_succeeded(&manager._id, None).await?;
+    if !manager.roles.iter().any(|role| APPROVER_ROLES.contains(role)) {
+        return Err(ServiceError::Forbidden("Invalid manager or PIN".to_string()));
+    }
+
+    Ok(Some(manager._id.to_string()))
+}
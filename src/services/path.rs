@@ -0,0 +1,31 @@
+use std::future::{ready, Ready};
+use actix_web::{dev::Payload, http::StatusCode, FromRequest, HttpRequest};
+use mongodb::bson::Uuid;
+use crate::services::error::ServiceError;
+
+/// The `{id}` segment of the route. `web::Path<Uuid>` cannot be used for this: bson's `Uuid`
+/// only deserializes from BSON or plain strings, not from a path segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IdPath(Uuid);
+
+impl IdPath {
+    pub fn into_inner(self) -> Uuid {
+        self.0
+    }
+}
+
+impl FromRequest for IdPath {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = req.match_info().get("id").unwrap_or_default();
+
+        ready(Uuid::parse_str(id).map(IdPath).map_err(|_| ServiceError::Detailed {
+            status: StatusCode::BAD_REQUEST,
+            code: "invalid_path",
+            message: format!("{} is not a valid id", id),
+            details: None,
+        }))
+    }
+}
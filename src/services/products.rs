@@ -1,50 +1,65 @@
 use actix_web::{get, HttpResponse, post, web};
-use mongodb::bson::{doc};
 use crate::models::categories::Category;
 use crate::models::products::{NewProduct, Product, ProductAPI, ProductId};
+use crate::models::validation::Validate;
+use crate::repo::error::RepoError;
 use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
 use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::roles::{Authorized, AllStaff, Managers};
 
 #[get("/products")]
-pub(crate) async fn get_all_products(repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn get_all_products(_auth: Authorized<AllStaff>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
     let products = repo.query_all::<Product>().await?;
     let categories = repo.query_all::<Category>().await?;
 
     let results = products.into_iter().map(|product| {
-        let category = categories.iter().find(|c| c._id == product.category_id).unwrap();
-        ProductAPI {
+        let category = categories
+            .iter()
+            .find(|c| c._id == product.category_id)
+            .ok_or(RepoError::IdNotFound(product.category_id))?;
+        Ok(ProductAPI {
             _id: product._id,
             name: product.name,
             price: product.price,
             category: category.clone(),
-        }
-    }).collect::<Vec<ProductAPI>>();
+            tax_rate: product.tax_rate,
+            sku: product.sku,
+            unit: product.unit,
+            open_price: product.open_price,
+        })
+    }).collect::<Result<Vec<ProductAPI>, RepoError>>()?;
 
     Ok(HttpResponse::Ok().json(results))
 }
 
 #[post("/products")]
-pub(crate) async fn add_product(repo: web::Data<Repository>, data: web::Json<NewProduct>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn add_product(_auth: Authorized<Managers>, repo: AuditedRepository, data: web::Json<NewProduct>) -> Result<HttpResponse, ServiceError> {
     let data = data.into_inner();
+    data.validate()?;
 
     let new_product = Product {
         _id: ProductId::new(),
         name: data.name,
         price: data.price,
         category_id: data.category_id,
+        tax_rate: data.tax_rate,
+        sku: data.sku,
+        unit: data.unit,
+        open_price: data.open_price,
     };
 
-    repo.insert_one::<Product>(new_product.clone()).await.map_err(|err| ServiceError::InternalError(err.to_string()))?;
+    repo.insert_product(new_product.clone()).await?;
 
     Ok(HttpResponse::Ok().json(new_product))
 }
 
 #[get("/products/{id}")]
-pub(crate) async fn get_product(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = ProductId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_product(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
 
-    let collection = repo.get_collection::<Product>();
+    let result = repo.query_one::<Product>(&id).await?;
 
-    let result = collection.find_one(doc! { "_id": id }, None).await.map_err(|err| ServiceError::InternalError(err.to_string()))?;
     Ok(HttpResponse::Ok().json(result))
 }
\ No newline at end of file
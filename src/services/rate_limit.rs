@@ -0,0 +1,41 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Sliding window limiter keyed by an arbitrary string, e.g. the caller and their address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_attempts: usize,
+    window: Duration,
+    attempts: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(max_attempts: usize, window: Duration) -> Self {
+        Self {
+            max_attempts,
+            window,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records an attempt for `key` and returns `false` if it exceeds the limit.
+    pub fn check(&self, key: &str) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        attempts.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < self.window));
+
+        let times = attempts.entry(key.to_string()).or_default();
+        while times.front().is_some_and(|first| now.duration_since(*first) >= self.window) {
+            times.pop_front();
+        }
+
+        if times.len() >= self.max_attempts {
+            return false;
+        }
+
+        times.push_back(now);
+        true
+    }
+}
@@ -0,0 +1,73 @@
+use actix_web::{get, post, web, HttpResponse};
+use chrono::{NaiveDate, NaiveTime};
+use crate::config::ReportsConfig;
+use crate::models::reports::{ReportFormat, ReportKind, ReportQuery, SalesReport, ZReport};
+use crate::repo::reports::business_day_of;
+use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
+use crate::services::error::ServiceError;
+use crate::services::roles::{Authorized, Managers};
+
+#[get("/reports/x")]
+pub(crate) async fn get_x_report(_auth: Authorized<Managers>, repo: web::Data<Repository>, reports: web::Data<ReportsConfig>, query: web::Query<ReportQuery>) -> Result<HttpResponse, ServiceError> {
+    let cutoff = reports.business_day_cutoff;
+    let day = requested_business_day(&query, cutoff)?;
+
+    let report = repo.sales_report(ReportKind::X, day, cutoff).await?;
+
+    Ok(report_response(&report, query.format))
+}
+
+#[post("/reports/z")]
+pub(crate) async fn close_business_day(_auth: Authorized<Managers>, repo: AuditedRepository, reports: web::Data<ReportsConfig>, query: web::Query<ReportQuery>) -> Result<HttpResponse, ServiceError> {
+    let cutoff = reports.business_day_cutoff;
+    let day = requested_business_day(&query, cutoff)?;
+
+    let z_report = repo.close_business_day(day, cutoff).await?;
+
+    Ok(match query.format {
+        ReportFormat::Json => HttpResponse::Ok().json(z_report),
+        ReportFormat::Text => report_response(&z_report.report, ReportFormat::Text),
+    })
+}
+
+#[get("/reports/z")]
+pub(crate) async fn get_all_z_reports(_auth: Authorized<Managers>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+    let result = repo.query_all::<ZReport>().await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/reports/z/{day}")]
+pub(crate) async fn get_z_report(_auth: Authorized<Managers>, repo: web::Data<Repository>, day: web::Path<String>, query: web::Query<ReportQuery>) -> Result<HttpResponse, ServiceError> {
+    let day = parse_day(&day.into_inner())?;
+
+    match repo.query_z_report(day).await? {
+        Some(z_report) => Ok(match query.format {
+            ReportFormat::Json => HttpResponse::Ok().json(z_report),
+            ReportFormat::Text => report_response(&z_report.report, ReportFormat::Text),
+        }),
+        None => Err(ServiceError::NotFound(format!("Business day {} is not closed", day))),
+    }
+}
+
+fn report_response(report: &SalesReport, format: ReportFormat) -> HttpResponse {
+    match format {
+        ReportFormat::Json => HttpResponse::Ok().json(report),
+        ReportFormat::Text => HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(report.to_string()),
+    }
+}
+
+fn requested_business_day(query: &ReportQuery, cutoff: NaiveTime) -> Result<NaiveDate, ServiceError> {
+    match &query.day {
+        Some(day) => parse_day(day),
+        None => Ok(business_day_of(&chrono::Local::now(), cutoff)),
+    }
+}
+
+pub fn parse_day(day: &str) -> Result<NaiveDate, ServiceError> {
+    NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|err| ServiceError::BadRequest(format!("Invalid day {}: {}", day, err)))
+}
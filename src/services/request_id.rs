@@ -0,0 +1,34 @@
+use std::fmt::{Display, Formatter};
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpMessage;
+use mongodb::bson::Uuid;
+
+/// Lower case, as `HeaderName::from_static` requires.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Id of the request being served, taken from the `X-Request-Id` header when the client sent a
+/// sensible one and generated otherwise.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn assign(req: &ServiceRequest) -> RequestId {
+        let request_id = req.headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN)
+            .filter(|value| value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .map(|value| RequestId(value.to_string()))
+            .unwrap_or_else(|| RequestId(Uuid::new().to_string()));
+
+        req.extensions_mut().insert(request_id.clone());
+        request_id
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
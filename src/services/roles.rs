@@ -0,0 +1,73 @@
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use actix_web::dev::Payload;
+use futures::future::{ready, Ready};
+use serde::Serialize;
+pub use crate::models::roles::Role;
+use crate::services::error::ServiceError;
+
+/// Authenticated caller, stored in the request extensions by the bearer validator.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Principal {
+    pub subject: String,
+    pub username: Option<String>,
+    pub roles: HashSet<Role>,
+}
+
+impl Principal {
+    /// Admins pass every check, everyone else needs one of `roles`.
+    pub fn has_any_role(&self, roles: &[Role]) -> bool {
+        self.roles.contains(&Role::Admin) || roles.iter().any(|role| self.roles.contains(role))
+    }
+}
+
+pub trait RolePolicy {
+    const ROLES: &'static [Role];
+}
+
+pub struct Admins;
+pub struct Managers;
+pub struct FloorStaff;
+pub struct AllStaff;
+
+impl RolePolicy for Admins {
+    const ROLES: &'static [Role] = &[Role::Admin];
+}
+
+impl RolePolicy for Managers {
+    const ROLES: &'static [Role] = &[Role::Manager];
+}
+
+impl RolePolicy for FloorStaff {
+    const ROLES: &'static [Role] = &[Role::Manager, Role::Waiter];
+}
+
+impl RolePolicy for AllStaff {
+    const ROLES: &'static [Role] = &[Role::Manager, Role::Waiter, Role::Kitchen];
+}
+
+/// Extractor guarding a handler: `_auth: Authorized<Managers>` rejects callers without the role
+/// with 403, and requests that never went through the bearer validator with 401.
+pub struct Authorized<P: RolePolicy> {
+    pub principal: Principal,
+    policy: PhantomData<P>,
+}
+
+impl<P: RolePolicy> FromRequest for Authorized<P> {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let principal = req.extensions().get::<Principal>().cloned();
+
+        ready(match principal {
+            Some(principal) if principal.has_any_role(P::ROLES) => Ok(Authorized {
+                principal,
+                policy: PhantomData,
+            }),
+            Some(principal) => Err(ServiceError::Forbidden(format!("{} lacks one of the roles {:?}", principal.subject, P::ROLES))),
+            None => Err(ServiceError::Unauthorized("No authenticated principal".to_string())),
+        })
+    }
+}
@@ -0,0 +1,90 @@
+use actix_web::web;
+use crate::services::analytics::{get_category_sales, get_hourly_sales, get_product_mix, get_ticket_stats, get_waiter_performance};
+use crate::services::audit::get_audit_log;
+use crate::services::backup::{get_backup, restore_backup};
+use crate::services::categories::{add_category, get_all_categories, get_category};
+use crate::services::devices::{add_device, get_all_devices, get_current_device, pair_device, restart_device_pairing, revoke_device, update_device_settings};
+use crate::services::menu::{export_menu, import_menu};
+use crate::services::metrics::{get_metrics, healthz, readyz};
+use crate::services::orders::{add_custom_line_to_order, add_discount_to_order, add_order, add_payment_to_order, add_product_to_order, apply_order_batch, check_empty_order, close_order, delete_order, get_all_orders, get_order, get_orders_by_table, get_orders_by_waiter, refund_order, remove_custom_line_from_order, remove_product_from_order, send_order, set_product_quantity, void_product};
+use crate::services::products::{add_product, get_all_products, get_product};
+use crate::services::reports::{close_business_day, get_all_z_reports, get_x_report, get_z_report};
+use crate::services::shifts::{add_cash_drop, clock_in, clock_out, end_break, get_open_shifts, get_shift, get_shifts_by_waiter, start_break};
+use crate::services::tables::{add_table, get_all_tables, get_table};
+use crate::services::waiters::{add_waiter, delete_waiter, get_all_waiters, get_current_waiter, login_waiter, logout_waiter};
+
+/// Routes reachable without a bearer token.
+pub fn public(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(healthz)
+        .service(readyz)
+        .service(get_metrics)
+        .service(pair_device);
+}
+
+/// Routes that go behind the bearer token middleware.
+pub fn protected(cfg: &mut web::ServiceConfig) {
+    cfg
+        .service(add_waiter)
+        .service(login_waiter)
+        .service(logout_waiter)
+        .service(get_current_waiter)
+        .service(get_all_waiters)
+        .service(delete_waiter)
+        .service(add_order)
+        .service(get_order)
+        .service(get_all_orders)
+        .service(get_orders_by_waiter)
+        .service(get_orders_by_table)
+        .service(add_product_to_order)
+        .service(remove_product_from_order)
+        .service(set_product_quantity)
+        .service(add_custom_line_to_order)
+        .service(remove_custom_line_from_order)
+        .service(apply_order_batch)
+        .service(check_empty_order)
+        .service(delete_order)
+        .service(add_payment_to_order)
+        .service(add_discount_to_order)
+        .service(send_order)
+        .service(void_product)
+        .service(refund_order)
+        .service(close_order)
+        .service(add_product)
+        .service(get_product)
+        .service(get_all_products)
+        .service(export_menu)
+        .service(import_menu)
+        .service(get_all_tables)
+        .service(get_table)
+        .service(add_table)
+        .service(get_all_categories)
+        .service(add_category)
+        .service(get_category)
+        .service(get_x_report)
+        .service(close_business_day)
+        .service(get_all_z_reports)
+        .service(get_z_report)
+        .service(get_product_mix)
+        .service(get_category_sales)
+        .service(get_hourly_sales)
+        .service(get_ticket_stats)
+        .service(get_waiter_performance)
+        .service(get_open_shifts)
+        .service(clock_in)
+        .service(get_shifts_by_waiter)
+        .service(get_shift)
+        .service(clock_out)
+        .service(start_break)
+        .service(end_break)
+        .service(add_cash_drop)
+        .service(get_all_devices)
+        .service(add_device)
+        .service(get_current_device)
+        .service(restart_device_pairing)
+        .service(update_device_settings)
+        .service(revoke_device)
+        .service(get_audit_log)
+        .service(get_backup)
+        .service(restore_backup);
+}
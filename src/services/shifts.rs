@@ -0,0 +1,95 @@
+use actix_web::{get, post, web, HttpResponse};
+use crate::models::shifts::{ClockIn, ClockOut, NewCashDrop, Shift};
+use crate::models::validation::Validate;
+use crate::models::waiters::WaiterId;
+use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
+use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::roles::{Authorized, FloorStaff, Managers, Principal, Role};
+use crate::services::waiter_session::AuthenticatedWaiter;
+
+#[get("/shifts")]
+pub(crate) async fn get_open_shifts(_auth: Authorized<Managers>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+    let result = repo.query_open_shifts().await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/shifts/{id}")]
+pub(crate) async fn get_shift(_auth: Authorized<FloorStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    let result = repo.query_one::<Shift>(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/shifts/waiter/{id}")]
+pub(crate) async fn get_shifts_by_waiter(_auth: Authorized<Managers>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    let result = repo.query_shifts_by_waiter(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/shifts/clock-in")]
+pub(crate) async fn clock_in(auth: Authorized<FloorStaff>, waiter: Option<AuthenticatedWaiter>, repo: AuditedRepository, data: web::Json<ClockIn>) -> Result<HttpResponse, ServiceError> {
+    data.validate()?;
+    require_self_or_manager(&auth.principal, waiter.as_ref(), &data.waiter_id)?;
+
+    let result = repo.clock_in(&data.waiter_id, data.opening_float).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/shifts/{id}/clock-out")]
+pub(crate) async fn clock_out(auth: Authorized<FloorStaff>, waiter: Option<AuthenticatedWaiter>, repo: AuditedRepository, id: IdPath, data: web::Json<ClockOut>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    data.validate()?;
+    let shift = repo.query_one::<Shift>(&id).await?;
+    require_self_or_manager(&auth.principal, waiter.as_ref(), &shift.waiter_id)?;
+
+    let result = repo.clock_out(&id, data.counted_cash).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/shifts/{id}/breaks/start")]
+pub(crate) async fn start_break(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    let result = repo.shift_start_break(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/shifts/{id}/breaks/end")]
+pub(crate) async fn end_break(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+
+    let result = repo.shift_end_break(&id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/shifts/{id}/drops")]
+pub(crate) async fn add_cash_drop(_auth: Authorized<FloorStaff>, repo: AuditedRepository, id: IdPath, data: web::Json<NewCashDrop>) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    data.validate()?;
+
+    let result = repo.shift_add_cash_drop(&id, data.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Waiters clock themselves in and out, as the waiter logged in on the terminal. Only managers
+/// may do it for someone else.
+fn require_self_or_manager(principal: &Principal, waiter: Option<&AuthenticatedWaiter>, waiter_id: &WaiterId) -> Result<(), ServiceError> {
+    if principal.has_any_role(&[Role::Manager]) || waiter.is_some_and(|waiter| waiter.waiter_id == *waiter_id) {
+        return Ok(());
+    }
+
+    Err(ServiceError::Forbidden(format!("Waiters can only clock themselves in or out, ask a manager to do it for {}", waiter_id)))
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use actix_web::dev::Payload;
+use actix_web::http::StatusCode;
+use actix_web::{web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use crate::services::error::ServiceError;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Counts requests that are changing data, so a shutdown can let them finish instead of cutting
+/// an order off half way. Once draining, new mutations are turned away with a 503.
+#[derive(Debug, Default)]
+pub struct ShutdownGate {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// Held for as long as a mutating request runs.
+#[derive(Debug)]
+pub struct MutationGuard(Arc<ShutdownGate>);
+
+/// Extractor for handlers that change data without an `AuditedRepository`, which already holds
+/// a guard. Nothing is counted when the app has no gate configured.
+#[derive(Debug)]
+pub struct InFlight {
+    _guard: Option<MutationGuard>,
+}
+
+impl InFlight {
+    pub fn enter(req: &HttpRequest) -> Result<Self, ServiceError> {
+        match req.app_data::<web::Data<ShutdownGate>>() {
+            Some(gate) => Ok(InFlight { _guard: Some(gate.clone().into_inner().enter()?) }),
+            None => Ok(InFlight { _guard: None }),
+        }
+    }
+}
+
+impl FromRequest for InFlight {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(InFlight::enter(req))
+    }
+}
+
+impl ShutdownGate {
+    pub fn enter(self: &Arc<Self>) -> Result<MutationGuard, ServiceError> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = MutationGuard(self.clone());
+
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(ServiceError::Detailed {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                code: "shutting_down",
+                message: "The server is shutting down".to_string(),
+                details: None,
+            });
+        }
+
+        Ok(guard)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Stops letting new mutations in and waits up to `timeout` for the running ones. Returns
+    /// whether they all finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let started = Instant::now();
+        while self.in_flight() > 0 {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        true
+    }
+}
+
+impl Drop for MutationGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM where there is such a thing.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                futures::future::select(Box::pin(actix_web::rt::signal::ctrl_c()), Box::pin(terminate.recv())).await;
+                return;
+            }
+            Err(err) => tracing::warn!("Not listening for SIGTERM: {}", err),
+        }
+    }
+
+    if let Err(err) = actix_web::rt::signal::ctrl_c().await {
+        tracing::error!("Listening for Ctrl-C failed: {}", err);
+        futures::future::pending::<()>().await;
+    }
+}
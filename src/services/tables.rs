@@ -1,17 +1,23 @@
 use actix_web::{get, HttpResponse, post, web};
 use crate::models::tables::{NewTable, Table, TableId};
+use crate::models::validation::Validate;
 use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
 use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::roles::{Authorized, AllStaff, Managers};
 
 #[get("/tables")]
-pub(crate) async fn get_all_tables(repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn get_all_tables(_auth: Authorized<AllStaff>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
     let result = repo.query_all::<Table>().await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 #[post("/tables")]
-pub(crate) async fn add_table(repo: web::Data<Repository>, data: web::Json<NewTable>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn add_table(_auth: Authorized<Managers>, repo: AuditedRepository, data: web::Json<NewTable>) -> Result<HttpResponse, ServiceError> {
+    data.validate()?;
+
     let new_table = Table {
         _id: TableId::new(),
         name: data.name.clone(),
@@ -26,8 +32,8 @@ pub(crate) async fn add_table(repo: web::Data<Repository>, data: web::Json<NewTa
 }
 
 #[get("/tables/{id}")]
-pub(crate) async fn get_table(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = TableId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn get_table(_auth: Authorized<AllStaff>, repo: web::Data<Repository>, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
 
     let result = repo.query_one::<Table>(&id).await?;
 
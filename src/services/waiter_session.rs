@@ -0,0 +1,102 @@
+use std::fmt::Write;
+use actix_web::{web, FromRequest, HttpRequest};
+use actix_web::dev::Payload;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::SaltString;
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+use crate::models::waiters::{WaiterId, WaiterSession};
+use crate::repo::repository::Repository;
+use crate::services::error::ServiceError;
+
+pub const TERMINAL_ID_HEADER: &str = "X-Terminal-Id";
+pub const WAITER_TOKEN_HEADER: &str = "X-Waiter-Token";
+
+pub async fn hash_pin(pin: String) -> Result<String, ServiceError> {
+    web::block(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(pin.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+        .await
+        .map_err(|err| ServiceError::InternalError(err.to_string()))?
+        .map_err(|err| ServiceError::InternalError(format!("Hashing PIN failed: {}", err)))
+}
+
+pub async fn verify_pin(pin: String, pin_hash: String) -> Result<bool, ServiceError> {
+    web::block(move || {
+        PasswordHash::new(&pin_hash)
+            .map(|hash| Argon2::default().verify_password(pin.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    })
+        .await
+        .map_err(|err| ServiceError::InternalError(err.to_string()))
+}
+
+/// Random session token handed to the terminal and the hash under which it is stored.
+pub fn new_session_token() -> (String, String) {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    let token = to_hex(&bytes);
+    let token_hash = session_token_hash(&token);
+
+    (token, token_hash)
+}
+
+pub fn session_token_hash(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn terminal_id(req: &HttpRequest) -> Result<String, ServiceError> {
+    req.headers()
+        .get(TERMINAL_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+        .ok_or_else(|| ServiceError::BadRequest(format!("Missing {} header", TERMINAL_ID_HEADER)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut acc, byte| {
+        let _ = write!(acc, "{:02x}", byte);
+        acc
+    })
+}
+
+/// Waiter session presented in the `X-Waiter-Token` header from the terminal it was issued to.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedWaiter {
+    pub waiter_id: WaiterId,
+    pub session: WaiterSession,
+}
+
+impl FromRequest for AuthenticatedWaiter {
+    type Error = ServiceError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+
+        Box::pin(async move {
+            let repo = req
+                .app_data::<web::Data<Repository>>()
+                .ok_or_else(|| ServiceError::InternalError("Repository is not configured".to_string()))?;
+
+            let token = req.headers()
+                .get(WAITER_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| ServiceError::Unauthorized(format!("Missing {} header", WAITER_TOKEN_HEADER)))?;
+            let terminal_id = terminal_id(&req)?;
+
+            match repo.query_waiter_session(&session_token_hash(token)).await? {
+                Some(session) if session.terminal_id == terminal_id => Ok(AuthenticatedWaiter {
+                    waiter_id: session.waiter_id,
+                    session,
+                }),
+                _ => Err(ServiceError::Unauthorized("Invalid or expired waiter session".to_string())),
+            }
+        })
+    }
+}
@@ -1,66 +1,151 @@
-use actix_web::{get, post, web, HttpResponse, delete};
-use mongodb::{Database, IndexModel};
-use mongodb::bson::{doc};
-use mongodb::options::IndexOptions;
-use crate::models::waiters::{NewWaiter, Waiter, WaiterInOrder, WaiterId};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, delete};
+use mongodb::bson;
+use crate::models::validation::Validate;
+use crate::models::waiters::{default_waiter_roles, NewWaiter, Waiter, WaiterInOrder, WaiterId, WaiterLogin, WaiterSession, WaiterSessionToken};
+use crate::repo::error::RepoError;
 use crate::repo::repository::Repository;
+use crate::services::audit::AuditedRepository;
 use crate::services::error::ServiceError;
+use crate::services::path::IdPath;
+use crate::services::rate_limit::RateLimiter;
+use crate::services::shutdown::InFlight;
+use crate::services::waiter_session::{hash_pin, new_session_token, terminal_id, verify_pin, AuthenticatedWaiter};
+use crate::services::local_auth::LocalAuthSettings;
+use crate::services::roles::{Authorized, Admins, FloorStaff, Managers, Role};
+
+pub(crate) const MAX_FAILED_PIN_ATTEMPTS: u32 = 5;
+pub(crate) const PIN_LOCKOUT_MILLIS: i64 = 5 * 60 * 1000;
+const WAITER_SESSION_MILLIS: i64 = 30 * 60 * 1000;
 
 #[get("/waiters")]
-pub(crate) async fn get_all_waiters(repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn get_all_waiters(_auth: Authorized<FloorStaff>, repo: web::Data<Repository>) -> Result<HttpResponse, ServiceError> {
     let result = repo.query_all::<WaiterInOrder>().await?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 #[post("/waiters")]
-pub(crate) async fn add_waiter(repo: web::Data<Repository>, data: web::Json<NewWaiter>) -> Result<HttpResponse, ServiceError> {
+pub(crate) async fn add_waiter(auth: Authorized<Managers>, repo: AuditedRepository, data: web::Json<NewWaiter>) -> Result<HttpResponse, ServiceError> {
+    let data = data.into_inner();
+    data.validate()?;
+
+    if data.roles.contains(&Role::Admin) && !auth.principal.roles.contains(&Role::Admin) {
+        return Err(ServiceError::Forbidden("Only admins may create admins".to_string()));
+    }
+
     let new_waiter = Waiter {
         _id: WaiterId::new(),
-        name: data.name.clone(),
-        code: data.code.clone(),
+        name: data.name,
+        pin_hash: hash_pin(data.code).await?,
+        failed_attempts: 0,
+        locked_until: None,
+        roles: if data.roles.is_empty() { default_waiter_roles() } else { data.roles },
+        code: None,
     };
 
     repo.insert_one::<Waiter>(new_waiter.clone()).await?;
 
-    Ok(HttpResponse::Ok().json(new_waiter))
+    Ok(HttpResponse::Ok().json(WaiterInOrder {
+        _id: new_waiter._id,
+        name: new_waiter.name,
+    }))
 }
 
-#[get("/waiters/{code}")]
-pub(crate) async fn get_waiter(repo: web::Data<Repository>, code: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let code = code.into_inner();
+#[post("/waiters/login")]
+pub(crate) async fn login_waiter(auth: Authorized<FloorStaff>, _mutation: InFlight, repo: web::Data<Repository>, limiter: web::Data<RateLimiter>, req: HttpRequest, data: web::Json<WaiterLogin>) -> Result<HttpResponse, ServiceError> {
+    let terminal_id = terminal_id(&req)?;
+    let data = data.into_inner();
 
-    let result = repo
-        .get_collection::<Waiter>()
-        .find_one(doc! { "code": &code }, None).await;
-    match result {
-        Ok(Some(waiter)) => Ok(HttpResponse::Ok().json(waiter)),
-        Ok(None) => Err(ServiceError::NotFound(format!("Waiter with code {} not found", code))),
-        Err(err) => Err(ServiceError::InternalError(err.to_string())),
+    // The terminal id is whatever the client sends, so attempts are counted per caller instead.
+    let peer = req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    if !limiter.check(&format!("login:{}@{}", auth.principal.subject, peer)) {
+        return Err(ServiceError::TooManyRequests("Too many login attempts, try again later".to_string()));
+    }
+
+    let waiter = match repo.query_one::<Waiter>(&data.waiter_id).await {
+        Ok(waiter) => waiter,
+        Err(RepoError::IdNotFound(_)) => return Err(ServiceError::Unauthorized("Invalid waiter or PIN".to_string())),
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Some(locked_until) = waiter.locked_until.filter(|until| *until > bson::DateTime::now()) {
+        return Err(ServiceError::TooManyRequests(format!(
+            "Waiter is locked until {}",
+            locked_until.try_to_rfc3339_string().unwrap_or_default()
+        )));
+    }
+
+    let (valid, upgraded_hash) = match &waiter.code {
+        Some(code) if waiter.pin_hash.is_empty() => {
+            let valid = code == &data.pin;
+            (valid, if valid { Some(hash_pin(data.pin.clone()).await?) } else { None })
+        }
+        _ => (verify_pin(data.pin, waiter.pin_hash.clone()).await?, None),
+    };
+
+    if !valid {
+        repo.waiter_login_failed(&waiter._id, MAX_FAILED_PIN_ATTEMPTS, PIN_LOCKOUT_MILLIS).await?;
+        return Err(ServiceError::Unauthorized("Invalid waiter or PIN".to_string()));
     }
+
+    repo.waiter_login_succeeded(&waiter._id, upgraded_hash).await?;
+
+    let (token, token_hash) = new_session_token();
+    let now = bson::DateTime::now();
+    let session = WaiterSession {
+        _id: token_hash,
+        waiter_id: waiter._id,
+        terminal_id,
+        created_at: now,
+        expires_at: bson::DateTime::from_millis(now.timestamp_millis() + WAITER_SESSION_MILLIS),
+    };
+
+    repo.insert_one::<WaiterSession>(session.clone()).await?;
+
+    let access_token = match req.app_data::<web::Data<LocalAuthSettings>>() {
+        Some(local) => Some(local
+            .issue_token(&waiter, &session)
+            .map_err(|err| ServiceError::InternalError(format!("Issuing token failed: {}", err)))?),
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(WaiterSessionToken {
+        token,
+        waiter: WaiterInOrder {
+            _id: waiter._id,
+            name: waiter.name,
+        },
+        terminal_id: session.terminal_id,
+        expires_at: session.expires_at,
+        access_token,
+    }))
 }
 
-pub(crate) async fn create_waiter_code_index(database: &Database) {
-    let options = IndexOptions::builder().unique(true).build();
-    let model = IndexModel::builder()
-        .keys(doc! { "code": 1 })
-        .options(options)
-        .build();
-    database
-        .collection::<Waiter>("waiters")
-        .create_index(model, None)
-        .await
-        .expect("creating an index should succeed");
+#[get("/waiters/me")]
+pub(crate) async fn get_current_waiter(repo: web::Data<Repository>, waiter: AuthenticatedWaiter) -> Result<HttpResponse, ServiceError> {
+    let result = repo.query_one::<WaiterInOrder>(&waiter.waiter_id).await?;
+
+    Ok(HttpResponse::Ok().json(result))
 }
 
+#[post("/waiters/logout")]
+pub(crate) async fn logout_waiter(repo: web::Data<Repository>, waiter: AuthenticatedWaiter) -> Result<HttpResponse, ServiceError> {
+    repo.delete_waiter_session(&waiter.session._id).await?;
+
+    Ok(HttpResponse::Ok().json(true))
+}
 
 #[delete("/waiters/{id}")]
-pub(crate) async fn delete_waiter(repo: web::Data<Repository>, id: web::Path<String>) -> Result<HttpResponse, ServiceError> {
-    let id = WaiterId::parse_str(&id.into_inner()).unwrap();
+pub(crate) async fn delete_waiter(auth: Authorized<Admins>, repo: AuditedRepository, id: IdPath) -> Result<HttpResponse, ServiceError> {
+    let id = id.into_inner();
+    tracing::info!("Deleting waiter {} on behalf of {}", id, auth.principal.subject);
 
     let result = repo.delete_one::<Waiter>(&id).await;
     match result {
-        Ok(_) => Ok(HttpResponse::Ok().json(true)),
+        Ok(_) => {
+            repo.delete_waiter_sessions(&id).await?;
+            Ok(HttpResponse::Ok().json(true))
+        }
         Err(err) => Err(ServiceError::InternalError(err.to_string())),
     }
-}
\ No newline at end of file
+}
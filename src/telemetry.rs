@@ -0,0 +1,123 @@
+use std::future::Future;
+use std::time::Instant;
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceRequest, ServiceResponse},
+    HttpMessage,
+};
+use tracing::field::Empty;
+use tracing::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use crate::config::{LogFormat, LoggingConfig};
+use crate::models::orders::OrderId;
+use crate::services::request_id::RequestId;
+
+/// Flushes exported spans when dropped, so keep it alive until the server stops.
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    otlp: bool,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Installs the global subscriber. Records from the `log` crate, used by our dependencies, are
+/// forwarded to it as well.
+pub fn init(config: &LoggingConfig) -> Result<Telemetry, String> {
+    let filter = EnvFilter::try_new(&config.filter)
+        .map_err(|err| format!("Invalid log filter {}: {}", config.filter, err))?;
+    let output = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let registry = tracing_subscriber::registry().with(filter).with(output);
+
+    #[cfg(feature = "otlp")]
+    {
+        let otlp = match &config.otlp_endpoint {
+            Some(endpoint) => Some(otlp_layer(endpoint)?),
+            None => None,
+        };
+        let telemetry = Telemetry { otlp: otlp.is_some() };
+        registry.with(otlp).try_init().map_err(|err| err.to_string())?;
+        Ok(telemetry)
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    {
+        registry.try_init().map_err(|err| err.to_string())?;
+        if let Some(endpoint) = &config.otlp_endpoint {
+            tracing::warn!("Built without the otlp feature, not exporting spans to {}", endpoint);
+        }
+        Ok(Telemetry {})
+    }
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(endpoint: &str) -> Result<tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>, String>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+        ])))
+        .install_batch(runtime::Tokio)
+        .map_err(|err| format!("Setting up OTLP export to {} failed: {}", endpoint, err))?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Middleware for `App::wrap_fn` opening the span every handler and repository call of a
+/// request runs in. `subject` is filled in by the auth validator and `order_id` by order
+/// handlers. Register it before `json_error_bodies` so the request id is already assigned.
+pub fn trace_requests<S, B>(req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+    where
+        S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+{
+    let request_id = req.extensions().get::<RequestId>().map(|id| id.to_string()).unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        route = %req.match_pattern().unwrap_or_else(|| req.path().to_string()),
+        subject = Empty,
+        order_id = Empty,
+    );
+    let started = Instant::now();
+    let response = span.in_scope(|| srv.call(req));
+
+    async move {
+        let response = response.await;
+        match &response {
+            Ok(res) => tracing::info!(status = res.status().as_u16(), elapsed_ms = started.elapsed().as_millis() as u64, "request finished"),
+            Err(err) => tracing::warn!(error = %err, elapsed_ms = started.elapsed().as_millis() as u64, "request failed"),
+        }
+        response
+    }
+        .instrument(span)
+}
+
+/// Tags the current request span with the order being worked on.
+pub fn record_order_id(id: &OrderId) {
+    tracing::Span::current().record("order_id", tracing::field::display(id));
+}
@@ -1,55 +1,1260 @@
-use actix_web::{
-    test::{call_and_read_body, call_and_read_body_json, init_service, TestRequest},
-    web::Bytes,
-};
-use mongodb::Client;
-use mongodb::options::{ClientOptions, Credential};
-use crate::models::waiters::Waiter;
-
-use super::*;
+use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+use crate::models::waiters::{NewWaiter, WaiterId, WaiterInOrder, WaiterLogin, WaiterSessionToken};
+use std::collections::{HashMap, HashSet};
+use actix_web::dev::Service;
+use actix_web::{FromRequest, HttpMessage};
+use crate::services::auth::TokenError;
+use crate::services::roles::{Authorized, FloorStaff, Managers, Principal, Role};
+use crate::services::waiter_session::TERMINAL_ID_HEADER;
+use std::time::Duration;
+use actix_web::{web, App, HttpServer};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use crate::repo::repository::Repository;
+use crate::services::auth::{validator, TokenValidationSettings};
+use crate::services::jwks::JwksCache;
+use crate::services::rate_limit::RateLimiter;
+use crate::services::waiters::{add_waiter, login_waiter};
 
 #[actix_web::test]
 #[ignore = "requires MongoDB instance running"]
 async fn test() {
-    let uri = dotenvy::var("URI").expect("URI must be set");
-    let username = dotenvy::var("DB_USERNAME").expect("DB_USERNAME must be set");
-    let password = dotenvy::var("DB_PASSWORD").expect("DB_PASSWORD must be set");
-    let db_name = dotenvy::var("DB_NAME").expect("DB_NAME must be set");
-
-    let mut client_options = ClientOptions::parse_async(uri).await.unwrap();
-    let default_cred = Credential::builder()
-        .username(username)
-        .password(password)
-        .source(db_name)
-        .build();
-    client_options.credential = Some(default_cred);
-    let client = Client::with_options(client_options).unwrap();
+    dotenvy::dotenv().ok();
+    let config = crate::config::Config::load_from(&crate::config::Cli::default(), |name| std::env::var(name).ok())
+        .expect("the test configuration should be valid");
+    let repo = Repository::connect(&config.database, None).await.expect("connecting to MongoDB should succeed");
+    repo.migrate().await.expect("migrating the test database should succeed");
 
     let app = init_service(
         App::new()
-            .app_data(web::Data::new(client))
+            .wrap_fn(|req, srv| {
+                req.extensions_mut().insert(Principal {
+                    subject: "test".into(),
+                    username: None,
+                    roles: HashSet::from([Role::Manager]),
+                });
+                srv.call(req)
+            })
+            .app_data(web::Data::new(repo))
+            .app_data(web::Data::new(RateLimiter::new(10, Duration::from_secs(60))))
             .service(add_waiter)
-            .service(get_waiter),
+            .service(login_waiter),
     )
         .await;
 
-    let waiter = Waiter {
+    let waiter = NewWaiter {
         name: "Kacper".into(),
         code: "1111".into(),
+        roles: vec![],
     };
 
     let req = TestRequest::post()
-        .uri("/add_waiter")
-        .set_form(&waiter)
+        .uri("/waiters")
+        .set_json(&waiter)
+        .to_request();
+
+    let response: WaiterInOrder = call_and_read_body_json(&app, req).await;
+    assert_eq!(response.name, waiter.name);
+
+    let req = TestRequest::post()
+        .uri("/waiters/login")
+        .insert_header((TERMINAL_ID_HEADER, "bar"))
+        .set_json(WaiterLogin { waiter_id: response._id, pin: waiter.code.clone() })
+        .to_request();
+
+    let session: WaiterSessionToken = call_and_read_body_json(&app, req).await;
+    assert_eq!(session.waiter, response);
+    assert_eq!(session.terminal_id, "bar");
+}
+
+#[actix_web::test]
+#[ignore = "requires MongoDB instance running"]
+async fn analytics_price_open_price_and_custom_lines_like_the_order() {
+    use mongodb::bson::DateTime;
+    use crate::models::analytics::AnalyticsFilter;
+    use crate::models::categories::CategoryId;
+    use crate::models::orders::{CustomLine, CustomLineId, Order, OrderId};
+    use crate::models::products::{PriceRange, Product, ProductId, ProductIdWithQuantity, Unit};
+    use crate::models::tables::TableId;
+
+    dotenvy::dotenv().ok();
+    let config = crate::config::Config::load_from(&crate::config::Cli::default(), |name| std::env::var(name).ok())
+        .expect("the test configuration should be valid");
+    let repo = Repository::connect(&config.database, None).await.expect("connecting to MongoDB should succeed");
+    repo.migrate().await.expect("migrating the test database should succeed");
+
+    let special = Product {
+        _id: ProductId::new(),
+        name: "Daily special".to_string(),
+        price: 12.0,
+        category_id: CategoryId::new(),
+        tax_rate: 8.0,
+        sku: None,
+        unit: Unit::Piece,
+        open_price: Some(PriceRange { min: 5.0, max: 25.0 }),
+    };
+    repo.insert_one::<Product>(special.clone()).await.expect("inserting the product should succeed");
+
+    let waiter_id = WaiterId::new();
+    let created_at = DateTime::now();
+    repo.insert_one::<Order>(Order {
+        _id: OrderId::new(),
+        waiter_id,
+        table_id: TableId::new(),
+        products: vec![ProductIdWithQuantity { _id: special._id, quantity: 2.0, sent_quantity: 0.0, price: Some(14.5) }],
+        created_at,
+        covers: 2,
+        payments: vec![],
+        discounts: vec![],
+        voids: vec![],
+        refunds: vec![],
+        closed_at: None,
+        device_id: None,
+        mutations: vec![],
+        custom_lines: vec![CustomLine {
+            _id: CustomLineId::new(),
+            name: "Misc food".to_string(),
+            price: 15.0,
+            tax_rate: 8.0,
+            quantity: 2.0,
+            sent_quantity: 0.0,
+            added_at: created_at,
+        }],
+    }).await.expect("inserting the order should succeed");
+
+    let filter = AnalyticsFilter {
+        from: DateTime::from_millis(created_at.timestamp_millis() - 1000),
+        to: DateTime::from_millis(created_at.timestamp_millis() + 1000),
+        waiter_id: Some(waiter_id),
+    };
+
+    let tickets = repo.query_ticket_stats(&filter).await.expect("ticket stats should load");
+    assert_eq!(tickets.revenue, 59.0);
+    assert_eq!(tickets.average_ticket, 59.0);
+
+    let mix = repo.query_product_mix(&filter, 10).await.expect("the product mix should load");
+    assert_eq!(mix.top_by_revenue[0].revenue, 29.0);
+
+    let categories = repo.query_category_sales(&filter).await.expect("category sales should load");
+    assert_eq!(categories[0].revenue, 29.0);
+}
+
+#[actix_web::test]
+async fn pin_hash_verifies_only_the_same_pin() {
+    use crate::services::waiter_session::{hash_pin, verify_pin};
+
+    let hash = hash_pin("1234".into()).await.unwrap();
+
+    assert_ne!(hash, "1234");
+    assert!(verify_pin("1234".into(), hash.clone()).await.unwrap());
+    assert!(!verify_pin("4321".into(), hash).await.unwrap());
+}
+
+#[actix_web::test]
+async fn authorized_checks_principal_roles() {
+    assert_eq!(Role::from_claim("pos-admin"), Some(Role::Admin));
+    assert_eq!(Role::from_claim("pos-user"), Some(Role::Waiter));
+    assert_eq!(Role::from_claim("kitchen"), Some(Role::Kitchen));
+    assert_eq!(Role::from_claim("offline_access"), None);
+
+    let req = TestRequest::default().to_http_request();
+    assert!(Authorized::<FloorStaff>::extract(&req).await.is_err());
+
+    req.extensions_mut().insert(Principal {
+        subject: "waiter".into(),
+        username: None,
+        roles: HashSet::from([Role::Waiter]),
+    });
+    assert!(Authorized::<FloorStaff>::extract(&req).await.is_ok());
+    assert!(Authorized::<Managers>::extract(&req).await.is_err());
+
+    let req = TestRequest::default().to_http_request();
+    req.extensions_mut().insert(Principal {
+        subject: "admin".into(),
+        username: None,
+        roles: HashSet::from([Role::Admin]),
+    });
+    assert!(Authorized::<Managers>::extract(&req).await.is_ok());
+}
+
+#[test]
+fn rate_limiter_blocks_after_max_attempts() {
+    let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+    assert!(limiter.check("bar"));
+    assert!(limiter.check("bar"));
+    assert!(!limiter.check("bar"));
+    assert!(limiter.check("patio"));
+}
+
+#[test]
+fn shift_cash_amounts_are_validated() {
+    use crate::models::shifts::{ClockIn, ClockOut, NewCashDrop};
+    use crate::models::validation::Validate;
+
+    assert!(ClockIn { waiter_id: WaiterId::new(), opening_float: 0.0 }.validate().is_ok());
+    assert!(ClockIn { waiter_id: WaiterId::new(), opening_float: -10.0 }.validate().is_err());
+    assert!(ClockOut { counted_cash: f64::INFINITY }.validate().is_err());
+    assert!(NewCashDrop { amount: 0.0, reason: String::new() }.validate().is_err());
+    assert!(NewCashDrop { amount: 50.0, reason: "Safe".into() }.validate().is_ok());
+}
+
+#[test]
+fn business_day_cutoff_belongs_to_previous_day() {
+    use chrono::{NaiveDate, NaiveTime, TimeZone, Utc};
+    use crate::repo::reports::{business_day_bounds, business_day_of};
+
+    let cutoff = NaiveTime::from_hms_opt(4, 0, 0).unwrap();
+    let day = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+
+    assert_eq!(business_day_of(&Utc.with_ymd_and_hms(2024, 3, 10, 3, 59, 0).unwrap(), cutoff), day);
+    assert_eq!(business_day_of(&Utc.with_ymd_and_hms(2024, 3, 10, 4, 0, 0).unwrap(), cutoff), day.succ_opt().unwrap());
+
+    let (start, end) = business_day_bounds(&Utc, day, cutoff);
+    assert_eq!(start.timestamp_millis(), Utc.with_ymd_and_hms(2024, 3, 9, 4, 0, 0).unwrap().timestamp_millis());
+    assert_eq!(end.timestamp_millis(), Utc.with_ymd_and_hms(2024, 3, 10, 4, 0, 0).unwrap().timestamp_millis());
+}
+
+#[test]
+fn payments_and_discounts_need_positive_amounts() {
+    use crate::models::orders::{Discount, NewPayment, Tender};
+    use crate::models::validation::Validate;
+
+    assert!(NewPayment { tender: Tender::Cash, amount: 20.0, tip: 0.0 }.validate().is_ok());
+    assert_eq!(NewPayment { tender: Tender::Cash, amount: f64::NAN, tip: -1.0 }.validate().unwrap_err().fields.len(), 2);
+    assert!(Discount { reason: "Birthday".into(), amount: 5.0 }.validate().is_ok());
+    assert_eq!(Discount { reason: " ".into(), amount: -5.0 }.validate().unwrap_err().fields.len(), 2);
+}
+
+#[test]
+fn sales_report_splits_tax_and_tenders() {
+    use chrono::NaiveDate;
+    use mongodb::bson::DateTime;
+    use crate::models::categories::{Category, CategoryId};
+    use crate::models::orders::{Discount, OrderAPI, OrderId, Payment, ReasonCode, Refund, Tender};
+    use crate::models::products::{ProductId, ProductInOrder, Unit};
+    use crate::models::reports::ReportKind;
+    use crate::models::tables::{TableId, TableInOrder};
+    use crate::models::waiters::WaiterInOrder;
+    use crate::repo::reports::build_sales_report;
+
+    let category = Category { _id: CategoryId::new(), name: "Food".into(), icon: "".into(), color: "#fff".into() };
+    let line = |price: f64, tax_rate: f64, quantity: f64| ProductInOrder {
+        _id: ProductId::new(),
+        name: "Item".into(),
+        price,
+        category: category.clone(),
+        tax_rate,
+        unit: Unit::Piece,
+        quantity,
+        sent_quantity: quantity,
+    };
+    let order = OrderAPI {
+        _id: OrderId::new(),
+        waiter: WaiterInOrder { _id: WaiterId::new(), name: "Kacper".into() },
+        table: TableInOrder { _id: TableId::new(), name: "1".into() },
+        products: vec![line(12.3, 23.0, 2.0), line(10.8, 8.0, 1.0)],
+        custom_lines: vec![],
+        sum: 35.4,
+        created_at: DateTime::now(),
+        covers: 2,
+        payments: vec![
+            Payment { tender: Tender::Card, amount: 20.0, tip: 2.0, paid_at: DateTime::now() },
+            Payment { tender: Tender::Cash, amount: 15.4, tip: 0.0, paid_at: DateTime::now() },
+        ],
+        discounts: vec![],
+        voids: vec![],
+        refunds: vec![],
+        closed_at: None,
+    };
+    let discounted = OrderAPI {
+        _id: OrderId::new(),
+        products: vec![line(10.8, 8.0, 1.0)],
+        sum: 10.8,
+        covers: 1,
+        payments: vec![],
+        discounts: vec![Discount { reason: "staff".into(), amount: 5.4 }],
+        ..order.clone()
+    };
+
+    let day = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+    let refund = Refund {
+        tender: Tender::Cash,
+        amount: 5.0,
+        reason: ReasonCode::QualityIssue,
+        note: None,
+        approved_by: Some("manager-1".into()),
+        refunded_at: DateTime::now(),
+    };
+    let report = build_sales_report(ReportKind::X, day, DateTime::now(), DateTime::now(), &[order, discounted], &[refund]);
+
+    assert_eq!(report.order_count, 2);
+    assert_eq!(report.covers, 3);
+    assert_eq!(report.gross_sales, 46.2);
+    assert_eq!(report.discounts, 5.4);
+    assert_eq!(report.tax.len(), 2);
+    assert_eq!(report.tax[0].rate, 8.0);
+    assert_eq!(report.tax[0].gross, 16.2);
+    assert_eq!(report.tax[0].tax, 1.2);
+    assert_eq!(report.tax[1].rate, 23.0);
+    assert_eq!(report.tax[1].tax, 4.6);
+    assert_eq!(report.net_sales, 35.0);
+    assert_eq!(report.payments[0].tender, Tender::Cash);
+    assert_eq!(report.payments[0].amount, 10.4);
+    assert_eq!(report.payments_total, 30.4);
+    assert_eq!(report.refunds_count, 1);
+    assert_eq!(report.refunds_amount, 5.0);
+    assert_eq!(report.tips_total, 2.0);
+}
+
+#[test]
+fn reports_keep_orders_whose_references_were_deleted() {
+    use mongodb::bson::DateTime;
+    use crate::models::categories::{Category, CategoryId};
+    use crate::models::orders::{Order, OrderId};
+    use crate::models::products::{Product, ProductId, ProductIdWithQuantity, Unit};
+    use crate::models::tables::{TableId, TableInOrder};
+    use crate::repo::reports::orders_with_references;
+
+    let category = Category { _id: CategoryId::new(), name: "Food".into(), icon: "".into(), color: "#fff".into() };
+    let soup = Product {
+        _id: ProductId::new(),
+        name: "Soup".into(),
+        price: 8.0,
+        category_id: category._id,
+        tax_rate: 8.0,
+        sku: None,
+        unit: Unit::Piece,
+        open_price: None,
+    };
+    let table = TableInOrder { _id: TableId::new(), name: "1".into() };
+    let line = |_id: ProductId, price: Option<f64>| ProductIdWithQuantity { _id, quantity: 1.0, sent_quantity: 1.0, price };
+    let order = Order {
+        _id: OrderId::new(),
+        waiter_id: WaiterId::new(),
+        table_id: table._id,
+        products: vec![line(soup._id, None), line(ProductId::new(), Some(14.5)), line(ProductId::new(), None)],
+        created_at: DateTime::now(),
+        covers: 1,
+        payments: vec![],
+        discounts: vec![],
+        voids: vec![],
+        refunds: vec![],
+        closed_at: None,
+        device_id: None,
+        mutations: vec![],
+        custom_lines: vec![],
+    };
+
+    let orders = orders_with_references(vec![order], &[], std::slice::from_ref(&table), &[soup], &[category]);
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].waiter.name, "Unknown");
+    assert_eq!(orders[0].table, table);
+    assert_eq!(orders[0].products[0].category.name, "Food");
+    assert_eq!(orders[0].products[1].name, "Unknown");
+    assert_eq!(orders[0].sum, 22.5);
+}
+
+struct JwksStandIn {
+    body: std::sync::RwLock<Option<&'static str>>,
+    hits: std::sync::atomic::AtomicUsize,
+}
+
+impl JwksStandIn {
+    fn serve(&self, body: Option<&'static str>) {
+        *self.body.write().unwrap() = body;
+    }
+
+    fn hits(&self) -> usize {
+        self.hits.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+async fn serve_jwks(state: web::Data<JwksStandIn>) -> actix_web::HttpResponse {
+    state.hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+    match *state.body.read().unwrap() {
+        Some(body) => actix_web::HttpResponse::Ok().content_type("application/json").body(body),
+        None => actix_web::HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Serves the JWKS fixture on a random local port, standing in for Keycloak's certs endpoint.
+fn start_jwks_stand_in() -> (web::Data<JwksStandIn>, String) {
+    let state = web::Data::new(JwksStandIn {
+        body: std::sync::RwLock::new(Some(include_str!("fixtures/jwks.json"))),
+        hits: std::sync::atomic::AtomicUsize::new(0),
+    });
+
+    let app_state = state.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(app_state.clone())
+            .route("/certs", web::get().to(serve_jwks))
+    })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+    let url = format!("http://{}/certs", server.addrs()[0]);
+    actix_web::rt::spawn(server.run());
+
+    (state, url)
+}
+
+#[derive(serde::Serialize)]
+struct TestClaims {
+    aud: Vec<&'static str>,
+    iss: &'static str,
+    sub: &'static str,
+    exp: u64,
+    realm_access: HashMap<&'static str, Vec<&'static str>>,
+}
+
+fn test_claims() -> TestClaims {
+    TestClaims {
+        aud: vec!["account"],
+        iss: "http://localhost:8888/realms/pos-system",
+        sub: "manager-1",
+        exp: jsonwebtoken::get_current_timestamp() + 600,
+        realm_access: HashMap::from([("roles", vec!["pos-manager", "offline_access"])]),
+    }
+}
+
+fn test_token_settings() -> TokenValidationSettings {
+    TokenValidationSettings {
+        issuers: vec!["http://localhost:8888/realms/pos-system".into()],
+        audiences: vec!["account".into()],
+        algorithms: vec![jsonwebtoken::Algorithm::RS256],
+        leeway_secs: 0,
+        client_id: None,
+    }
+}
+
+fn sign_claims(kid: &str, key_pem: &str, claims: &TestClaims) -> String {
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(kid.to_string());
+
+    encode(&header, claims, &EncodingKey::from_rsa_pem(key_pem.as_bytes()).unwrap()).unwrap()
+}
+
+fn signed_token(kid: &str, key_pem: &str) -> String {
+    sign_claims(kid, key_pem, &test_claims())
+}
+
+#[actix_web::test]
+async fn jwks_cache_fetches_keys_once() {
+    use crate::services::auth::validate_token;
+
+    let (stand_in, url) = start_jwks_stand_in();
+    let cache = JwksCache::new(url, Duration::from_secs(60), Duration::from_secs(60));
+    let token = signed_token("pos-key-1", include_str!("fixtures/jwks-signing-key.pem"));
+
+    for _ in 0..3 {
+        let claims = validate_token(&token, &cache, &test_token_settings()).await.expect("token should validate");
+        assert_eq!(claims.principal(None).roles, HashSet::from([Role::Manager]));
+    }
+
+    assert_eq!(stand_in.hits(), 1);
+}
+
+#[actix_web::test]
+async fn jwks_cache_refetches_on_unknown_kid() {
+    use crate::services::auth::validate_token;
+
+    let (stand_in, url) = start_jwks_stand_in();
+    let cache = JwksCache::new(url, Duration::from_secs(60), Duration::from_secs(60));
+
+    let token = signed_token("pos-key-1", include_str!("fixtures/jwks-signing-key.pem"));
+    assert!(validate_token(&token, &cache, &test_token_settings()).await.is_ok());
+
+    stand_in.serve(Some(include_str!("fixtures/jwks-rotated.json")));
+    let rotated = signed_token("pos-key-2", include_str!("fixtures/jwks-rotated-signing-key.pem"));
+    assert!(validate_token(&rotated, &cache, &test_token_settings()).await.is_ok());
+    assert_eq!(stand_in.hits(), 2);
+
+    // A kid nobody knows only triggers another fetch once the cooldown has passed.
+    let unknown = signed_token("pos-key-3", include_str!("fixtures/jwks-rotated-signing-key.pem"));
+    assert_eq!(validate_token(&unknown, &cache, &test_token_settings()).await.unwrap_err(), TokenError::UnknownKey);
+    assert_eq!(validate_token(&unknown, &cache, &test_token_settings()).await.unwrap_err(), TokenError::UnknownKey);
+    assert_eq!(stand_in.hits(), 2);
+}
+
+#[actix_web::test]
+async fn jwks_cache_serves_stale_keys_while_keycloak_is_down() {
+    use crate::services::auth::validate_token;
+
+    let (stand_in, url) = start_jwks_stand_in();
+    let token = signed_token("pos-key-1", include_str!("fixtures/jwks-signing-key.pem"));
+
+    let cache = JwksCache::new(url.clone(), Duration::from_millis(50), Duration::from_secs(60));
+    assert!(validate_token(&token, &cache, &test_token_settings()).await.is_ok());
+
+    // Expired keys are served straight away while the refresh runs in the background.
+    stand_in.serve(None);
+    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    assert!(validate_token(&token, &cache, &test_token_settings()).await.is_ok());
+    assert_eq!(stand_in.hits(), 1);
+    actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(stand_in.hits(), 2);
+    assert!(validate_token(&token, &cache, &test_token_settings()).await.is_ok());
+
+    let strict = JwksCache::new(url, Duration::from_millis(50), Duration::ZERO);
+    assert!(matches!(validate_token(&token, &strict, &test_token_settings()).await, Err(TokenError::KeysUnavailable(_))));
+}
+
+#[actix_web::test]
+async fn token_validation_reports_why_a_token_was_rejected() {
+    use crate::services::auth::validate_token;
+
+    let (_stand_in, url) = start_jwks_stand_in();
+    let cache = JwksCache::new(url, Duration::from_secs(60), Duration::from_secs(60));
+    let key = include_str!("fixtures/jwks-signing-key.pem");
+    let settings = test_token_settings();
+
+    let claims = validate_token(&sign_claims("pos-key-1", key, &test_claims()), &cache, &settings).await.unwrap();
+    assert_eq!(claims.principal(None).subject, "manager-1");
+
+    let expired = TestClaims { exp: jsonwebtoken::get_current_timestamp() - 120, ..test_claims() };
+    assert_eq!(validate_token(&sign_claims("pos-key-1", key, &expired), &cache, &settings).await.unwrap_err(), TokenError::Expired);
+
+    let lenient = TokenValidationSettings { leeway_secs: 300, ..test_token_settings() };
+    assert!(validate_token(&sign_claims("pos-key-1", key, &expired), &cache, &lenient).await.is_ok());
+
+    let other_audience = TestClaims { aud: vec!["broker", "realm-management"], ..test_claims() };
+    assert_eq!(validate_token(&sign_claims("pos-key-1", key, &other_audience), &cache, &settings).await.unwrap_err(), TokenError::WrongAudience);
+
+    let other_issuer = TestClaims { iss: "http://evil.example/realms/pos-system", ..test_claims() };
+    assert_eq!(validate_token(&sign_claims("pos-key-1", key, &other_issuer), &cache, &settings).await.unwrap_err(), TokenError::WrongIssuer);
+
+    let forged = sign_claims("pos-key-1", include_str!("fixtures/jwks-rotated-signing-key.pem"), &test_claims());
+    assert_eq!(validate_token(&forged, &cache, &settings).await.unwrap_err(), TokenError::BadSignature);
+
+    let ps_only = TokenValidationSettings { algorithms: vec![jsonwebtoken::Algorithm::PS256], ..test_token_settings() };
+    assert_eq!(
+        validate_token(&sign_claims("pos-key-1", key, &test_claims()), &cache, &ps_only).await.unwrap_err(),
+        TokenError::AlgorithmNotAccepted(jsonwebtoken::Algorithm::RS256)
+    );
+}
+
+#[actix_web::test]
+async fn local_auth_accepts_issued_tokens_and_device_keys() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::call_service;
+    use mongodb::bson::DateTime;
+    use crate::models::waiters::{Waiter, WaiterSession};
+    use crate::services::local_auth::{parse_device_keys, LocalAuthSettings};
+
+    let mut local = LocalAuthSettings::hs256(b"a-secret-that-never-leaves-the-restaurant", Duration::from_secs(600));
+    for (key, device) in parse_device_keys("bar=bar-key, office:manager+kitchen=office-key").unwrap() {
+        local = local.with_device_key(&key, device);
+    }
+    assert!(parse_device_keys("bar:chef=key").is_err());
+
+    let waiter = Waiter {
+        _id: WaiterId::new(),
+        name: "Kacper".into(),
+        pin_hash: String::new(),
+        failed_attempts: 0,
+        locked_until: None,
+        roles: vec![Role::Waiter],
+        code: None,
+    };
+    let session = WaiterSession {
+        _id: "session-hash".into(),
+        waiter_id: waiter._id,
+        terminal_id: "bar".into(),
+        created_at: DateTime::now(),
+        expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000),
+    };
+    let token = local.issue_token(&waiter, &session).unwrap();
+
+    let app = init_service(
+        App::new()
+            .wrap(HttpAuthentication::bearer(validator))
+            .app_data(web::Data::new(local))
+            .route("/whoami", web::get().to(|auth: Authorized<FloorStaff>| async move {
+                actix_web::HttpResponse::Ok().body(auth.principal.subject)
+            })),
+    )
+        .await;
+
+    let whoami = |token: &str, terminal: &str| TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .insert_header((TERMINAL_ID_HEADER, terminal.to_string()))
+        .to_request();
+
+    // Without a stored session the token is no good; see local_tokens_stop_working_after_logout.
+    assert_eq!(call_service(&app, whoami(&token, "bar")).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(call_service(&app, whoami(&token, "patio")).await.status(), StatusCode::UNAUTHORIZED);
+
+    let body = actix_web::test::call_and_read_body(&app, whoami("bar-key", "bar")).await;
+    assert_eq!(body, "device:bar");
+    assert_eq!(call_service(&app, whoami("guessed-key", "bar")).await.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(call_service(&app, whoami("office-key", "office")).await.status(), StatusCode::OK);
+
+    let forged = LocalAuthSettings::hs256(b"someone-elses-secret-of-enough-length", Duration::from_secs(600))
+        .issue_token(&waiter, &session)
+        .unwrap();
+    assert_eq!(call_service(&app, whoami(&forged, "bar")).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+#[ignore = "requires MongoDB instance running"]
+async fn local_tokens_stop_working_after_logout() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body, call_service};
+    use mongodb::bson::DateTime;
+    use crate::models::waiters::{Waiter, WaiterSession};
+    use crate::services::local_auth::LocalAuthSettings;
+    use crate::services::waiter_session::new_session_token;
+
+    dotenvy::dotenv().ok();
+    let config = crate::config::Config::load_from(&crate::config::Cli::default(), |name| std::env::var(name).ok())
+        .expect("the test configuration should be valid");
+    let repo = Repository::connect(&config.database, None).await.expect("connecting to MongoDB should succeed");
+    repo.migrate().await.expect("migrating the test database should succeed");
+
+    let local = LocalAuthSettings::hs256(b"a-secret-that-never-leaves-the-restaurant", Duration::from_secs(600));
+    let waiter = Waiter {
+        _id: WaiterId::new(),
+        name: "Kacper".into(),
+        pin_hash: String::new(),
+        failed_attempts: 0,
+        locked_until: None,
+        roles: vec![Role::Waiter],
+        code: None,
+    };
+    let (_, token_hash) = new_session_token();
+    let session = WaiterSession {
+        _id: token_hash,
+        waiter_id: waiter._id,
+        terminal_id: "bar".into(),
+        created_at: DateTime::now(),
+        expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000),
+    };
+    repo.insert_one::<WaiterSession>(session.clone()).await.unwrap();
+    let token = local.issue_token(&waiter, &session).unwrap();
+
+    let app = init_service(
+        App::new()
+            .wrap(HttpAuthentication::bearer(validator))
+            .app_data(web::Data::new(local))
+            .app_data(web::Data::new(repo.clone()))
+            .route("/whoami", web::get().to(|auth: Authorized<FloorStaff>| async move {
+                actix_web::HttpResponse::Ok().body(auth.principal.subject)
+            })),
+    )
+        .await;
+
+    let whoami = || TestRequest::get()
+        .uri("/whoami")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .insert_header((TERMINAL_ID_HEADER, "bar"))
         .to_request();
 
-    let response = call_and_read_body(&app, req).await;
-    assert_eq!(response, Bytes::from_static(b"waiter added"));
+    assert_eq!(call_and_read_body(&app, whoami()).await, waiter._id.to_string());
+
+    repo.delete_waiter_session(&session._id).await.unwrap();
+    assert_eq!(call_service(&app, whoami()).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn audit_diff_lists_changed_fields_and_redacts_secrets() {
+    use mongodb::bson::{doc, Bson};
+    use crate::repo::audit::diff_documents;
+
+    let before = doc! { "_id": 1, "name": "Kacper", "pin_hash": "old", "failed_attempts": 2 };
+    let after = doc! { "_id": 1, "name": "Kacper", "pin_hash": "new", "failed_attempts": 0, "locked_until": Bson::Null };
+
+    let changes = diff_documents(Some(&before), Some(&after));
+    let fields = changes.iter().map(|change| change.field.as_str()).collect::<Vec<&str>>();
+    assert_eq!(fields, vec!["pin_hash", "failed_attempts", "locked_until"]);
+    assert_eq!(changes[0].before, Some(Bson::String("[redacted]".into())));
+    assert_eq!(changes[1].after, Some(Bson::Int32(0)));
+    assert_eq!(changes[2].before, None);
+
+    let deleted = diff_documents(Some(&before), None);
+    assert_eq!(deleted.len(), 4);
+    assert!(deleted.iter().all(|change| change.after.is_none()));
+
+    let before = doc! { "_id": 1, "payments": [{ "amount": 10.0 }], "mutations": [{ "action": "open" }] };
+    let after = doc! { "_id": 1, "payments": [{ "amount": 10.0 }, { "amount": 5.0 }], "mutations": [{ "action": "open" }, { "action": "pay" }] };
+    let changes = diff_documents(Some(&before), Some(&after));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].field, "payments.1");
+    assert_eq!(changes[0].before, None);
+    assert_eq!(changes[0].after, Some(Bson::Document(doc! { "amount": 5.0 })));
+}
+
+#[actix_web::test]
+async fn errors_are_json_with_code_and_request_id() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, read_body_json};
+    use crate::repo::error::RepoError;
+    use crate::services::error::{json_error_bodies, json_payload_error, ServiceError};
+
+    let missing = WaiterId::new();
+    let app = init_service(
+        App::new()
+            .wrap_fn(json_error_bodies)
+            .app_data(web::JsonConfig::default().error_handler(json_payload_error))
+            .route("/missing", web::get().to(move || async move {
+                Err::<actix_web::HttpResponse, ServiceError>(RepoError::IdsNotFound(vec![missing]).into())
+            }))
+            .route("/echo", web::post().to(|body: web::Json<WaiterLogin>| async move {
+                actix_web::HttpResponse::Ok().json(body.into_inner())
+            })),
+    )
+        .await;
+
+    let res = call_service(&app, TestRequest::get().uri("/missing").insert_header(("X-Request-Id", "req-1")).to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.headers().get("x-request-id").unwrap(), "req-1");
+    let body: serde_json::Value = read_body_json(res).await;
+    assert_eq!(body["code"], "ids_not_found");
+    assert_eq!(body["details"]["missing_ids"], serde_json::json!([missing.to_string()]));
+    assert_eq!(body["request_id"], "req-1");
+
+    let res = call_service(&app, TestRequest::post().uri("/echo").insert_header(("Content-Type", "application/json")).set_payload("{").to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_body");
+    assert!(body["request_id"].is_string());
+
+    let res = call_service(&app, TestRequest::get().uri("/nowhere").to_request()).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = read_body_json(res).await;
+    assert_eq!(body["code"], "not_found");
+}
+
+#[actix_web::test]
+async fn bad_ids_and_bodies_are_rejected_with_400() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, read_body_json};
+    use crate::models::products::{NewProduct, ProductId, Unit};
+    use crate::models::validation::Validate;
+    use crate::services::error::{json_error_bodies, ServiceError};
+    use crate::services::path::IdPath;
+
+    let app = init_service(
+        App::new()
+            .wrap_fn(json_error_bodies)
+            .route("/products/{id}", web::get().to(|id: IdPath| async move {
+                actix_web::HttpResponse::Ok().body(id.into_inner().to_string())
+            })),
+    )
+        .await;
+
+    let id = ProductId::new();
+    let body = actix_web::test::call_and_read_body(&app, TestRequest::get().uri(&format!("/products/{}", id)).to_request()).await;
+    assert_eq!(body, id.to_string());
+
+    let res = call_service(&app, TestRequest::get().uri("/products/not-a-uuid").to_request()).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = read_body_json(res).await;
+    assert_eq!(body["code"], "invalid_path");
+
+    let product = NewProduct {
+        name: "  ".to_string(),
+        price: -1.0,
+        category_id: ProductId::new(),
+        tax_rate: 19.0,
+        sku: None,
+        unit: Unit::Piece,
+        open_price: None,
+    };
+    let error: ServiceError = product.validate().unwrap_err().into();
+    let body = error.body(None);
+    assert_eq!(body.code, "validation_failed");
+    let fields = body.details.unwrap()["fields"].clone();
+    assert_eq!(fields[0]["field"], "name");
+    assert_eq!(fields[1]["field"], "price");
+    assert_eq!(fields.as_array().unwrap().len(), 2);
+
+    assert!(crate::models::validation::is_hex_color("#1e90ff"));
+    assert!(!crate::models::validation::is_hex_color("1e90ff"));
+    assert!(!crate::models::validation::is_hex_color("#12345"));
+}
+
+#[test]
+fn unknown_references_are_422_with_each_reference() {
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use crate::repo::error::{RepoError, UnknownReference};
+    use crate::services::error::ServiceError;
+
+    let waiter_id = WaiterId::new();
+    let error: ServiceError = RepoError::UnknownReferences(vec![UnknownReference {
+        field: "waiter_id",
+        collection: "waiters",
+        id: waiter_id,
+    }]).into();
+
+    assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = error.body(None);
+    assert_eq!(body.code, "unknown_references");
+    assert_eq!(
+        body.details.unwrap()["references"],
+        serde_json::json!([{ "field": "waiter_id", "collection": "waiters", "id": waiter_id.to_string() }])
+    );
+}
+
+#[test]
+fn config_layers_file_then_env_then_cli() {
+    use crate::config::{Cli, Config};
+    use crate::services::local_auth::AuthMode;
+
+    let path = std::env::temp_dir().join(format!("pos-config-{}.toml", WaiterId::new()));
+    std::fs::write(&path, r#"
+[server]
+address = "0.0.0.0"
+port = 9000
+cors_origins = ["https://pos.example.com"]
+
+[database]
+uri = "mongodb://db:27017"
+name = "pos"
+"#).unwrap();
+
+    let env: HashMap<&str, &str> = HashMap::from([
+        ("API_PORT", "9100"),
+        ("AUTH_MODE", "local"),
+        ("LOCAL_AUTH_SECRET", "a-secret-that-never-leaves-the-restaurant"),
+        ("BUSINESS_DAY_CUTOFF", "05:30"),
+        ("VOID_APPROVAL_THRESHOLD", "25"),
+        ("API_AUTH_ATTEMPT_LIMIT", "5"),
+    ]);
+    let cli = Cli {
+        config: Some(path.clone()),
+        db_name: Some("pos_test".to_string()),
+        ..Cli::default()
+    };
+    let config = Config::load_from(&cli, |name| env.get(name).map(|value| value.to_string()));
+    std::fs::remove_file(&path).unwrap();
+    let config = config.unwrap();
+
+    assert_eq!(config.server.address, "0.0.0.0");
+    assert_eq!(config.server.port, 9100);
+    assert_eq!(config.server.cors_origins, vec!["https://pos.example.com"]);
+    assert_eq!(config.database.name, "pos_test");
+    assert_eq!(config.auth.mode, AuthMode::Local);
+    assert_eq!(config.reports.business_day_cutoff, chrono::NaiveTime::from_hms_opt(5, 30, 0).unwrap());
+    assert_eq!(config.orders.void_approval_threshold, 25.0);
+    assert_eq!((config.auth.attempt_limit, config.auth.attempt_window_secs), (5, 60));
+
+    let err = Config::load_from(&Cli { config: Some(path), ..Cli::default() }, |_| None).unwrap_err();
+    assert!(err.contains("Reading config file"), "{}", err);
+
+    let err = Config::load_from(&Cli::default(), |_| None).unwrap_err();
+    assert!(err.contains("DB_URI"), "{}", err);
+    assert!(err.contains("API_AUTH_CERTS"), "{}", err);
+
+    let env: HashMap<&str, &str> = HashMap::from([("AUTH_MODE", "local"), ("API_AUTH_ALGORITHMS", "HS256"), ("VOID_APPROVAL_THRESHOLD", "-1")]);
+    let err = Config::load_from(&Cli::default(), |name| env.get(name).map(|value| value.to_string())).unwrap_err();
+    assert!(err.contains("LOCAL_AUTH_SECRET"), "{}", err);
+    assert!(err.contains("VOID_APPROVAL_THRESHOLD"), "{}", err);
+    assert!(!err.contains("HS256"), "Keycloak settings are not checked in local mode: {}", err);
+    let err = Config::load_from(&Cli::default(), |name| (name == "BUSINESS_DAY_CUTOFF").then(|| "4am".to_string())).unwrap_err();
+    assert!(err.contains("BUSINESS_DAY_CUTOFF"), "{}", err);
+
+    // pos-admin only talks to MongoDB, so Keycloak settings must not be required.
+    let env: HashMap<&str, &str> = HashMap::from([("DB_URI", "mongodb://db:27017"), ("DB_NAME", "pos")]);
+    let config = Config::layered(&Cli::default(), |name| env.get(name).map(|value| value.to_string())).unwrap();
+    assert!(config.validate().is_err());
+    assert_eq!(config.validate_database(), Ok(()));
+}
+
+#[test]
+fn menu_import_reports_row_errors_and_upserts_by_sku_or_name() {
+    use crate::models::categories::{Category, CategoryId};
+    use crate::models::menu::MenuFile;
+    use crate::models::products::{Product, ProductId, Unit};
+    use crate::repo::menu::plan_menu_import;
+
+    let drinks = Category { _id: CategoryId::new(), name: "Drinks".to_string(), icon: "local_bar".to_string(), color: "#1e88e5".to_string() };
+    let cola = Product { _id: ProductId::new(), name: "Cola".to_string(), price: 3.0, category_id: drinks._id, tax_rate: 19.0, sku: Some("D-1".to_string()), unit: Unit::Piece, open_price: None };
+    let water = Product { _id: ProductId::new(), name: "Water".to_string(), price: 2.0, category_id: drinks._id, tax_rate: 19.0, sku: None, unit: Unit::Piece, open_price: None };
+
+    let csv = "\
+category,category_icon,category_color,name,sku,price,tax_rate
+Desserts,icecream,#8e24aa,Tiramisu,,6.5,7
+Desserts,icecream,#8e24aa,,,,
+drinks,,,Coca-Cola,D-1,3.2,19
+Drinks,,,Water,,2,19
+Drinks,,,Juice,,abc,19
+Snacks,,,Chips,,2.5,7
+Desserts,icecream,#8e24aa,Tiramisu,,6.9,7
+";
+    let (menu, parse_errors) = MenuFile::from_csv(csv.as_bytes());
+    assert_eq!(menu.categories.len(), 1);
+    assert_eq!(parse_errors.len(), 1);
+    assert_eq!((parse_errors[0].row, parse_errors[0].field.as_str()), (6, "price"));
+
+    let plan = plan_menu_import(&menu, std::slice::from_ref(&drinks), &[cola.clone(), water]);
+    let errors: Vec<(usize, &str)> = plan.report.errors.iter().map(|error| (error.row, error.field.as_str())).collect();
+    assert_eq!(errors, vec![(7, "category"), (8, "name")]);
+    assert_eq!(plan.report.categories_created, 1);
+    assert_eq!(plan.report.products_created, 2);
+    // Matched on SKU despite the new name; Water matched by name and did not change.
+    assert_eq!(plan.report.products_updated, 1);
+    assert_eq!(plan.report.unchanged, 1);
+    let renamed = plan.products.iter().find(|product| product.name == "Coca-Cola").unwrap();
+    assert_eq!(renamed._id, cola._id);
+
+    let exported = MenuFile::from_csv(&menu.to_csv().unwrap()).0;
+    assert_eq!(exported.products.len(), menu.products.len());
+    assert_eq!(MenuFile::from_json(&menu.to_json().unwrap()).unwrap().products.len(), menu.products.len());
+}
+
+#[test]
+fn backups_round_trip_and_refuse_damaged_or_newer_archives() {
+    use mongodb::bson::{doc, DateTime};
+    use crate::models::backup::{BackupCollection, BackupManifest, BACKUP_FORMAT_VERSION};
+    use crate::repo::backup::Backup;
+    use crate::repo::error::RepoError;
+    use crate::repo::migrations::latest_version;
+
+    let collections = vec![
+        ("orders".to_string(), vec![doc! { "_id": WaiterId::new(), "created_at": DateTime::now(), "covers": 2, "total": 12.5 }]),
+        ("schema_migrations".to_string(), vec![doc! { "_id": 1, "name": "first" }, doc! { "_id": 2, "name": "second" }]),
+    ];
+    let backup = Backup {
+        manifest: BackupManifest {
+            format_version: BACKUP_FORMAT_VERSION,
+            schema_version: 2,
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            created_by: "test".to_string(),
+            collections: collections.iter().map(|(name, documents)| BackupCollection { name: name.clone(), documents: documents.len() }).collect(),
+        },
+        collections,
+    };
+
+    assert_eq!(Backup::from_archive(&backup.to_archive().unwrap()).unwrap(), backup);
+
+    let mut miscounted = backup.clone();
+    miscounted.manifest.collections[0].documents = 5;
+    assert!(matches!(Backup::from_archive(&miscounted.to_archive().unwrap()), Err(RepoError::InvalidBackup(_))));
+
+    let mut newer = backup.clone();
+    newer.manifest.schema_version = latest_version() + 1;
+    newer.collections[1].1.push(doc! { "_id": latest_version() as i32 + 1, "name": "from the future" });
+    newer.manifest.collections[1].documents = 3;
+    let err = Backup::from_archive(&newer.to_archive().unwrap()).unwrap_err();
+    assert!(err.to_string().contains("only knows up to"), "{}", err);
+
+    assert!(matches!(Backup::from_archive(b"not a tar"), Err(RepoError::InvalidBackup(_))));
+}
+
+#[test]
+fn order_batches_apply_in_order_or_not_at_all() {
+    use crate::models::orders::{LineOperation, OrderBatch};
+    use crate::models::validation::Validate;
+    use crate::models::products::{ProductId, ProductIdWithQuantity};
+    use crate::repo::error::RepoError;
+    use crate::repo::orders::apply_line_operations;
+
+    let (beer, soup, cake) = (ProductId::new(), ProductId::new(), ProductId::new());
+    let lines = vec![
+        ProductIdWithQuantity { _id: beer, quantity: 2.0, sent_quantity: 1.0, price: None },
+        ProductIdWithQuantity { _id: soup, quantity: 1.0, sent_quantity: 0.0, price: None },
+    ];
+
+    let result = apply_line_operations(&lines, &[
+        LineOperation::Add { product_id: beer, quantity: 8.0, scale: None, price: None },
+        LineOperation::Remove { product_id: soup, quantity: 1.0 },
+        LineOperation::SetQuantity { product_id: cake, quantity: Some(3.0), scale: None },
+        LineOperation::Remove { product_id: beer, quantity: 4.0 },
+    ]).unwrap();
+    assert_eq!(result, vec![
+        ProductIdWithQuantity { _id: beer, quantity: 6.0, sent_quantity: 1.0, price: None },
+        ProductIdWithQuantity { _id: cake, quantity: 3.0, sent_quantity: 0.0, price: None },
+    ]);
+
+    // Sent units stay, however the batch gets there.
+    let err = apply_line_operations(&lines, &[
+        LineOperation::Add { product_id: soup, quantity: 1.0, scale: None, price: None },
+        LineOperation::SetQuantity { product_id: beer, quantity: Some(0.0), scale: None },
+    ]).unwrap_err();
+    assert!(matches!(err, RepoError::InvalidState(_)), "{}", err);
+    assert!(apply_line_operations(&lines, &[LineOperation::Remove { product_id: cake, quantity: 1.0 }]).is_err());
+
+    let batch: OrderBatch = serde_json::from_value(serde_json::json!({ "operations": [
+        { "op": "add", "product_id": beer.to_string() },
+        { "op": "set_quantity", "product_id": soup.to_string(), "quantity": -1 },
+    ] })).unwrap();
+    assert_eq!(batch.operations[0], LineOperation::Add { product_id: beer, quantity: 1.0, scale: None, price: None });
+    let errors = batch.validate().unwrap_err();
+    assert_eq!(errors.fields.len(), 1);
+    assert!(OrderBatch { operations: vec![] }.validate().is_err());
+}
+
+#[test]
+fn scale_readings_become_quantities_in_the_product_unit() {
+    use crate::models::categories::CategoryId;
+    use crate::models::orders::{LineOperation, OrderBatch};
+    use crate::models::products::{Product, ProductId, ScaleReading, Unit};
+    use crate::models::validation::Validate;
+    use crate::repo::orders::resolve_line_operations;
+
+    assert_eq!(Unit::Kilogram.round(0.12345), 0.123);
+    assert_eq!(Unit::Piece.round(1.6), 2.0);
+    assert_eq!(Unit::Gram.convert(1250.0, Unit::Kilogram), Some(1.25));
+    assert_eq!(Unit::Litre.convert(1.0, Unit::Kilogram), None);
+    assert_eq!(Unit::Piece.convert(2.0, Unit::Piece), Some(2.0));
+
+    let product = |unit: Unit| Product {
+        _id: ProductId::new(),
+        name: "Item".to_string(),
+        price: 24.0,
+        category_id: CategoryId::new(),
+        tax_rate: 5.0,
+        sku: None,
+        unit,
+        open_price: None,
+    };
+    let (cheese, bread) = (product(Unit::Kilogram), product(Unit::Piece));
+    let products = vec![cheese.clone(), bread.clone()];
+    let reading = |gross: f64, unit: Unit, stable: bool| Some(ScaleReading { gross, tare: 15.0, unit, stable });
+
+    let resolved = resolve_line_operations(&[
+        LineOperation::SetQuantity { product_id: cheese._id, quantity: None, scale: reading(427.6, Unit::Gram, true) },
+        LineOperation::Add { product_id: bread._id, quantity: 1.4, scale: None, price: None },
+    ], &products).unwrap();
+    assert_eq!(resolved, vec![
+        LineOperation::SetQuantity { product_id: cheese._id, quantity: Some(0.413), scale: None },
+        LineOperation::Add { product_id: bread._id, quantity: 1.0, scale: None, price: None },
+    ]);
+
+    let refused = |operation: LineOperation| resolve_line_operations(&[operation], &products).is_err();
+    assert!(refused(LineOperation::SetQuantity { product_id: cheese._id, quantity: None, scale: reading(400.0, Unit::Gram, false) }));
+    assert!(refused(LineOperation::SetQuantity { product_id: cheese._id, quantity: None, scale: reading(400.0, Unit::Millilitre, true) }));
+    assert!(refused(LineOperation::Add { product_id: bread._id, quantity: 1.0, scale: reading(400.0, Unit::Gram, true), price: None }));
+    assert!(refused(LineOperation::Add { product_id: cheese._id, quantity: 0.0001, scale: None, price: None }));
+
+    let batch = OrderBatch { operations: vec![
+        LineOperation::SetQuantity { product_id: cheese._id, quantity: Some(1.0), scale: reading(400.0, Unit::Gram, true) },
+        LineOperation::SetQuantity { product_id: bread._id, quantity: None, scale: None },
+    ] };
+    assert_eq!(batch.validate().unwrap_err().fields.len(), 2);
+}
+
+#[test]
+fn open_prices_stay_in_range_and_custom_lines_are_sold_like_products() {
+    use chrono::NaiveDate;
+    use mongodb::bson::DateTime;
+    use crate::models::categories::CategoryId;
+    use crate::models::orders::{CustomLine, CustomLineId, LineOperation, NewCustomLine, OrderAPI, OrderId};
+    use crate::models::products::{NewProduct, PriceRange, Product, ProductId, ProductIdWithQuantity, Unit};
+    use crate::models::reports::ReportKind;
+    use crate::models::tables::{TableId, TableInOrder};
+    use crate::models::validation::Validate;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use crate::repo::orders::{apply_line_operations, check_line_price, resolve_line_operations};
+    use crate::repo::reports::build_sales_report;
+    use crate::services::error::ServiceError;
+
+    let special = Product {
+        _id: ProductId::new(),
+        name: "Daily special".to_string(),
+        price: 12.0,
+        category_id: CategoryId::new(),
+        tax_rate: 8.0,
+        sku: None,
+        unit: Unit::Piece,
+        open_price: Some(PriceRange { min: 5.0, max: 25.0 }),
+    };
+    let fries = Product { _id: ProductId::new(), name: "Fries".to_string(), open_price: None, ..special.clone() };
+
+    assert!(check_line_price(&special, Some(14.5)).is_ok());
+    assert!(check_line_price(&special, Some(30.0)).is_err());
+    let missing_price = ServiceError::from(check_line_price(&special, None).unwrap_err());
+    assert_eq!(missing_price.status_code(), StatusCode::BAD_REQUEST);
+    assert!(check_line_price(&fries, Some(1.0)).is_err());
+    assert!(check_line_price(&fries, None).is_ok());
+
+    let add = |price: Option<f64>| LineOperation::Add { product_id: special._id, quantity: 1.0, scale: None, price };
+    assert!(resolve_line_operations(&[add(Some(40.0))], std::slice::from_ref(&special)).is_err());
+    let lines = apply_line_operations(&[], &[add(Some(14.5)), add(Some(14.5))]).unwrap();
+    assert_eq!(lines, vec![ProductIdWithQuantity { _id: special._id, quantity: 2.0, sent_quantity: 0.0, price: Some(14.5) }]);
+    assert!(apply_line_operations(&lines, &[add(Some(16.0))]).is_err());
+
+    let product = NewProduct {
+        name: "Catch of the day".to_string(),
+        price: 30.0,
+        category_id: CategoryId::new(),
+        tax_rate: 8.0,
+        sku: None,
+        unit: Unit::Kilogram,
+        open_price: Some(PriceRange { min: 20.0, max: 10.0 }),
+    };
+    assert_eq!(product.validate().unwrap_err().fields.len(), 1);
+    let custom = NewCustomLine { name: " ".to_string(), price: 0.0, tax_rate: 8.0, quantity: 1.0 };
+    assert_eq!(custom.validate().unwrap_err().fields.len(), 2);
+
+    let order = OrderAPI {
+        _id: OrderId::new(),
+        waiter: WaiterInOrder { _id: WaiterId::new(), name: "Kacper".into() },
+        table: TableInOrder { _id: TableId::new(), name: "1".into() },
+        products: vec![],
+        custom_lines: vec![CustomLine {
+            _id: CustomLineId::new(),
+            name: "Misc food".to_string(),
+            price: 15.0,
+            tax_rate: 8.0,
+            quantity: 2.0,
+            sent_quantity: 2.0,
+            added_at: DateTime::now(),
+        }],
+        sum: 30.0,
+        created_at: DateTime::now(),
+        covers: 1,
+        payments: vec![],
+        discounts: vec![],
+        voids: vec![],
+        refunds: vec![],
+        closed_at: None,
+    };
+    let day = NaiveDate::from_ymd_opt(2024, 3, 9).unwrap();
+    let report = build_sales_report(ReportKind::X, day, DateTime::now(), DateTime::now(), &[order], &[]);
+    assert_eq!(report.gross_sales, 30.0);
+    assert_eq!(report.tax.len(), 1);
+    assert_eq!(report.tax[0].rate, 8.0);
+}
+
+#[actix_web::test]
+async fn health_and_metrics_bypass_auth_and_count_requests() {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body, call_service};
+    use crate::services::local_auth::LocalAuthSettings;
+    use crate::services::metrics::{healthz, record_request_latency, Metrics};
+
+    let metrics = web::Data::new(Metrics::new());
+    let app = init_service(
+        App::new()
+            .wrap_fn(record_request_latency)
+            .app_data(metrics.clone())
+            .app_data(web::Data::new(LocalAuthSettings::hs256(b"a-secret-that-never-leaves-the-restaurant", Duration::from_secs(600))))
+            .service(healthz)
+            .service(
+                web::scope("")
+                    .wrap(HttpAuthentication::bearer(validator))
+                    .route("/orders/{id}", web::get().to(|| async { actix_web::HttpResponse::Ok().finish() })),
+            ),
+    )
+        .await;
+
+    assert_eq!(call_service(&app, TestRequest::get().uri("/healthz").to_request()).await.status(), StatusCode::OK);
+    let body = call_and_read_body(&app, TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(body, r#"{"status":"ok"}"#);
+
+    let res = call_service(&app, TestRequest::get().uri("/orders/1").insert_header(("Authorization", "Bearer not.a.token")).to_request()).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let exposition = metrics.encode().unwrap();
+    assert!(exposition.contains(r#"pos_http_request_duration_seconds_count{method="GET",route="/healthz",status="200"} 2"#), "{}", exposition);
+    assert!(exposition.contains(r#"route="/orders/{id}",status="401""#), "{}", exposition);
+    assert!(exposition.contains(r#"pos_auth_failures_total{reason="malformed"} 1"#), "{}", exposition);
+}
+
+#[actix_web::test]
+async fn request_spans_carry_request_id_subject_and_order() {
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+    use crate::models::orders::OrderId;
+    use crate::services::error::json_error_bodies;
+    use crate::services::local_auth::{parse_device_keys, LocalAuthSettings};
+    use crate::services::path::IdPath;
+    use crate::telemetry::{record_order_id, trace_requests};
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::registry().with(
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(move || writer.clone()),
+    );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (key, device) = parse_device_keys("bar=bar-key").unwrap().remove(0);
+    let local = LocalAuthSettings::hs256(b"a-secret-that-never-leaves-the-restaurant", Duration::from_secs(600))
+        .with_device_key(&key, device);
+    let order_id = OrderId::new();
+    let app = init_service(
+        App::new()
+            .wrap_fn(trace_requests)
+            .wrap_fn(json_error_bodies)
+            .app_data(web::Data::new(local))
+            .service(
+                web::scope("")
+                    .wrap(HttpAuthentication::bearer(validator))
+                    .route("/orders/{id}", web::get().to(|id: IdPath| async move {
+                        record_order_id(&id.into_inner());
+                        tracing::info!("looking up order");
+                        actix_web::HttpResponse::Ok().finish()
+                    })),
+            ),
+    )
+        .await;
 
     let req = TestRequest::get()
-        .uri(&format!("/get_waiter/{}", &waiter.code))
+        .uri(&format!("/orders/{}", order_id))
+        .insert_header(("Authorization", "Bearer bar-key"))
+        .insert_header(("X-Request-Id", "req-7"))
         .to_request();
+    actix_web::test::call_service(&app, req).await;
+
+    let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let line = output.lines().find(|line| line.contains("looking up order")).unwrap_or_else(|| panic!("the handler event should be logged: {}", output));
+    let event: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(event["span"]["request_id"], "req-7");
+    assert_eq!(event["span"]["route"], "/orders/{id}");
+    assert_eq!(event["span"]["subject"], "device:bar");
+    assert_eq!(event["span"]["order_id"], order_id.to_string());
+    assert!(output.contains("request finished"));
+}
+
+
+#[actix_web::test]
+async fn shutdown_waits_for_running_mutations_and_turns_new_ones_away() {
+    use std::sync::Arc;
+    use actix_web::http::StatusCode;
+    use actix_web::ResponseError;
+    use crate::services::shutdown::ShutdownGate;
+
+    let gate = Arc::new(ShutdownGate::default());
+    let running = gate.enter().unwrap();
+    assert_eq!(gate.in_flight(), 1);
+
+    assert!(!gate.drain(Duration::from_millis(100)).await);
+    assert_eq!(gate.enter().unwrap_err().status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(gate.in_flight(), 1);
+
+    let finishing = actix_web::rt::spawn(async move {
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+        drop(running);
+    });
+    assert!(gate.drain(Duration::from_secs(5)).await);
+    finishing.await.unwrap();
+    assert_eq!(gate.in_flight(), 0);
 
-    let response: Waiter = call_and_read_body_json(&app, req).await;
-    assert_eq!(response, waiter);
-}
\ No newline at end of file
+    assert!(crate::repo::migrations::latest_version() > 0);
+}